@@ -1,4 +1,4 @@
-use std::{env, io};
+use std::{env, io, process::Command as Cmd};
 
 use clap::CommandFactory;
 use clap_complete::{generate_to, shells::Bash};
@@ -6,6 +6,7 @@ use clap_complete::{generate_to, shells::Bash};
 include!("src/cli.rs");
 
 fn main() -> io::Result<()> {
+  println!("cargo:rustc-env=HMD_VERSION={}", version());
   let outdir = env::var("OUT_DIR").map_err(io::Error::other)?;
   let mut cmd = Cli::command();
   let bin_name = cmd.get_name().to_string();
@@ -13,3 +14,22 @@ fn main() -> io::Result<()> {
   println!("cargo:warning=completion file is generated: {path:?}");
   Ok(())
 }
+
+/// Crate version with short sha of git commit it's built from,
+/// bare version out of git repo
+fn version() -> String {
+  let version = env!("CARGO_PKG_VERSION");
+  let sha = Cmd::new("git")
+    .args(["rev-parse", "--short", "HEAD"])
+    .output()
+    .ok()
+    .filter(|output| output.status.success())
+    .map(|output| {
+      String::from_utf8_lossy(&output.stdout).trim().to_owned()
+    })
+    .filter(|sha| !sha.is_empty());
+  match sha {
+    Some(sha) => format!("{version} ({sha})"),
+    None => version.to_owned(),
+  }
+}
@@ -1,7 +1,39 @@
 #!/bin/bash
 
+# Status, exit code and events of app instance launched with
+# `HMD_STATUS_LOG`, `HMD_EXIT_CODE` and `HMD_EVENTS_LOG`
+status_log=${HMD_STATUS_LOG:-status.log};
+exit_code=${HMD_EXIT_CODE:-exit.code};
+events_log=${HMD_EVENTS_LOG:-events.log};
+
+# Records pipeline exit status for `hmd status --watch-exit`
+rm -f "$exit_code";
+trap 'echo $? > "$exit_code"' EXIT;
+
+# Separate stage logs are written to logs/<stage>.log
+mkdir -p logs;
+
+# Appends json line of stage `$1` event `$2` to `$events_log`,
+# `\` and `"` of stage name are escaped for json
+event() {
+  local stage=${stages[$1]//\\/\\\\};
+  stage=${stage//\"/\\\"};
+  echo "{\"stage\":\"$stage\",\"event\":\"$2\",\"ts\":\"`date +%FT%T`\"}" >> "$events_log";
+}
+
+# Traces commands with `set -x` if `trace` is 1
+trace_on() {
+  if [[ $trace == 1 ]]; then set -x; fi;
+}
+
+# Stops tracing without tracing itself
+trace_off() {
+  { set +x; } 2> /dev/null;
+}
+
 run() {
   i=$1;
+  event $i start;
   status="";
   for stage in "${!stages[@]}"; do
     if (( stage < i )); then
@@ -14,11 +46,12 @@ run() {
       status="$status🟨 ${stages[$stage]}\n";
     fi
   done
-  echo -e "$status`date +%FT%T`" > status.log;
+  echo -e "$status`date +%FT%T`" > "$status_log";
 }
 
 complete() {
   i=$1;
+  event $i complete;
   status="";
   for stage in "${!stages[@]}"; do
     if (( stage <= i )); then
@@ -28,11 +61,12 @@ complete() {
       status="$status🟨 ${stages[$stage]}\n";
     fi
   done
-  echo -e "$status`date +%FT%T`" > status.log;
+  echo -e "$status`date +%FT%T`" > "$status_log";
 }
 
 panic() {
   i=$1;
+  event $i fail;
   status="";
   for stage in "${!stages[@]}"; do
     if (( stage < i )); then
@@ -45,6 +79,6 @@ panic() {
       status="$status🟥 ${stages[$stage]}\n";
     fi
   done
-  echo -e "$status`date +%FT%T`" > status.log;
+  echo -e "$status`date +%FT%T`" > "$status_log";
   exit 1;
 }
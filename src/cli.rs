@@ -1,4 +1,15 @@
-use clap::{Args, Parser, Subcommand};
+use std::{path::PathBuf, time::Duration};
+
+use clap::{
+  ArgAction, ArgGroup, Args, ColorChoice, Parser, Subcommand,
+  ValueEnum,
+};
+
+/// Crate version with build commit, set by `build.rs`
+const VERSION: &str = match option_env!("HMD_VERSION") {
+  Some(version) => version,
+  None => env!("CARGO_PKG_VERSION"),
+};
 
 /// Home Deploy Tool
 ///
@@ -13,8 +24,39 @@ use clap::{Args, Parser, Subcommand};
 /// or after manually calling the stop command.
 ///
 #[derive(Parser)]
-#[clap(version)]
+#[clap(version = VERSION)]
 pub(crate) struct Cli {
+  /// Run as if hmd was started in <DIRECTORY>
+  #[clap(short = 'C', long, global = true)]
+  pub(crate) directory: Option<PathBuf>,
+  #[clap(flatten)]
+  pub(crate) targets: TargetOptions,
+  /// Print errors only
+  #[clap(long, short, global = true)]
+  pub(crate) quiet: bool,
+  /// Increase verbosity, `-vv` traces remote pipeline with `set -x`
+  #[clap(
+    long,
+    short,
+    global = true,
+    action = ArgAction::Count,
+    conflicts_with = "quiet"
+  )]
+  pub(crate) verbose: u8,
+  /// Prefix output lines with target or project name,
+  /// enabled by default with `--target`
+  #[clap(long, global = true)]
+  pub(crate) prefix: bool,
+  /// Colorize output
+  #[clap(long, global = true, default_value = "auto")]
+  pub(crate) color: ColorChoice,
+  /// Print failure as single-line json for tools
+  #[clap(long, global = true, value_enum, default_value = "human")]
+  pub(crate) error_format: ErrorFormat,
+  /// Check of server host key by ssh and scp,
+  /// `accept-new` trusts key of new server
+  #[clap(long, global = true, value_enum, value_name = "CHECK")]
+  pub(crate) host_key_check: Option<HostKeyCheck>,
   #[clap(subcommand)]
   pub(crate) command: Command,
 }
@@ -27,18 +69,136 @@ pub(crate) enum Command {
     ssh_address: SshAddressOption,
     #[clap(flatten)]
     project: ProjectOption,
+    /// Overwrite existing `hmd.yml` with defaults
+    #[clap(long)]
+    force: bool,
+    /// Default stages of `hmd.yml`,
+    /// detected by project files if omitted
+    #[clap(long, value_enum)]
+    template: Option<Template>,
+    /// Stage of new `hmd.yml` instead of template ones,
+    /// repeat to keep order, e.g. `--stage build='make all'`
+    #[clap(
+      long = "stage",
+      value_name = "NAME=COMMAND",
+      value_parser = parse_stage,
+      conflicts_with = "template"
+    )]
+    stages: Vec<(String, String)>,
+    /// Artifact of new `hmd.yml`, repeatable
+    #[clap(long = "artifact", value_name = "PATH")]
+    artifacts: Vec<String>,
+    /// Deploy artifacts only, without git repo at server
+    #[clap(long)]
+    no_git: bool,
+    /// Check server is reachable and writable
+    /// without writing anything
+    #[clap(
+      long,
+      conflicts_with_all = ["force", "template", "stages", "artifacts"]
+    )]
+    check: bool,
   },
 
   /// Push HEAD to server and run pipeline
   #[clap(visible_alias = "d")]
+  // `--watch` deploys dirty work tree too
+  #[clap(group(
+    ArgGroup::new("dirty_deploy")
+      .args(["dirty", "watch"])
+      .multiple(true)
+  ))]
   Deploy {
-    /// Push work tree with staged and unstaged changes
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    /// Push work tree with uncommitted and untracked changes
     #[clap(long)]
     dirty: bool,
+    /// Message of temp commit of `--dirty` deploy
+    #[clap(long, short, requires = "dirty_deploy")]
+    message: Option<String>,
+    /// Branch to push deployed commit to at ssh server,
+    /// current branch by default
+    #[clap(long)]
+    remote_branch: Option<String>,
+    /// Limit artifacts upload bandwidth to <KBPS> KB/s
+    #[clap(long, value_name = "KBPS")]
+    limit: Option<u32>,
+    /// Compress artifacts upload and ssh traffic
+    #[clap(long)]
+    compress: bool,
+    /// Upload artifacts by <N> concurrent scp
+    #[clap(long, value_name = "N", default_value_t = 1)]
+    parallel: usize,
+    /// Upload artifacts as one tar.gz archive,
+    /// faster for many small files
+    #[clap(long, conflicts_with = "parallel")]
+    compress_artifacts: bool,
+    /// Deploy tag, branch or commit instead of HEAD
+    #[clap(long = "ref", conflicts_with = "dirty")]
+    git_ref: Option<String>,
+    /// Retry failed stage <N> times,
+    /// overrides `stage_retries` of `hmd.yml`
+    #[clap(long, value_name = "N")]
+    retry_stages: Option<u32>,
+    /// Export variables of dotenv <FILE> in pipeline,
+    /// e.g. secrets kept out of git
+    #[clap(long, value_name = "FILE")]
+    env_from: Option<PathBuf>,
+    /// Label of deploy in history, short commit by default
+    #[clap(long, value_name = "LABEL")]
+    tag: Option<String>,
+    /// Stream pipeline and exit with its status
+    #[clap(long)]
+    wait: bool,
+    /// Push and upload artifacts without running pipeline
+    #[clap(
+      long,
+      conflicts_with_all = ["wait", "no_checkout", "keep_pipeline_sh"]
+    )]
+    only_upload: bool,
+    /// Run pipeline on existing work tree without git checkout
+    #[clap(long)]
+    no_checkout: bool,
+    /// Don't check free disk space at server before upload
+    #[clap(long)]
+    no_disk_check: bool,
+    /// Remove lock of other deploy before deploying
+    #[clap(long)]
+    force_unlock: bool,
+    /// Push without `--force`, fail if server branch diverged
+    #[clap(long)]
+    no_force_push: bool,
+    /// Keep generated `pipeline.sh` to inspect it after deploy
+    #[clap(long)]
+    keep_pipeline_sh: bool,
+    /// Print generated `pipeline.sh` without touching server
+    #[clap(
+      long,
+      conflicts_with_all = ["wait", "only_upload", "log", "status"]
+    )]
+    print_pipeline: bool,
+    /// Dirty deploy again on every change of files
+    #[clap(
+      long,
+      conflicts_with_all = [
+        "git_ref",
+        "wait",
+        "only_upload",
+        "print_pipeline",
+        "log",
+        "status",
+      ]
+    )]
+    watch: bool,
+    #[clap(flatten)]
+    timeout: TimeoutOption,
     #[clap(flatten)]
     log: LogOption,
     #[clap(flatten)]
     status: StatusOption,
+    #[clap(flatten)]
+    instance: InstanceOption,
   },
 
   /// Stop pipeline
@@ -47,6 +207,11 @@ pub(crate) enum Command {
     ssh_address: SshAddressOption,
     #[clap(flatten)]
     project: ProjectOption,
+    /// Stop pipelines of all projects on server
+    #[clap(long, conflicts_with_all = ["project", "instance"])]
+    all: bool,
+    #[clap(flatten)]
+    instance: InstanceOption,
   },
 
   /// Restart pipeline
@@ -55,10 +220,26 @@ pub(crate) enum Command {
     ssh_address: SshAddressOption,
     #[clap(flatten)]
     project: ProjectOption,
+    /// Restart only if pipeline is running
+    #[clap(long)]
+    if_running: bool,
+    /// Fail if `pipeline.sh` at server isn't one of last deploy
+    #[clap(long, conflicts_with = "all")]
+    verify_script: bool,
+    /// Restart pipelines of all deployed projects on server
+    #[clap(
+      long,
+      conflicts_with_all = ["project", "log", "status", "instance"]
+    )]
+    all: bool,
+    #[clap(flatten)]
+    timeout: TimeoutOption,
     #[clap(flatten)]
     log: LogOption,
     #[clap(flatten)]
     status: StatusOption,
+    #[clap(flatten)]
+    instance: InstanceOption,
   },
 
   /// Show pipeline status
@@ -68,6 +249,25 @@ pub(crate) enum Command {
     ssh_address: SshAddressOption,
     #[clap(flatten)]
     project: ProjectOption,
+    /// Exit with status of finished pipeline
+    #[clap(long)]
+    watch_exit: bool,
+    /// Print running state as json without streaming
+    #[clap(long, conflicts_with_all = ["watch_exit", "tail"])]
+    json: bool,
+    #[clap(flatten)]
+    tail: TailOption,
+    #[clap(flatten)]
+    instance: InstanceOption,
+  },
+
+  /// Show pipeline CPU and memory usage
+  #[clap(visible_alias = "top")]
+  Ps {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
   },
 
   /// Stream pipeline log
@@ -77,6 +277,62 @@ pub(crate) enum Command {
     ssh_address: SshAddressOption,
     #[clap(flatten)]
     project: ProjectOption,
+    /// Show log since stage marker not older than <SINCE>,
+    /// e.g. `30s`, `10m`, `1h`, `2d`
+    #[clap(
+      long,
+      value_parser = parse_duration,
+      conflicts_with = "tail"
+    )]
+    since: Option<Duration>,
+    /// Show whole log of last deploy
+    #[clap(long, conflicts_with_all = ["since", "tail"])]
+    last_deploy: bool,
+    /// Show separate log of stage <STAGE>
+    #[clap(long, conflicts_with_all = ["since", "last_deploy"])]
+    stage: Option<String>,
+    #[clap(flatten)]
+    tail: TailOption,
+    #[clap(flatten)]
+    instance: InstanceOption,
+  },
+
+  /// Print deploys recorded at server
+  History {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
+  },
+
+  /// Stream json stage events of pipeline
+  Events {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
+  },
+
+  /// Download pipeline log to share it
+  Download {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
+    /// Log to download
+    #[clap(value_enum, default_value = "out")]
+    what: LogFile,
+    /// Local file, or dir for `all`,
+    /// `./<PROJECT>-<LOG>` by default
+    dest: Option<PathBuf>,
+  },
+
+  /// Print resolved project paths and address without connecting
+  Env {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
   },
 
   /// List projects at ssh server
@@ -84,6 +340,16 @@ pub(crate) enum Command {
   List {
     #[clap(flatten)]
     ssh_address: SshAddressOption,
+    /// Show disk usage of projects
+    #[clap(long)]
+    size: bool,
+    /// List projects under <PATH> instead of `remote_root`
+    #[clap(
+      long,
+      value_name = "PATH",
+      value_parser = parse_remote_root
+    )]
+    remote_root: Option<String>,
   },
 
   /// Open working dir at ssh server
@@ -95,15 +361,128 @@ pub(crate) enum Command {
     project: ProjectOption,
   },
 
+  /// Run command at work tree on ssh server
+  #[clap(visible_alias = "shell")]
+  Exec {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
+    /// Command and its arguments, e.g. `hmd exec -- ls -la`
+    #[clap(
+      required = true,
+      trailing_var_arg = true,
+      allow_hyphen_values = true
+    )]
+    args: Vec<String>,
+  },
+
+  /// Stop pipeline and remove logs from work tree
+  Clean {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
+    /// Also run `clean` command from `hmd.yml`
+    #[clap(long)]
+    all: bool,
+  },
+
+  /// Remove old rotated logs from work tree
+  Prune {
+    #[clap(flatten)]
+    ssh_address: SshAddressOption,
+    #[clap(flatten)]
+    project: ProjectOption,
+    /// Number of recent rotated logs to keep
+    #[clap(long, default_value_t = 5)]
+    keep: usize,
+  },
+
+  /// Install latest release of hmd by cargo
+  #[cfg(feature = "self-update")]
+  SelfUpdate {
+    /// Only report whether newer release exists
+    #[clap(long)]
+    check_only: bool,
+  },
+
+  /// View and edit `~/.hmd/config.yml`
+  Config {
+    #[clap(subcommand)]
+    command: ConfigCommand,
+  },
+
   /// Remove project from server
   Remove {
     #[clap(flatten)]
     ssh_address: SshAddressOption,
     #[clap(flatten)]
     project: ProjectOption,
+    /// Skip confirmation
+    #[clap(long, short)]
+    yes: bool,
+    /// Download `out.log` and `status.log` to local dir before
+    #[clap(long, value_name = "LOCAL_DIR")]
+    keep_logs: Option<PathBuf>,
   },
 }
 
+#[derive(Subcommand)]
+pub(crate) enum ConfigCommand {
+  /// Print config
+  Show,
+
+  /// Set default ssh address
+  SetSsh {
+    /// Formats: login@ip, alias
+    ssh_address: String,
+  },
+
+  /// Print config path
+  Path,
+}
+
+/// Format of failure printed to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ErrorFormat {
+  /// Message and its debug details
+  Human,
+  /// `{"error": "...", "kind": "..."}`
+  Json,
+}
+
+/// Value of ssh `StrictHostKeyChecking` option
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum HostKeyCheck {
+  /// Fail on unknown or changed host key
+  Yes,
+  /// Skip check of host key
+  No,
+  /// Add key of new host, fail on changed one
+  AcceptNew,
+}
+
+/// Pipeline log of `download`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFile {
+  /// `out.log` of stages output
+  Out,
+  /// `status.log` of stages status
+  Status,
+  /// Both logs
+  All,
+}
+
+/// Project type to choose default stages of `hmd.yml`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Template {
+  Rust,
+  Node,
+  Python,
+  Static,
+}
+
 #[derive(Args)]
 pub(crate) struct ProjectOption {
   /// Unique project name
@@ -111,11 +490,29 @@ pub(crate) struct ProjectOption {
   pub(crate) project: Option<String>,
 }
 
+/// Global options choosing target of command
+#[derive(Args, Clone)]
+pub(crate) struct TargetOptions {
+  /// Target name from `~/.hmd/config.yml`
+  #[clap(long, global = true)]
+  pub(crate) target: Option<String>,
+  /// Namespace of projects at server, e.g. user name
+  #[clap(long, global = true)]
+  pub(crate) scope: Option<String>,
+  /// User to log in as, overrides one of ssh address
+  #[clap(long, global = true, value_name = "NAME")]
+  pub(crate) user: Option<String>,
+}
+
 #[derive(Args)]
 pub(crate) struct SshAddressOption {
   /// Formats: login@ip, alias
   #[clap(long = "ssh")]
   pub(crate) ssh_address: Option<String>,
+  /// Extra ssh option, repeatable,
+  /// e.g. `-o StrictHostKeyChecking=accept-new`
+  #[clap(long = "ssh-option", short = 'o', value_name = "OPT")]
+  pub(crate) ssh_options: Vec<String>,
 }
 
 #[derive(Args)]
@@ -131,3 +528,206 @@ pub(crate) struct StatusOption {
   #[clap(long, short)]
   pub(crate) status: bool,
 }
+
+#[derive(Args)]
+pub(crate) struct TailOption {
+  /// Print last <N> lines before streaming new ones,
+  /// 50 of log by default
+  #[clap(long, short = 'n', value_name = "N")]
+  pub(crate) tail: Option<usize>,
+}
+
+#[derive(Args)]
+pub(crate) struct TimeoutOption {
+  /// Kill pipeline after <TIMEOUT> seconds
+  #[clap(long)]
+  pub(crate) timeout: Option<u64>,
+}
+
+#[derive(Args)]
+pub(crate) struct InstanceOption {
+  /// App instance with own pid and logs, e.g. `blue`
+  #[clap(long, value_parser = parse_instance)]
+  pub(crate) instance: Option<String>,
+}
+
+/// Parses stage like `build=cargo build`
+///
+/// Stage name is letters, digits, `_` and `-`, as in `hmd.yml`.
+fn parse_stage(stage: &str) -> Result<(String, String), String> {
+  let error = || format!("Invalid stage `{stage}`, try `build=make`");
+  let (name, cmd) = stage.split_once('=').ok_or_else(error)?;
+  let (name, cmd) = (name.trim(), cmd.trim());
+  if name.is_empty() || cmd.is_empty() {
+    return Err(error());
+  }
+  let valid_name = name
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+  if !valid_name {
+    return Err(format!(
+      "Invalid stage name `{name}`, use letters, digits, `_` and `-`"
+    ));
+  }
+  Ok((name.to_owned(), cmd.to_owned()))
+}
+
+/// Parses instance name of letters, digits, `_` and `-`,
+/// so it's safe in file names
+fn parse_instance(instance: &str) -> Result<String, String> {
+  let valid = !instance.is_empty()
+    && instance
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+  if !valid {
+    return Err(format!(
+      "Invalid instance `{instance}`, use letters, digits, `_` and `-`"
+    ));
+  }
+  Ok(instance.to_owned())
+}
+
+/// Parses non-empty remote dir of projects
+fn parse_remote_root(root: &str) -> Result<String, String> {
+  let root = root.trim();
+  if root.is_empty() {
+    return Err("Remote root can't be empty".to_owned());
+  }
+  Ok(root.to_owned())
+}
+
+/// Parses duration like `30s`, `10m`, `1h` or `2d`
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+  let error = || format!("Invalid duration `{duration}`, try `10m`");
+  let (unit_at, unit) =
+    duration.char_indices().last().ok_or_else(error)?;
+  let value =
+    duration[..unit_at].parse::<u64>().map_err(|_| error())?;
+  let unit_secs = match unit {
+    's' => 1,
+    'm' => 60,
+    'h' => 60 * 60,
+    'd' => 60 * 60 * 24,
+    _ => return Err(error()),
+  };
+  let secs = value.checked_mul(unit_secs).ok_or_else(error)?;
+  Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::CommandFactory;
+
+  use super::*;
+
+  #[test]
+  fn version_has_build_commit() {
+    let version = Cli::command().render_version();
+    let prefix = concat!("hmd ", env!("CARGO_PKG_VERSION"));
+    let sha = version.trim_end().strip_prefix(prefix).unwrap();
+    // Build out of git repo has bare version
+    if !sha.is_empty() {
+      let sha = sha.strip_prefix(" (").unwrap().strip_suffix(')');
+      assert!(
+        sha.is_some_and(|sha| !sha.is_empty()
+          && sha.chars().all(|c| c.is_ascii_hexdigit())),
+        "{version}"
+      );
+    }
+  }
+
+  #[test]
+  fn parses_duration_units() {
+    let secs =
+      |duration| parse_duration(duration).map(|d| d.as_secs());
+    assert_eq!(secs("30s"), Ok(30));
+    assert_eq!(secs("10m"), Ok(600));
+    assert_eq!(secs("1h"), Ok(3600));
+    assert_eq!(secs("2d"), Ok(172_800));
+  }
+
+  #[test]
+  fn rejects_invalid_duration() {
+    for duration in ["", "m", "10", "10x", "-1m", "1.5h"] {
+      assert!(parse_duration(duration).is_err(), "{duration}");
+    }
+  }
+
+  #[test]
+  fn rejects_multibyte_unit_without_panic() {
+    assert!(parse_duration("10é").is_err());
+    assert!(parse_duration("é").is_err());
+  }
+
+  #[test]
+  fn rejects_overflowing_duration() {
+    assert!(parse_duration("99999999999999999d").is_err());
+  }
+
+  fn stage(name: &str, cmd: &str) -> (String, String) {
+    (name.to_owned(), cmd.to_owned())
+  }
+
+  #[test]
+  fn parses_stage_name_and_command() {
+    assert_eq!(parse_stage("build=make"), Ok(stage("build", "make")));
+    assert_eq!(
+      parse_stage(" run = ./app --port=80 "),
+      Ok(stage("run", "./app --port=80"))
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_stage() {
+    for invalid in
+      ["build", "=make", "build=", " = ", "my build=make"]
+    {
+      assert!(parse_stage(invalid).is_err(), "{invalid}");
+    }
+    assert_eq!(
+      parse_stage("b.d=make"),
+      Err(
+        "Invalid stage name `b.d`, use letters, digits, `_` and `-`"
+          .to_owned()
+      )
+    );
+  }
+
+  #[test]
+  fn init_keeps_stages_in_given_order() {
+    let cli = Cli::try_parse_from([
+      "hmd",
+      "init",
+      "--stage",
+      "test=make test",
+      "--stage",
+      "build=make",
+      "--stage",
+      "run=./app",
+    ])
+    .unwrap();
+    let Command::Init { stages, .. } = cli.command else {
+      panic!("init expected");
+    };
+    assert_eq!(
+      stages,
+      [
+        stage("test", "make test"),
+        stage("build", "make"),
+        stage("run", "./app"),
+      ]
+    );
+  }
+
+  #[test]
+  fn message_requires_dirty_or_watch_deploy() {
+    let parse = |args: &[&str]| {
+      Cli::try_parse_from(
+        [&["hmd", "deploy", "-m", "wip"], args].concat(),
+      )
+    };
+    assert!(parse(&["--dirty"]).is_ok());
+    assert!(parse(&["--watch"]).is_ok());
+    assert!(parse(&[]).is_err());
+  }
+}
@@ -0,0 +1,103 @@
+use std::{
+  fmt::Display,
+  io::{self, IsTerminal},
+  sync::atomic::{AtomicBool, Ordering},
+};
+
+static STDOUT_ENABLED: AtomicBool = AtomicBool::new(false);
+static STDERR_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables colors if `forced` or detects them if `None`
+///
+/// Detection enables colors of stdout and stderr separately,
+/// each only for terminal, without `NO_COLOR` environment variable.
+pub fn init(forced: Option<bool>) {
+  let detect = |is_terminal: bool| {
+    forced.unwrap_or_else(|| {
+      std::env::var_os("NO_COLOR").is_none() && is_terminal
+    })
+  };
+  STDOUT_ENABLED
+    .store(detect(io::stdout().is_terminal()), Ordering::Relaxed);
+  STDERR_ENABLED
+    .store(detect(io::stderr().is_terminal()), Ordering::Relaxed);
+}
+
+/// Colors of text printed to one stream
+#[derive(Clone, Copy)]
+pub struct Palette {
+  enabled: bool,
+}
+
+impl Palette {
+  #[must_use]
+  pub fn dim(self, text: impl Display) -> String {
+    self.paint("2", text)
+  }
+
+  #[must_use]
+  pub fn red(self, text: impl Display) -> String {
+    self.paint("31", text)
+  }
+
+  #[must_use]
+  pub fn green(self, text: impl Display) -> String {
+    self.paint("32", text)
+  }
+
+  fn paint(self, code: &str, text: impl Display) -> String {
+    if self.enabled {
+      format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+      text.to_string()
+    }
+  }
+}
+
+/// Colors of text printed to stdout
+#[must_use]
+pub fn stdout() -> Palette {
+  Palette {
+    enabled: STDOUT_ENABLED.load(Ordering::Relaxed),
+  }
+}
+
+/// Colors of text printed to stderr
+#[must_use]
+pub fn stderr() -> Palette {
+  Palette {
+    enabled: STDERR_ENABLED.load(Ordering::Relaxed),
+  }
+}
+
+pub fn dim(text: impl Display) -> String {
+  stdout().dim(text)
+}
+
+pub fn red(text: impl Display) -> String {
+  stdout().red(text)
+}
+
+pub(crate) fn green(text: impl Display) -> String {
+  stdout().green(text)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn color_never_strips_ansi_codes() {
+    init(Some(false));
+    assert_eq!(red("failed"), "failed");
+    assert_eq!(stderr().dim("failed"), "failed");
+  }
+
+  #[test]
+  fn enabled_palette_wraps_text_in_ansi_codes() {
+    let palette = Palette { enabled: true };
+    assert_eq!(palette.red("failed"), "\x1b[31mfailed\x1b[0m");
+    assert_eq!(palette.dim("cmd"), "\x1b[2mcmd\x1b[0m");
+    assert_eq!(palette.green("ok"), "\x1b[32mok\x1b[0m");
+  }
+}
@@ -0,0 +1,186 @@
+use std::{fmt::Write, fs, io, path::Path};
+
+use crate::{error::HmdError, shell::shell_quote};
+
+/// Reads `KEY=VALUE` variables of dotenv file at `path`
+///
+/// # Errors
+///
+/// Returns an error if file can't be read or has invalid line
+pub fn read(path: &Path) -> io::Result<Vec<(String, String)>> {
+  let text = fs::read_to_string(path).map_err(|err| {
+    io::Error::new(
+      err.kind(),
+      format!("Can't read {}: {err}", path.display()),
+    )
+  })?;
+  parse(&text).map_err(|message| {
+    HmdError::ConfigParse {
+      file: path.display().to_string(),
+      message,
+    }
+    .into()
+  })
+}
+
+/// `export` lines of `vars` for bash script
+pub(crate) fn exports_cmd(vars: &[(String, String)]) -> String {
+  let mut exports = String::new();
+  for (key, value) in vars {
+    let _ = writeln!(exports, "export {key}={};", shell_quote(value));
+  }
+  exports
+}
+
+/// Parses dotenv lines skipping blank and `#` comment ones
+///
+/// Value is taken literally in single quotes, double quoted one
+/// has `\n`, `\"` and `\\` escapes, unquoted one ends before ` #`.
+fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+  let mut vars = Vec::new();
+  for (i, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let parsed = line
+      .split_once('=')
+      .ok_or_else(|| "expected KEY=VALUE".to_owned())
+      .and_then(|(key, value)| {
+        let key = key.trim();
+        if !is_key(key) {
+          return Err(format!("invalid key `{key}`"));
+        }
+        Ok((key.to_owned(), parse_value(value.trim())?))
+      });
+    match parsed {
+      Ok(var) => vars.push(var),
+      Err(err) => return Err(format!("line {}: {err}", i + 1)),
+    }
+  }
+  Ok(vars)
+}
+
+fn is_key(key: &str) -> bool {
+  let mut chars = key.chars();
+  chars
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_value(value: &str) -> Result<String, String> {
+  let (parsed, rest) = match value.chars().next() {
+    Some('\'') => {
+      let (quoted, rest) = value[1..]
+        .split_once('\'')
+        .ok_or("unterminated single quote")?;
+      (quoted.to_owned(), rest)
+    }
+    Some('"') => parse_double_quoted(&value[1..])?,
+    _ => {
+      let end = value.find(" #").unwrap_or(value.len());
+      return Ok(value[..end].trim_end().to_owned());
+    }
+  };
+  let rest = rest.trim_start();
+  if rest.is_empty() || rest.starts_with('#') {
+    Ok(parsed)
+  } else {
+    Err(format!("unexpected `{rest}` after quoted value"))
+  }
+}
+
+/// Unescapes value up to closing quote returning rest of line
+fn parse_double_quoted(
+  value: &str,
+) -> Result<(String, &str), String> {
+  let mut parsed = String::new();
+  let mut chars = value.char_indices();
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '"' => return Ok((parsed, &value[i + 1..])),
+      '\\' => match chars.next() {
+        Some((_, 'n')) => parsed.push('\n'),
+        Some((_, c @ ('"' | '\\'))) => parsed.push(c),
+        Some((_, c)) => {
+          parsed.push('\\');
+          parsed.push(c);
+        }
+        None => break,
+      },
+      c => parsed.push(c),
+    }
+  }
+  Err("unterminated double quote".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+      .iter()
+      .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+      .collect()
+  }
+
+  #[test]
+  fn parses_quoted_values() {
+    let text = "A=plain value\nB='single $HOME \\n'\n\
+      C=\"double \\\"quoted\\\"\\nline\"\nexport D=\"\"\n";
+    assert_eq!(
+      parse(text).unwrap(),
+      vars(&[
+        ("A", "plain value"),
+        ("B", "single $HOME \\n"),
+        ("C", "double \"quoted\"\nline"),
+        ("D", ""),
+      ])
+    );
+  }
+
+  #[test]
+  fn skips_comment_lines_and_trailing_comments() {
+    let text = "# secrets\n\n  # indented\nTOKEN=abc # prod\n\
+      URL=http://host/#anchor\nKEY='x' # quoted\n";
+    assert_eq!(
+      parse(text).unwrap(),
+      vars(&[
+        ("TOKEN", "abc"),
+        ("URL", "http://host/#anchor"),
+        ("KEY", "x"),
+      ])
+    );
+  }
+
+  #[test]
+  fn rejects_invalid_lines() {
+    assert_eq!(
+      parse("A=1\nnot a var\n").unwrap_err(),
+      "line 2: expected KEY=VALUE"
+    );
+    assert_eq!(
+      parse("1A=x").unwrap_err(),
+      "line 1: invalid key `1A`"
+    );
+    assert_eq!(
+      parse("A=\"open").unwrap_err(),
+      "line 1: unterminated double quote"
+    );
+    assert_eq!(
+      parse("A='x' y").unwrap_err(),
+      "line 1: unexpected `y` after quoted value"
+    );
+  }
+
+  #[test]
+  fn exports_quoted_values() {
+    assert_eq!(
+      exports_cmd(&vars(&[("A", "it's"), ("B", "$x")])),
+      "export A='it'\\''s';\nexport B='$x';\n"
+    );
+  }
+}
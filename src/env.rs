@@ -1,41 +1,337 @@
-use crate::HMD_ROOT;
+use std::{fmt, net::Ipv6Addr};
 
-pub(crate) struct Env {
-  pub(crate) project: String,
-  pub(crate) ssh_address: String,
-  pub(crate) project_dir: String,
-  pub(crate) git_dir: String,
-  pub(crate) work_tree: String,
+use crate::hmd_config_yml::Target;
+
+pub struct Env {
+  pub project: String,
+  pub ssh_address: String,
+  pub port: Option<u16>,
+  pub retries: u32,
+  pub project_dir: String,
+  pub git_dir: String,
+  pub work_tree: String,
+  /// Staging dir of artifacts upload
+  pub upload_dir: String,
+  /// Artifacts upload bandwidth limit in KB/s
+  pub upload_limit: Option<u32>,
+  /// Compress ssh and scp traffic
+  pub compress: bool,
+  /// Extra `-o` options of ssh and scp
+  pub ssh_options: Vec<String>,
+  /// Bastion host to reach server through
+  pub jump_host: Option<String>,
+  /// User of ssh config alias passed by `-l`
+  pub user: Option<String>,
+  /// Interpreter of `pipeline.sh`
+  pub remote_shell: String,
+  /// File sourced before remote commands, empty to skip
+  pub profile_file: String,
+  /// App instance of project with own pid and logs
+  pub instance: Option<String>,
 }
 
 impl Env {
+  pub(crate) const DEPLOY_LOCK: &'static str = "deploy.lock";
+  pub(crate) const DEPLOY_META: &'static str = "deploy.meta";
+  pub(crate) const EVENTS_LOG: &'static str = "events.log";
+  pub(crate) const EXIT_CODE: &'static str = "exit.code";
   pub(crate) const OUT_LOG: &'static str = "out.log";
   pub(crate) const PIPELINE_PID: &'static str = "pipeline.pid";
   pub(crate) const PIPELINE_SH: &'static str = "pipeline.sh";
+  /// Checksum of `pipeline.sh` uploaded by last deploy
+  pub(crate) const PIPELINE_SHA256: &'static str = "pipeline.sha256";
   pub(crate) const STATUS_LOG: &'static str = "status.log";
+  /// Dir of separate stage logs in work tree
+  pub(crate) const STAGE_LOGS: &'static str = "logs";
 
-  pub(crate) fn new(project: &str, ssh_address: &str) -> Self {
-    let project_dir = format!("{HMD_ROOT}/{project}");
+  #[must_use]
+  pub fn new(project: &str, target: &Target) -> Self {
+    let root = target.projects_dir();
+    let project_dir = format!("{root}/{project}");
     let git_dir = format!("{project_dir}/git");
     let work_tree = format!("{project_dir}/work-tree");
+    let upload_dir = format!("{project_dir}/upload");
+    let (ssh_address, user) = target.login();
     Self {
-      ssh_address: ssh_address.into(),
+      ssh_address,
+      port: target.port,
+      retries: target.retries.unwrap_or_default(),
       project: project.into(),
       project_dir,
       git_dir,
       work_tree,
+      upload_dir,
+      upload_limit: target.upload_limit,
+      compress: target.compress.unwrap_or_default(),
+      ssh_options: target.ssh_options.clone(),
+      jump_host: target.jump_host.clone(),
+      user,
+      remote_shell: target.remote_shell().to_owned(),
+      profile_file: target.profile_file().to_owned(),
+      instance: None,
+    }
+  }
+
+  /// Targets pid and logs of named app `instance`
+  #[must_use]
+  pub fn with_instance(self, instance: Option<String>) -> Self {
+    Self { instance, ..self }
+  }
+
+  /// Name of `file` of app `instance`,
+  /// e.g. `out-blue.log` of `out.log`
+  pub(crate) fn instance_file(
+    file: &str,
+    instance: Option<&str>,
+  ) -> String {
+    let Some(instance) = instance else {
+      return file.to_owned();
+    };
+    match file.rsplit_once('.') {
+      Some((name, ext)) => format!("{name}-{instance}.{ext}"),
+      None => format!("{file}-{instance}"),
+    }
+  }
+
+  /// `out.log` of instance relative to work tree
+  pub(crate) fn out_log_file(&self) -> String {
+    Self::instance_file(Self::OUT_LOG, self.instance.as_deref())
+  }
+
+  /// `status.log` of instance relative to work tree
+  pub(crate) fn status_log_file(&self) -> String {
+    Self::instance_file(Self::STATUS_LOG, self.instance.as_deref())
+  }
+
+  /// `pipeline.pid` of instance relative to work tree
+  pub(crate) fn pipeline_pid_file(&self) -> String {
+    Self::instance_file(Self::PIPELINE_PID, self.instance.as_deref())
+  }
+
+  /// `exit.code` of instance relative to work tree
+  pub(crate) fn exit_code_file(&self) -> String {
+    Self::instance_file(Self::EXIT_CODE, self.instance.as_deref())
+  }
+
+  /// `events.log` of instance relative to work tree
+  pub(crate) fn events_log_file(&self) -> String {
+    Self::instance_file(Self::EVENTS_LOG, self.instance.as_deref())
+  }
+
+  /// Scp `host:path` target of `path` at server
+  pub(crate) fn remote_path(&self, path: &str) -> String {
+    format!("{}:{path}", scp_host(&self.ssh_address))
+  }
+
+  /// Git remote url of project bare repository
+  pub(crate) fn git_remote(&self) -> String {
+    let ssh_address = &scp_host(&self.ssh_address);
+    let git_dir = &self.git_dir;
+    match self.port {
+      Some(port) => format!(
+        "ssh://{ssh_address}:{port}/{}",
+        git_dir.trim_start_matches('/')
+      ),
+      None => format!("{ssh_address}:{git_dir}"),
     }
   }
 
+  /// Dir created while deploy is in progress
+  pub(crate) fn deploy_lock(&self) -> String {
+    format!("{}/{}", self.project_dir, Self::DEPLOY_LOCK)
+  }
+
+  /// Json lines of deploys recorded by `deploy --tag`
+  pub(crate) fn deploy_meta(&self) -> String {
+    format!("{}/{}", self.work_tree, Self::DEPLOY_META)
+  }
+
   pub(crate) fn out_log(&self) -> String {
-    format!("{}/{}", self.work_tree, Self::OUT_LOG)
+    format!("{}/{}", self.work_tree, self.out_log_file())
+  }
+
+  pub(crate) fn events_log(&self) -> String {
+    format!("{}/{}", self.work_tree, self.events_log_file())
+  }
+
+  pub(crate) fn exit_code(&self) -> String {
+    format!("{}/{}", self.work_tree, self.exit_code_file())
   }
 
   pub(crate) fn status_log(&self) -> String {
-    format!("{}/{}", self.work_tree, Self::STATUS_LOG)
+    format!("{}/{}", self.work_tree, self.status_log_file())
   }
 
   pub(crate) fn pipeline_pid(&self) -> String {
-    format!("{}/{}", self.work_tree, Self::PIPELINE_PID)
+    format!("{}/{}", self.work_tree, self.pipeline_pid_file())
+  }
+
+  pub(crate) fn pipeline_sh(&self) -> String {
+    format!("{}/{}", self.work_tree, Self::PIPELINE_SH)
+  }
+
+  pub(crate) fn pipeline_sha256(&self) -> String {
+    format!("{}/{}", self.work_tree, Self::PIPELINE_SHA256)
+  }
+
+  pub(crate) fn stage_logs(&self) -> String {
+    format!("{}/{}", self.work_tree, Self::STAGE_LOGS)
+  }
+
+  /// Log of `stage` output relative to work tree
+  pub(crate) fn stage_log_file(stage: &str) -> String {
+    format!("{}/{stage}.log", Self::STAGE_LOGS)
+  }
+
+  pub(crate) fn stage_log(&self, stage: &str) -> String {
+    format!("{}/{}", self.work_tree, Self::stage_log_file(stage))
+  }
+}
+
+/// Splits ssh `address` to `user@` prefix and IPv6 literal host
+/// without brackets, `None` if host isn't IPv6 literal
+fn split_ipv6(address: &str) -> Option<(&str, &str)> {
+  let at = address.rfind('@').map_or(0, |i| i + 1);
+  let (login, host) = address.split_at(at);
+  let host = host
+    .strip_prefix('[')
+    .and_then(|host| host.strip_suffix(']'))
+    .unwrap_or(host);
+  let ip = host.split_once('%').map_or(host, |(ip, _zone)| ip);
+  ip.parse::<Ipv6Addr>().is_ok().then_some((login, host))
+}
+
+/// Whether host of ssh `address` is IPv6 literal
+pub(crate) fn is_ipv6(address: &str) -> bool {
+  split_ipv6(address).is_some()
+}
+
+/// Ssh `address` with brackets of IPv6 host stripped
+pub(crate) fn ssh_host(address: &str) -> String {
+  match split_ipv6(address) {
+    Some((login, host)) => format!("{login}{host}"),
+    None => address.to_owned(),
+  }
+}
+
+/// Ssh `address` for `host:path` targets,
+/// IPv6 host is bracketed not to mix its colons with path one
+pub(crate) fn scp_host(address: &str) -> String {
+  match split_ipv6(address) {
+    Some((login, host)) => format!("{login}[{host}]"),
+    None => address.to_owned(),
+  }
+}
+
+/// Resolved fields and paths as `key: value` lines
+impl fmt::Display for Env {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "project: {}", self.project)?;
+    writeln!(f, "ssh_address: {}", self.ssh_address)?;
+    if let Some(user) = &self.user {
+      writeln!(f, "user: {user}")?;
+    }
+    if let Some(port) = self.port {
+      writeln!(f, "port: {port}")?;
+    }
+    writeln!(f, "retries: {}", self.retries)?;
+    writeln!(f, "project_dir: {}", self.project_dir)?;
+    writeln!(f, "git_dir: {}", self.git_dir)?;
+    writeln!(f, "git_remote: {}", self.git_remote())?;
+    writeln!(f, "work_tree: {}", self.work_tree)?;
+    writeln!(f, "out_log: {}", self.out_log())?;
+    writeln!(f, "status_log: {}", self.status_log())?;
+    writeln!(f, "pipeline_pid: {}", self.pipeline_pid())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn displays_env_fields() {
+    let mut target = Target::new("user@host".to_owned());
+    target.port = Some(2222);
+    let env = Env::new("app", &target);
+    assert_eq!(
+      env.to_string(),
+      "project: app
+ssh_address: user@host
+port: 2222
+retries: 0
+project_dir: ~/.hmd/app
+git_dir: ~/.hmd/app/git
+git_remote: ssh://user@host:2222/~/.hmd/app/git
+work_tree: ~/.hmd/app/work-tree
+out_log: ~/.hmd/app/work-tree/out.log
+status_log: ~/.hmd/app/work-tree/status.log
+pipeline_pid: ~/.hmd/app/work-tree/pipeline.pid
+"
+    );
+  }
+
+  #[test]
+  fn instances_have_distinct_pid_and_logs() {
+    let target = Target::new("host".to_owned());
+    let blue =
+      Env::new("app", &target).with_instance(Some("blue".into()));
+    let green =
+      Env::new("app", &target).with_instance(Some("green".into()));
+    let dir = "~/.hmd/app/work-tree";
+    assert_eq!(blue.out_log(), format!("{dir}/out-blue.log"));
+    assert_eq!(blue.status_log(), format!("{dir}/status-blue.log"));
+    assert_eq!(
+      blue.pipeline_pid(),
+      format!("{dir}/pipeline-blue.pid")
+    );
+    assert_ne!(blue.out_log(), green.out_log());
+    assert_ne!(blue.status_log(), green.status_log());
+    assert_ne!(blue.pipeline_pid(), green.pipeline_pid());
+    assert_eq!(blue.exit_code(), format!("{dir}/exit-blue.code"));
+    assert_eq!(blue.events_log(), format!("{dir}/events-blue.log"));
+    assert_ne!(blue.exit_code(), green.exit_code());
+    assert_ne!(blue.events_log(), green.events_log());
+    let default = Env::new("app", &target);
+    assert_eq!(default.pipeline_pid(), format!("{dir}/pipeline.pid"));
+    assert_eq!(default.exit_code(), format!("{dir}/exit.code"));
+    assert_eq!(default.events_log(), format!("{dir}/events.log"));
+  }
+
+  #[test]
+  fn brackets_ipv6_host_of_scp_targets() {
+    let remote_path = |address: &str| {
+      let env = Env::new("app", &Target::new(address.to_owned()));
+      env.remote_path("app/out.log")
+    };
+    assert_eq!(
+      remote_path("user@10.0.0.1"),
+      "user@10.0.0.1:app/out.log"
+    );
+    assert_eq!(remote_path("example.com"), "example.com:app/out.log");
+    assert_eq!(remote_path("user@::1"), "user@[::1]:app/out.log");
+    assert_eq!(
+      remote_path("[fe80::1%eth0]"),
+      "[fe80::1%eth0]:app/out.log"
+    );
+    assert_eq!(ssh_host("user@[::1]"), "user@::1");
+    assert!(!is_ipv6("user@10.0.0.1"));
+    let mut target = Target::new("2001:db8::2".to_owned());
+    target.port = Some(2222);
+    assert_eq!(
+      Env::new("app", &target).git_remote(),
+      "ssh://[2001:db8::2]:2222/~/.hmd/app/git"
+    );
+  }
+
+  #[test]
+  fn display_skips_unset_port() {
+    let env = Env::new("app", &Target::new("host".to_owned()));
+    let display = env.to_string();
+    assert!(display
+      .starts_with("project: app\nssh_address: host\nretries: 0\n"));
+    assert!(display.ends_with(
+      "pipeline_pid: ~/.hmd/app/work-tree/pipeline.pid\n"
+    ));
   }
 }
@@ -0,0 +1,189 @@
+use std::{error::Error, fmt, io, process::ExitStatus};
+
+use crate::hmd_config_yml::HMD_CONFIG_YML;
+
+/// Known failures of hmd
+///
+/// Travels inside `io::Error`, so `main` can downcast it
+/// to choose exit code.
+#[derive(Debug)]
+pub enum HmdError {
+  ProjectNotProvided,
+  SshAddressNotProvided,
+  TargetNotFound(String),
+  ConfigParse {
+    file: String,
+    message: String,
+  },
+  ArtifactNotFound(String),
+  RefNotFound(String),
+  CommandFailed {
+    status: ExitStatus,
+  },
+  /// ssh or scp failed to connect to server
+  ConnectionFailed,
+  Interrupted,
+  Io(io::Error),
+}
+
+impl HmdError {
+  /// Finds `HmdError` wrapped by `err`
+  #[must_use]
+  pub fn downcast(err: &io::Error) -> Option<&Self> {
+    err.get_ref()?.downcast_ref()
+  }
+
+  /// Variant name in snake case for machine readable output
+  #[must_use]
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::ProjectNotProvided => "project_not_provided",
+      Self::SshAddressNotProvided => "ssh_address_not_provided",
+      Self::TargetNotFound(_) => "target_not_found",
+      Self::ConfigParse { .. } => "config_parse",
+      Self::ArtifactNotFound(_) => "artifact_not_found",
+      Self::RefNotFound(_) => "ref_not_found",
+      Self::CommandFailed { .. } => "command_failed",
+      Self::ConnectionFailed => "connection_failed",
+      Self::Interrupted => "interrupted",
+      Self::Io(_) => "io",
+    }
+  }
+
+  /// Process exit code for error
+  ///
+  /// Failed command passes its own exit code.
+  #[must_use]
+  pub fn exit_code(&self) -> u8 {
+    match self {
+      Self::ProjectNotProvided
+      | Self::SshAddressNotProvided
+      | Self::TargetNotFound(_) => 2,
+      Self::ConfigParse { .. } => 3,
+      Self::ConnectionFailed => 4,
+      Self::Interrupted => 130,
+      Self::CommandFailed { status } => status
+        .code()
+        .and_then(|code| u8::try_from(code).ok())
+        .filter(|&code| code != 0)
+        .unwrap_or(1),
+      Self::ArtifactNotFound(_)
+      | Self::RefNotFound(_)
+      | Self::Io(_) => 1,
+    }
+  }
+}
+
+impl fmt::Display for HmdError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::ProjectNotProvided => write!(f, "Project not provided"),
+      Self::SshAddressNotProvided => {
+        write!(f, "SSH address not provided")
+      }
+      Self::TargetNotFound(name) => {
+        write!(f, "No target `{name}` in {HMD_CONFIG_YML}")
+      }
+      Self::ConfigParse { file, message } => {
+        write!(f, "Can't read {file}. Invalid format: {message}")
+      }
+      Self::ArtifactNotFound(artifact) => {
+        write!(f, "Artifact `{artifact}` not found")
+      }
+      Self::RefNotFound(git_ref) => {
+        write!(f, "Ref `{git_ref}` not found")
+      }
+      Self::CommandFailed { status } => {
+        write!(f, "Process terminated with {status}")
+      }
+      Self::ConnectionFailed => write!(f, "Can't connect to server"),
+      Self::Interrupted => write!(f, "Interrupted"),
+      Self::Io(err) => write!(f, "{err}"),
+    }
+  }
+}
+
+impl Error for HmdError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      Self::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for HmdError {
+  fn from(err: io::Error) -> Self {
+    Self::Io(err)
+  }
+}
+
+impl From<HmdError> for io::Error {
+  fn from(err: HmdError) -> Self {
+    match err {
+      HmdError::Io(err) => err,
+      HmdError::ConfigParse { .. } => {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+      }
+      HmdError::ArtifactNotFound(_) | HmdError::RefNotFound(_) => {
+        io::Error::new(io::ErrorKind::NotFound, err)
+      }
+      HmdError::Interrupted => {
+        io::Error::new(io::ErrorKind::Interrupted, err)
+      }
+      err => io::Error::other(err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[cfg(unix)]
+  use std::os::unix::process::ExitStatusExt;
+
+  use super::*;
+
+  #[cfg(unix)]
+  fn command_failed(code: i32) -> HmdError {
+    HmdError::CommandFailed {
+      status: ExitStatus::from_raw(code << 8),
+    }
+  }
+
+  #[test]
+  fn exit_codes() {
+    assert_eq!(HmdError::ProjectNotProvided.exit_code(), 2);
+    assert_eq!(HmdError::SshAddressNotProvided.exit_code(), 2);
+    assert_eq!(HmdError::TargetNotFound("x".into()).exit_code(), 2);
+    let parse = HmdError::ConfigParse {
+      file: "hmd.yml".into(),
+      message: "bad".into(),
+    };
+    assert_eq!(parse.exit_code(), 3);
+    assert_eq!(HmdError::ArtifactNotFound("x".into()).exit_code(), 1);
+    assert_eq!(HmdError::RefNotFound("x".into()).exit_code(), 1);
+    assert_eq!(HmdError::ConnectionFailed.exit_code(), 4);
+    assert_eq!(HmdError::Interrupted.exit_code(), 130);
+    assert_eq!(HmdError::Io(io::Error::other("x")).exit_code(), 1);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn failed_command_passes_its_exit_code() {
+    assert_eq!(command_failed(42).exit_code(), 42);
+    assert_eq!(command_failed(0).exit_code(), 1);
+    let killed = HmdError::CommandFailed {
+      status: ExitStatus::from_raw(9),
+    };
+    assert_eq!(killed.exit_code(), 1);
+  }
+
+  #[test]
+  fn survives_io_error_round_trip() {
+    let err = io::Error::from(HmdError::TargetNotFound("x".into()));
+    let hmd_error = HmdError::downcast(&err).unwrap();
+    assert_eq!(hmd_error.exit_code(), 2);
+    let err = io::Error::from(HmdError::ArtifactNotFound("x".into()));
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+  }
+}
@@ -0,0 +1,187 @@
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use crate::error::HmdError;
+
+/// Expands `pattern` with `*`, `?` and `**` to sorted matching paths
+///
+/// Literal path passes through as is even if it doesn't exist.
+///
+/// # Errors
+///
+/// Returns `ArtifactNotFound` if pattern matches nothing
+pub(crate) fn expand(pattern: &str) -> io::Result<Vec<String>> {
+  if !is_pattern(pattern) {
+    return Ok(vec![pattern.to_owned()]);
+  }
+  let (root, relative) = match pattern.strip_prefix('/') {
+    Some(relative) => (PathBuf::from("/"), relative),
+    None => (PathBuf::new(), pattern),
+  };
+  let components = relative
+    .split('/')
+    .filter(|c| !c.is_empty() && *c != ".")
+    .collect::<Vec<_>>();
+  let mut paths = Vec::new();
+  walk(&root, &components, &mut paths)?;
+  if paths.is_empty() {
+    return Err(
+      HmdError::ArtifactNotFound(pattern.to_owned()).into(),
+    );
+  }
+  let mut paths = paths
+    .into_iter()
+    .map(|path| path.to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+  paths.sort();
+  paths.dedup();
+  Ok(paths)
+}
+
+pub(crate) fn is_pattern(value: &str) -> bool {
+  value.contains(['*', '?'])
+}
+
+fn walk(
+  dir: &Path,
+  components: &[&str],
+  paths: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+  let Some((&component, rest)) = components.split_first() else {
+    paths.push(dir.to_owned());
+    return Ok(());
+  };
+  if component == "**" {
+    walk(dir, rest, paths)?;
+    for subdir in entries(dir)?.into_iter().filter(|p| p.is_dir()) {
+      walk(&subdir, components, paths)?;
+    }
+    return Ok(());
+  }
+  let matched = if is_pattern(component) {
+    entries(dir)?
+      .into_iter()
+      .filter(|path| {
+        path
+          .file_name()
+          .and_then(|name| name.to_str())
+          .is_some_and(|name| matches(component, name))
+      })
+      .collect()
+  } else {
+    vec![dir.join(component)]
+  };
+  for path in matched {
+    if rest.is_empty() && path.exists() || path.is_dir() {
+      walk(&path, rest, paths)?;
+    }
+  }
+  Ok(())
+}
+
+/// Entries of `dir` without hidden ones
+fn entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+  let read_dir = if dir.as_os_str().is_empty() {
+    fs::read_dir(".")?
+  } else {
+    fs::read_dir(dir)?
+  };
+  let mut entries = Vec::new();
+  for entry in read_dir {
+    let name = entry?.file_name();
+    if !name.to_string_lossy().starts_with('.') {
+      entries.push(dir.join(name));
+    }
+  }
+  Ok(entries)
+}
+
+/// Matches file `name` by `pattern` with `*` and `?`
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+  let pattern = pattern.chars().collect::<Vec<_>>();
+  let name = name.chars().collect::<Vec<_>>();
+  matches_chars(&pattern, &name)
+}
+
+fn matches_chars(pattern: &[char], name: &[char]) -> bool {
+  match (pattern.split_first(), name.split_first()) {
+    (None, None) => true,
+    (Some(('*', rest)), _) => {
+      matches_chars(rest, name)
+        || !name.is_empty() && matches_chars(pattern, &name[1..])
+    }
+    (Some(('?', rest)), Some((_, name))) => matches_chars(rest, name),
+    (Some((p, rest)), Some((c, name))) if p == c => {
+      matches_chars(rest, name)
+    }
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_dir::TestDir;
+
+  /// Temp dir of test `name` with empty `files`
+  fn dir_with(name: &str, files: &[&str]) -> TestDir {
+    let dir = TestDir::new(name);
+    for file in files {
+      dir.file(file);
+    }
+    dir
+  }
+
+  #[test]
+  fn passes_literal_path_through() {
+    assert_eq!(expand("no/such/file").unwrap(), ["no/such/file"]);
+  }
+
+  #[test]
+  fn expands_patterns_sorted() {
+    let dir = dir_with(
+      "glob-expand",
+      &["b.js", "a.js", "a.css", "lib/c.js", "lib/deep/d.js"],
+    );
+    assert_eq!(
+      expand(&dir.path("*.js")).unwrap(),
+      [dir.path("a.js"), dir.path("b.js")]
+    );
+    assert_eq!(
+      expand(&dir.path("?.css")).unwrap(),
+      [dir.path("a.css")]
+    );
+    assert_eq!(
+      expand(&dir.path("**/*.js")).unwrap(),
+      [
+        dir.path("a.js"),
+        dir.path("b.js"),
+        dir.path("lib/c.js"),
+        dir.path("lib/deep/d.js"),
+      ]
+    );
+  }
+
+  #[test]
+  fn fails_if_pattern_matches_nothing() {
+    let dir = dir_with("glob-none", &["a.js"]);
+    let err = expand(&dir.path("*.rs")).unwrap_err();
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::ArtifactNotFound(pattern))
+        if *pattern == dir.path("*.rs")
+    ));
+  }
+
+  #[test]
+  fn matches_names() {
+    assert!(matches("*.js", "app.js"));
+    assert!(matches("*", ""));
+    assert!(matches("release/*", "release/1.0"));
+    assert!(matches("v?.*", "v1.2"));
+    assert!(!matches("*.js", "app.jsx"));
+    assert!(!matches("v?", "v"));
+  }
+}
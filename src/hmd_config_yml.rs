@@ -1,41 +1,527 @@
-use std::{fs, io};
+use std::{ffi::OsString, fs, io, path::PathBuf};
 
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
-use crate::{other_err, HMD_ROOT};
+use crate::{error::HmdError, glob, other_err, HMD_ROOT};
 
-pub(crate) const HMD_CONFIG_YML: &str = "~/.hmd/config.yml";
+pub const HMD_CONFIG_YML: &str = "~/.hmd/config.yml";
+pub const DEFAULT_TARGET: &str = "default";
+const DEFAULT_REMOTE_SHELL: &str = "bash";
+const DEFAULT_PROFILE_FILE: &str = ".profile";
+/// Pattern of `branches` matching any other branch
+const FALLBACK_BRANCH: &str = "*";
 
-#[derive(Debug, Deserialize, Serialize)]
-pub(crate) struct HmdConfigYml {
-  pub(crate) ssh_address: String,
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HmdConfigYml {
+  /// Address of default target, kept for configs without targets
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub ssh_address: Option<String>,
+  /// Connection retries of targets without own `retries`
+  #[serde(default, skip_serializing_if = "is_zero")]
+  pub retries: u32,
+  /// Scope of targets without own `scope`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+  /// Upload limit of targets without own `upload_limit`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub upload_limit: Option<u32>,
+  /// Compression of targets without own `compress`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub compress: Option<bool>,
+  /// Ssh options prepended to options of every target
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub ssh_options: Vec<String>,
+  /// Jump host of targets without own `jump_host`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub jump_host: Option<String>,
+  /// Remote shell of targets without own `remote_shell`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub remote_shell: Option<String>,
+  /// Profile file of targets without own `profile_file`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub profile_file: Option<String>,
+  #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+  pub targets: IndexMap<String, Target>,
+  /// Defaults of projects by name
+  #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+  pub projects: IndexMap<String, ProjectConfig>,
+  /// Deploy targets of git branches by name or pattern,
+  /// `*` for other branches
+  #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+  pub branches: IndexMap<String, String>,
+}
+
+/// Named deploy server
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Target {
+  pub ssh_address: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub port: Option<u16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub remote_root: Option<String>,
+  /// Times to retry ssh and scp on connection failure
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub retries: Option<u32>,
+  /// Namespace of projects inside `remote_root`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub scope: Option<String>,
+  /// Artifacts upload bandwidth limit in KB/s
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub upload_limit: Option<u32>,
+  /// Compress ssh and scp traffic, helps on slow links
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub compress: Option<bool>,
+  /// Extra `-o` options of ssh and scp,
+  /// e.g. `ServerAliveInterval=30`
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub ssh_options: Vec<String>,
+  /// Bastion host to reach server through, `[user@]host[:port]`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub jump_host: Option<String>,
+  /// User to log in as instead of one of `ssh_address`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub user: Option<String>,
+  /// Interpreter of `pipeline.sh`, `bash` by default
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub remote_shell: Option<String>,
+  /// File sourced before remote commands, `.profile` by default,
+  /// empty one isn't sourced
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub profile_file: Option<String>,
+}
+
+/// Defaults of one project, local `hmd.yml` overrides them
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ProjectConfig {
+  /// Target used without `--target` and `hmd.yml`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub target: Option<String>,
+  /// Overrides `remote_root` of any target
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub remote_root: Option<String>,
 }
 
 impl HmdConfigYml {
-  pub(crate) fn new(ssh_address: String) -> Self {
-    Self { ssh_address }
+  #[must_use]
+  pub fn new(ssh_address: String) -> Self {
+    Self {
+      ssh_address: Some(ssh_address),
+      ..Self::default()
+    }
+  }
+
+  /// Selects target by `name` or default one
+  ///
+  /// Default target is bare `ssh_address` field
+  /// or target named `default`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if target not found
+  pub fn target(&self, name: Option<&str>) -> io::Result<Target> {
+    let target = match name {
+      Some(name) => {
+        self.targets.get(name).cloned().ok_or_else(|| {
+          io::Error::from(HmdError::TargetNotFound(name.to_owned()))
+        })?
+      }
+      None => self
+        .ssh_address
+        .clone()
+        .map(Target::new)
+        .or_else(|| self.targets.get(DEFAULT_TARGET).cloned())
+        .ok_or_else(|| {
+          other_err(format!("No default target in {HMD_CONFIG_YML}"))
+        })?,
+    };
+    Ok(self.with_defaults(target))
+  }
+
+  /// Unnamed target at `ssh_address` with config defaults
+  #[must_use]
+  pub fn target_at(&self, ssh_address: String) -> Target {
+    self.with_defaults(Target::new(ssh_address))
+  }
+
+  /// Selects target named in `projects` entry of `project`
+  /// or default one
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if target not found
+  pub fn project_target(
+    &self,
+    project: Option<&str>,
+  ) -> io::Result<Target> {
+    let name = self
+      .project(project)
+      .and_then(|project| project.target.as_deref());
+    self.target(name)
+  }
+
+  /// Applies `projects` entry of `project` to `target`
+  #[must_use]
+  pub fn with_project(
+    &self,
+    project: Option<&str>,
+    target: Target,
+  ) -> Target {
+    let Some(project) = self.project(project) else {
+      return target;
+    };
+    Target {
+      remote_root: project.remote_root.clone().or(target.remote_root),
+      ..target
+    }
+  }
+
+  /// Target name of `branch` by `branches`, none without them
+  ///
+  /// First matching pattern wins, bare `*` is a fallback
+  /// wherever it's listed.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if nothing matches `branch`
+  pub fn branch_target(
+    &self,
+    branch: &str,
+  ) -> io::Result<Option<&str>> {
+    if self.branches.is_empty() {
+      return Ok(None);
+    }
+    let fallback = FALLBACK_BRANCH;
+    self
+      .branches
+      .iter()
+      .find(|(pattern, _)| {
+        *pattern != fallback && glob::matches(pattern, branch)
+      })
+      .or_else(|| self.branches.get_key_value(fallback))
+      .map(|(_, target)| Some(target.as_str()))
+      .ok_or_else(|| {
+        other_err(format!(
+          "No target for branch `{branch}` in branches \
+          of {HMD_CONFIG_YML}. Add `{fallback}` for other branches"
+        ))
+      })
+  }
+
+  fn project(&self, project: Option<&str>) -> Option<&ProjectConfig> {
+    self.projects.get(project?)
+  }
+
+  fn with_defaults(&self, target: Target) -> Target {
+    Target {
+      retries: target.retries.or(Some(self.retries)),
+      scope: target.scope.or_else(|| self.scope.clone()),
+      upload_limit: target.upload_limit.or(self.upload_limit),
+      compress: target.compress.or(self.compress),
+      jump_host: target.jump_host.or_else(|| self.jump_host.clone()),
+      remote_shell: target
+        .remote_shell
+        .or_else(|| self.remote_shell.clone()),
+      profile_file: target
+        .profile_file
+        .or_else(|| self.profile_file.clone()),
+      ssh_options: self
+        .ssh_options
+        .iter()
+        .cloned()
+        .chain(target.ssh_options)
+        .collect(),
+      ..target
+    }
   }
 }
 
-pub(crate) fn read() -> io::Result<HmdConfigYml> {
-  let home = std::env::var("HOME").map_err(other_err)?;
-  let yml =
-    fs::read_to_string(HMD_CONFIG_YML.replacen('~', &home, 1))?;
+impl Target {
+  #[must_use]
+  pub fn new(ssh_address: String) -> Self {
+    Self {
+      ssh_address,
+      port: None,
+      remote_root: None,
+      retries: None,
+      scope: None,
+      upload_limit: None,
+      compress: None,
+      ssh_options: Vec::new(),
+      jump_host: None,
+      user: None,
+      remote_shell: None,
+      profile_file: None,
+    }
+  }
+
+  /// Ssh address with `user` applied and user to pass by `-l`
+  ///
+  /// `user` replaces one of address or prefixes host name or ip.
+  /// Alias of ssh config, a name without dots, is kept as is,
+  /// so `-l` overrides its configured user.
+  #[must_use]
+  pub fn login(&self) -> (String, Option<String>) {
+    let address = &self.ssh_address;
+    let Some(user) = &self.user else {
+      return (address.clone(), None);
+    };
+    match address.split_once('@') {
+      Some((_, host)) => (format!("{user}@{host}"), None),
+      None if is_alias(address) => {
+        (address.clone(), Some(user.clone()))
+      }
+      None => (format!("{user}@{address}"), None),
+    }
+  }
+
+  #[must_use]
+  pub fn remote_root(&self) -> &str {
+    self.remote_root.as_deref().unwrap_or(HMD_ROOT)
+  }
+
+  #[must_use]
+  pub fn remote_shell(&self) -> &str {
+    self.remote_shell.as_deref().unwrap_or(DEFAULT_REMOTE_SHELL)
+  }
+
+  #[must_use]
+  pub fn profile_file(&self) -> &str {
+    self.profile_file.as_deref().unwrap_or(DEFAULT_PROFILE_FILE)
+  }
+
+  /// Dir of projects, `remote_root` or its `scope` subdir
+  #[must_use]
+  pub fn projects_dir(&self) -> String {
+    let remote_root = self.remote_root();
+    match &self.scope {
+      Some(scope) => format!("{remote_root}/{scope}"),
+      None => remote_root.to_owned(),
+    }
+  }
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_zero(n: &u32) -> bool {
+  *n == 0
+}
+
+/// Resolved local path of `~/.hmd/config.yml`
+///
+/// # Errors
+///
+/// Returns an error if home dir is unknown
+pub fn path() -> io::Result<PathBuf> {
+  home_path(HMD_CONFIG_YML)
+}
+
+/// Reads `~/.hmd/config.yml`
+///
+/// # Errors
+///
+/// Returns an error if config is missing or invalid
+pub fn read() -> io::Result<HmdConfigYml> {
+  let path = path()?;
+  let yml = fs::read_to_string(&path)?;
   let hmd_config_yml: HmdConfigYml = serde_yaml::from_str(&yml)
-    .map_err(|err| {
-      other_err(format!("Can't read hmd config: {err}"))
+    .map_err(|err| HmdError::ConfigParse {
+      file: path.display().to_string(),
+      message: err.to_string(),
     })?;
   Ok(hmd_config_yml)
 }
 
-pub(crate) fn write(ssh_address: String) -> io::Result<()> {
-  let hmd_config_yml = HmdConfigYml::new(ssh_address);
-  let yml =
-    serde_yaml::to_string(&hmd_config_yml).map_err(|err| {
-      other_err(format!("Can't serialize hmd config: {err}"))
-    })?;
-  let home = std::env::var("HOME").map_err(other_err)?;
-  fs::create_dir_all(HMD_ROOT.replacen('~', &home, 1))?;
-  fs::write(HMD_CONFIG_YML.replacen('~', &home, 1), yml)?;
+/// Writes `~/.hmd/config.yml` creating `~/.hmd`
+///
+/// # Errors
+///
+/// Returns an error if config can't be written
+pub fn write(hmd_config_yml: &HmdConfigYml) -> io::Result<()> {
+  let yml = to_string(hmd_config_yml)?;
+  fs::create_dir_all(home_path(HMD_ROOT)?)?;
+  fs::write(path()?, yml)?;
   Ok(())
 }
+
+/// Serializes config to yaml
+///
+/// # Errors
+///
+/// Returns an error if config can't be serialized
+pub fn to_string(
+  hmd_config_yml: &HmdConfigYml,
+) -> io::Result<String> {
+  serde_yaml::to_string(hmd_config_yml).map_err(|err| {
+    other_err(format!("Can't serialize hmd config: {err}"))
+  })
+}
+
+/// Resolves `~/` prefixed local `path` with native separators
+fn home_path(path: &str) -> io::Result<PathBuf> {
+  let mut home_path = home_dir()?;
+  home_path.extend(path.trim_start_matches("~/").split('/'));
+  Ok(home_path)
+}
+
+/// Home dir from `HOME` or `USERPROFILE` on Windows
+fn home_dir() -> io::Result<PathBuf> {
+  home_dir_of(|name| std::env::var_os(name))
+}
+
+/// Home dir from environment read by `var`
+fn home_dir_of(
+  var: impl Fn(&str) -> Option<OsString>,
+) -> io::Result<PathBuf> {
+  var("HOME")
+    .or_else(|| var("USERPROFILE"))
+    .map(PathBuf::from)
+    .ok_or(other_err("Can't find home dir: no HOME or USERPROFILE"))
+}
+
+/// Whether `host` is likely alias of ssh config, not host name or ip
+fn is_alias(host: &str) -> bool {
+  !host.contains(['.', ':']) && host != "localhost"
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(yml: &str) -> HmdConfigYml {
+    serde_yaml::from_str(yml).unwrap()
+  }
+
+  fn login(ssh_address: &str) -> (String, Option<String>) {
+    Target {
+      user: Some("root".to_owned()),
+      ..Target::new(ssh_address.to_owned())
+    }
+    .login()
+  }
+
+  #[test]
+  fn user_prefixes_host_without_user() {
+    assert_eq!(
+      login("example.com"),
+      ("root@example.com".into(), None)
+    );
+    assert_eq!(login("10.0.0.2"), ("root@10.0.0.2".into(), None));
+    assert_eq!(login("localhost"), ("root@localhost".into(), None));
+  }
+
+  #[test]
+  fn user_overrides_user_of_address() {
+    assert_eq!(
+      login("deploy@example.com"),
+      ("root@example.com".into(), None)
+    );
+    assert_eq!(login("deploy@box"), ("root@box".into(), None));
+  }
+
+  #[test]
+  fn user_of_alias_is_passed_by_flag() {
+    assert_eq!(login("box"), ("box".into(), Some("root".into())));
+    let target = Target::new("box".to_owned());
+    assert_eq!(target.login(), ("box".into(), None));
+  }
+
+  #[test]
+  fn branch_selects_first_matching_target() {
+    let config = config(
+      "branches:\n  '*': staging\n  main: prod\n  release/*: prod\n",
+    );
+    let target = |branch| config.branch_target(branch).unwrap();
+    assert_eq!(target("main"), Some("prod"));
+    assert_eq!(target("release/1.2"), Some("prod"));
+    assert_eq!(target("feature/login"), Some("staging"));
+    assert_eq!(target(""), Some("staging"));
+  }
+
+  #[test]
+  fn branch_without_match_and_fallback_fails() {
+    let config = config("branches:\n  main: prod\n");
+    let err = config.branch_target("dev").unwrap_err();
+    assert!(err
+      .to_string()
+      .starts_with("No target for branch `dev`"));
+    assert_eq!(
+      HmdConfigYml::default().branch_target("dev").unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn selects_named_and_default_targets() {
+    let yml = "ssh_address: main\ntargets:\n  prod:\n    ssh_address: prod\n  default:\n    ssh_address: other\n";
+    let config = config(yml);
+    assert_eq!(
+      config.target(Some("prod")).unwrap().ssh_address,
+      "prod"
+    );
+    assert_eq!(config.target(None).unwrap().ssh_address, "main");
+    let config = HmdConfigYml {
+      ssh_address: None,
+      ..config
+    };
+    assert_eq!(config.target(None).unwrap().ssh_address, "other");
+  }
+
+  #[test]
+  fn fails_on_missing_target() {
+    let err = config("ssh_address: main\n")
+      .target(Some("prod"))
+      .unwrap_err();
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::TargetNotFound(name)) if name == "prod"
+    ));
+    assert!(HmdConfigYml::default().target(None).is_err());
+  }
+
+  #[test]
+  fn target_inherits_config_defaults() {
+    let yml = "retries: 3\nscope: team\nssh_options: [A=1]\ntargets:\n  prod:\n    ssh_address: prod\n    scope: ops\n    ssh_options: [B=2]\n";
+    let target = config(yml).target(Some("prod")).unwrap();
+    assert_eq!(target.retries, Some(3));
+    assert_eq!(target.scope.as_deref(), Some("ops"));
+    assert_eq!(target.ssh_options, ["A=1", "B=2"]);
+    assert_eq!(target.projects_dir(), "~/.hmd/ops");
+  }
+
+  #[test]
+  fn project_config_overrides_target() {
+    let yml = "ssh_address: main\ntargets:\n  prod:\n    ssh_address: prod\nprojects:\n  blog:\n    target: prod\n    remote_root: /srv/hmd\n";
+    let config = config(yml);
+    let target = config.project_target(Some("blog")).unwrap();
+    assert_eq!(target.ssh_address, "prod");
+    let target = config.with_project(Some("blog"), target);
+    assert_eq!(target.projects_dir(), "/srv/hmd");
+  }
+
+  #[test]
+  fn other_project_uses_default_target() {
+    let yml = "ssh_address: main\nprojects:\n  blog:\n    remote_root: /srv/hmd\n";
+    let config = config(yml);
+    for project in [Some("app"), None] {
+      let target = config.project_target(project).unwrap();
+      assert_eq!(target.ssh_address, "main");
+      let target = config.with_project(project, target);
+      assert_eq!(target.projects_dir(), "~/.hmd");
+    }
+  }
+
+  #[test]
+  fn home_dir_of_unix_and_windows() {
+    let unix =
+      |name: &str| (name == "HOME").then(|| "/home/u".into());
+    assert_eq!(home_dir_of(unix).unwrap(), PathBuf::from("/home/u"));
+    let windows = |name: &str| {
+      (name == "USERPROFILE").then(|| r"C:\Users\u".into())
+    };
+    assert_eq!(
+      home_dir_of(windows).unwrap(),
+      PathBuf::from(r"C:\Users\u")
+    );
+    assert!(home_dir_of(|_| None).is_err());
+  }
+}
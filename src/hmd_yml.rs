@@ -1,20 +1,175 @@
-use std::{error::Error, fs, io};
+use std::{
+  fmt::Display,
+  fs, io,
+  path::{Component, Path, PathBuf},
+};
 
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
-use crate::other_err;
+use crate::{
+  error::HmdError, glob, other_err, shell::shell_quote, InitOptions,
+};
 
-pub(crate) const HMD_YML: &str = "hmd.yml";
+pub const HMD_YML: &str = "hmd.yml";
+/// Alternative to `hmd.yml` preferred when both exist
+pub const HMD_TOML: &str = "hmd.toml";
+/// Key of `hmd.yml` with path of base file
+const EXTENDS: &str = "extends";
+/// Key of `hmd.yml` with `Settings`
+const SETTINGS: &str = "settings";
+const MAX_EXTENDS_DEPTH: usize = 8;
 
 #[derive(Debug, Deserialize, Serialize)]
-pub(crate) struct HmdYml {
-  pub(crate) ssh_address: String,
-  pub(crate) project: String,
+pub struct HmdYml {
+  pub ssh_address: String,
+  pub project: String,
   #[serde(default)]
-  pub(crate) artifacts: Vec<String>,
+  pub artifacts: Vec<String>,
+  #[serde(default, skip_serializing_if = "Settings::is_default")]
+  pub settings: Settings,
   #[serde(flatten)]
-  pub(crate) stages: IndexMap<String, String>,
+  pub stages: IndexMap<String, Stage>,
+}
+
+/// Options of deploy under `settings` key,
+/// so their names stay free for stages, e.g. `clean` or `timeout`
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Settings {
+  /// Paths or path components to skip from `artifacts`
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub artifact_excludes: Vec<String>,
+  /// Command to clean build output with `hmd clean --all`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub clean: Option<String>,
+  /// Seconds to kill pipeline after
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub timeout: Option<u64>,
+  /// Local command to pass before deploy, e.g. `cargo test`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub verify_local: Option<String>,
+  /// Times to retry failed stage before pipeline fails
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub stage_retries: Option<u32>,
+  /// How last stage is run
+  #[serde(default, skip_serializing_if = "Supervisor::is_nohup")]
+  pub supervisor: Supervisor,
+  /// Check of app availability after deploy
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub health_check: Option<HealthCheck>,
+  /// Slack or Discord incoming webhook to report pipeline result
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub webhook_url: Option<String>,
+  /// How sources get to server
+  #[serde(default, skip_serializing_if = "Vcs::is_git")]
+  pub vcs: Vcs,
+  /// `false` pushes without `--force`, as `deploy --no-force-push`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub force_push: Option<bool>,
+}
+
+/// Project type to choose default stages of `hmd.yml`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Template {
+  Rust,
+  Node,
+  Python,
+  Static,
+}
+
+/// Local file or dir uploaded to `remote` path
+///
+/// Relative `remote` is inside work tree,
+/// `/` and `~/` prefixed ones are outside of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+  pub local: String,
+  pub remote: String,
+}
+
+/// Stage command, bare or with options
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Stage {
+  Cmd(String),
+  Options {
+    cmd: String,
+    /// Dir relative to work tree to run `cmd` in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir: Option<String>,
+    /// Interpreter to run `cmd` with instead of pipeline bash,
+    /// e.g. `sh`, `python3`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell: Option<String>,
+    /// Times to retry failed `cmd`, overrides `stage_retries`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retries: Option<u32>,
+  },
+}
+
+/// Url requested from ssh server until it responds with 2xx
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HealthCheck {
+  pub url: String,
+  /// Times to retry failed request
+  #[serde(default = "HealthCheck::default_retries")]
+  pub retries: u32,
+  /// Seconds between requests
+  #[serde(default = "HealthCheck::default_interval")]
+  pub interval: u64,
+}
+
+impl HealthCheck {
+  fn default_retries() -> u32 {
+    10
+  }
+
+  fn default_interval() -> u64 {
+    3
+  }
+}
+
+/// Runner of last stage
+#[derive(
+  Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Supervisor {
+  /// Last stage is run by pipeline in background
+  #[default]
+  Nohup,
+  /// Last stage is run by `--user` systemd unit,
+  /// pipeline only restarts it
+  Systemd,
+}
+
+/// Version control of deployed sources
+#[derive(
+  Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Vcs {
+  /// Commit is pushed to server repo and checked out
+  #[default]
+  Git,
+  /// No git at all, work tree is made of artifacts only,
+  /// e.g. of static site
+  None,
+}
+
+impl Vcs {
+  #[allow(clippy::trivially_copy_pass_by_ref)]
+  fn is_git(&self) -> bool {
+    matches!(self, Self::Git)
+  }
+}
+
+impl Supervisor {
+  #[allow(clippy::trivially_copy_pass_by_ref)]
+  fn is_nohup(&self) -> bool {
+    matches!(self, Self::Nohup)
+  }
 }
 
 impl Default for HmdYml {
@@ -23,46 +178,603 @@ impl Default for HmdYml {
       project: String::default(),
       ssh_address: String::default(),
       artifacts: Vec::default(),
-      stages: [
-        ["lint", "cargo clippy"],
-        ["test", "cargo test"],
-        ["build", "cargo build --release"],
-        ["run", "cargo run --release"],
-      ]
-      .into_iter()
-      .map(|pair| pair.map(<_>::to_string).into())
-      .collect(),
+      settings: Settings::default(),
+      stages: default_stages(Template::Rust),
+    }
+  }
+}
+
+impl Settings {
+  fn is_default(&self) -> bool {
+    *self == Self::default()
+  }
+}
+
+impl Stage {
+  #[must_use]
+  pub fn cmd(&self) -> &str {
+    match self {
+      Self::Cmd(cmd) | Self::Options { cmd, .. } => cmd,
+    }
+  }
+
+  /// Own times to retry failed stage
+  #[must_use]
+  pub fn retries(&self) -> Option<u32> {
+    match self {
+      Self::Cmd(_) => None,
+      Self::Options { retries, .. } => *retries,
     }
   }
+
+  /// Bash command running `cmd` with `shell` at `dir`
+  #[must_use]
+  pub fn script(&self) -> String {
+    let Self::Options {
+      cmd, dir, shell, ..
+    } = self
+    else {
+      return self.cmd().to_owned();
+    };
+    let cmd = match shell {
+      Some(shell) => format!("{shell} -c {}", shell_quote(cmd)),
+      None => cmd.clone(),
+    };
+    match dir {
+      Some(dir) => {
+        let dir = shell_quote(dir);
+        // Subshell keeps pipeline shell in work tree,
+        // also when `cmd` fails
+        format!("( cd {dir} && {cmd} )")
+      }
+      None => cmd,
+    }
+  }
+}
+
+/// Default stages of project type
+fn default_stages(template: Template) -> IndexMap<String, Stage> {
+  let stages: &[[&str; 2]] = match template {
+    Template::Rust => &[
+      ["lint", "cargo clippy"],
+      ["test", "cargo test"],
+      ["build", "cargo build --release"],
+      ["run", "cargo run --release"],
+    ],
+    Template::Node => &[
+      ["install", "npm ci"],
+      ["test", "npm test"],
+      ["build", "npm run build"],
+      ["run", "npm start"],
+    ],
+    Template::Python => &[
+      ["install", "pip install -r requirements.txt"],
+      ["test", "python -m pytest"],
+      ["run", "python main.py"],
+    ],
+    Template::Static => &[["run", "python3 -m http.server 8080"]],
+  };
+  stages
+    .iter()
+    .map(|[name, cmd]| {
+      (name.to_string(), Stage::Cmd(cmd.to_string()))
+    })
+    .collect()
+}
+
+/// Stages of `options` in given order or `template` ones
+fn seed_stages(options: &InitOptions) -> IndexMap<String, Stage> {
+  if options.stages.is_empty() {
+    return default_stages(options.template);
+  }
+  options
+    .stages
+    .iter()
+    .map(|(name, cmd)| (name.clone(), Stage::Cmd(cmd.clone())))
+    .collect()
+}
+
+/// Detects project type by files in current dir
+///
+/// Falls back to `Template::Rust`.
+#[must_use]
+pub fn detect_template() -> Template {
+  detect_template_in(Path::new("."))
+}
+
+fn detect_template_in(dir: &Path) -> Template {
+  let exists = |file: &str| dir.join(file).exists();
+  if exists("Cargo.toml") {
+    Template::Rust
+  } else if exists("package.json") {
+    Template::Node
+  } else if exists("requirements.txt") || exists("pyproject.toml") {
+    Template::Python
+  } else if exists("index.html") {
+    Template::Static
+  } else {
+    Template::Rust
+  }
+}
+
+impl HmdYml {
+  /// Artifacts to upload with expanded globs without excluded ones
+  ///
+  /// Entry `local:remote` maps artifact to remote path,
+  /// glob matches and `remote` ending with `/` are put into it.
+  /// Exclude matches whole artifact path or any of its components,
+  /// e.g. `node_modules` excludes `web/node_modules`.
+  /// Dir with excluded paths inside is replaced by its other entries.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if glob matches nothing or entry is malformed
+  pub fn uploaded_artifacts(&self) -> io::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    for entry in &self.artifacts {
+      let (pattern, remote) = split_artifact(entry);
+      if pattern.is_empty() || remote == Some("") {
+        return Err(parse_error(
+          &found_config(),
+          format!("Invalid artifact `{entry}`, try `local:remote`"),
+        ));
+      }
+      let into_dir = glob::is_pattern(pattern)
+        || remote.is_some_and(|remote| remote.ends_with('/'));
+      for local in glob::expand(pattern)? {
+        let remote = match remote {
+          None => remote_artifact_path(&local),
+          Some(dir) if into_dir => {
+            let name = Path::new(&local)
+              .file_name()
+              .map(|name| name.to_string_lossy().into_owned())
+              .unwrap_or_default();
+            format!("{}/{name}", dir.trim_end_matches('/'))
+          }
+          Some(remote) => remote.to_owned(),
+        };
+        artifacts.push(Artifact { local, remote });
+      }
+    }
+    let mut uploaded = Vec::new();
+    for artifact in artifacts {
+      self.push_not_excluded(artifact, &mut uploaded)?;
+    }
+    Ok(uploaded)
+  }
+
+  /// Pushes `artifact` unless it's excluded, dir with excluded
+  /// paths inside is split to its entries, so `scp -r` skips them
+  fn push_not_excluded(
+    &self,
+    artifact: Artifact,
+    artifacts: &mut Vec<Artifact>,
+  ) -> io::Result<()> {
+    if self.is_excluded(&artifact.local) {
+      return Ok(());
+    }
+    let dir = Path::new(&artifact.local);
+    if !dir.is_dir() || !self.has_excluded_inside(dir)? {
+      artifacts.push(artifact);
+      return Ok(());
+    }
+    let mut names = fs::read_dir(dir)?
+      .map(|entry| {
+        Ok(entry?.file_name().to_string_lossy().into_owned())
+      })
+      .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    for name in names {
+      let entry = Artifact {
+        local: join_path(&artifact.local, &name),
+        remote: join_path(&artifact.remote, &name),
+      };
+      self.push_not_excluded(entry, artifacts)?;
+    }
+    Ok(())
+  }
+
+  /// Whether any path inside `dir` is excluded,
+  /// symlinked dirs aren't followed
+  fn has_excluded_inside(&self, dir: &Path) -> io::Result<bool> {
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      let path = entry.path();
+      if self.is_excluded(&path.to_string_lossy())
+        || entry.file_type()?.is_dir()
+          && self.has_excluded_inside(&path)?
+      {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+
+  /// Whether `artifact` path is skipped by `artifact_excludes`
+  pub(crate) fn is_excluded(&self, artifact: &str) -> bool {
+    let path = Path::new(artifact);
+    self.settings.artifact_excludes.iter().any(|exclude| {
+      let exclude = exclude.trim_end_matches('/');
+      path == Path::new(exclude)
+        || path.components().any(|c| c.as_os_str() == exclude)
+    })
+  }
+}
+
+/// `name` inside `dir`, just `name` if `dir` is empty
+fn join_path(dir: &str, name: &str) -> String {
+  match dir.trim_end_matches('/') {
+    "" => name.to_owned(),
+    dir => format!("{dir}/{name}"),
+  }
+}
+
+/// Local path and remote one of `local:remote` artifact `entry`
+///
+/// Colon of Windows drive, like `C:\dist`, doesn't separate them.
+fn split_artifact(entry: &str) -> (&str, Option<&str>) {
+  let has_drive = matches!(
+    entry.as_bytes(),
+    [letter, b':', b'\\' | b'/', ..] if letter.is_ascii_alphabetic()
+  );
+  let drive = if has_drive { 2 } else { 0 };
+  match entry[drive..].find(':') {
+    Some(i) => (&entry[..drive + i], Some(&entry[drive + i + 1..])),
+    None => (entry, None),
+  }
+}
+
+/// Path of artifact relative to work tree
+///
+/// Relative path is kept, absolute or with `..` is cut to file name.
+fn remote_artifact_path(artifact: &str) -> String {
+  let path = Path::new(artifact);
+  let is_relative = path
+    .components()
+    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+  if is_relative {
+    let path = path
+      .components()
+      .filter(|c| matches!(c, Component::Normal(_)))
+      .collect::<PathBuf>();
+    return path.to_string_lossy().into_owned();
+  }
+  path
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default()
+}
+
+/// Syntax of config file
+#[derive(Debug, Clone, Copy)]
+enum Format {
+  Yaml,
+  Toml,
+}
+
+impl Format {
+  /// Format of file at `path` by its extension
+  fn of(path: &Path) -> Self {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("toml") => Self::Toml,
+      _ => Self::Yaml,
+    }
+  }
+
+  /// Parses `text` of file at `path` to YAML value,
+  /// so `extends` merges the same way
+  fn parse(self, path: &Path, text: &str) -> io::Result<Value> {
+    if text.trim().is_empty() {
+      return Err(parse_error(
+        path,
+        "File is empty. Try `hmd init --force` to overwrite",
+      ));
+    }
+    match self {
+      Self::Yaml => {
+        if let Some(line) = tab_indented_line(text) {
+          return Err(parse_error(
+            path,
+            format!("YAML uses spaces, not tabs (line {line})"),
+          ));
+        }
+        serde_yaml::from_str(text)
+          .map_err(|err| parse_error(path, err))
+      }
+      Self::Toml => {
+        toml::from_str(text).map_err(|err| parse_error(path, err))
+      }
+    }
+  }
+
+  fn serialize(self, hmd_yml: &HmdYml) -> io::Result<String> {
+    match self {
+      Self::Yaml => serde_yaml::to_string(hmd_yml).map_err(other_err),
+      Self::Toml => toml::to_string(hmd_yml).map_err(other_err),
+    }
+  }
+
+  /// Line assigning string `value` to top-level `key`
+  fn field(self, key: &str, value: &str) -> io::Result<String> {
+    match self {
+      Self::Yaml => {
+        let value =
+          serde_yaml::to_string(value).map_err(other_err)?;
+        Ok(format!("{key}: {}", value.trim_end()))
+      }
+      Self::Toml => Ok(format!(
+        "{key} = {}",
+        toml::Value::String(value.to_owned())
+      )),
+    }
+  }
+
+  fn is_field(self, line: &str, key: &str) -> bool {
+    let Some(rest) = line.strip_prefix(key) else {
+      return false;
+    };
+    match self {
+      Self::Yaml => rest.starts_with(':'),
+      Self::Toml => rest.trim_start().starts_with('='),
+    }
+  }
+}
+
+/// Searches `hmd.toml` or `hmd.yml` in current dir and its parents
+///
+/// # Errors
+///
+/// Returns `NotFound` error if there is no config up to root
+pub fn find_hmd_yml() -> io::Result<PathBuf> {
+  find_hmd_yml_in(&std::env::current_dir()?)
 }
 
-pub(crate) fn read() -> io::Result<HmdYml> {
-  let yml = fs::read_to_string(HMD_YML).map_err(not_found_context)?;
-  let hmd_yml: HmdYml =
-    serde_yaml::from_str(&yml).map_err(parse_error_context)?;
+fn find_hmd_yml_in(dir: &Path) -> io::Result<PathBuf> {
+  dir
+    .ancestors()
+    .find_map(config_in)
+    .ok_or_else(|| not_found_context(io::ErrorKind::NotFound.into()))
+}
+
+/// Existing `hmd.toml` or `hmd.yml` of `dir`
+fn config_in(dir: &Path) -> Option<PathBuf> {
+  [HMD_TOML, HMD_YML]
+    .into_iter()
+    .map(|file| dir.join(file))
+    .find(|path| path.is_file())
+}
+
+/// Reads `hmd.yml` found by `find_hmd_yml`
+///
+/// # Errors
+///
+/// Returns an error if config is missing or invalid
+pub fn read() -> io::Result<HmdYml> {
+  read_from(&find_hmd_yml()?)
+}
+
+/// Reads `hmd.toml` or `hmd.yml` at `path`
+///
+/// # Errors
+///
+/// Returns an error if config is missing or invalid
+pub fn read_from(path: &Path) -> io::Result<HmdYml> {
+  let yml = fs::read_to_string(path).map_err(not_found_context)?;
+  let yml = Format::of(path).parse(path, &yml)?;
+  let yml = resolve_extends(path, yml, MAX_EXTENDS_DEPTH)?;
+  let hmd_yml: HmdYml = serde_yaml::from_value(yml)
+    .map_err(|err| parse_error(path, err))?;
   if hmd_yml.project.is_empty() {
-    return Err(parse_error_context(empty_project_error()));
+    return Err(parse_error(path, "Field `project` can't be empty"));
   }
   if hmd_yml.stages.is_empty() {
-    return Err(parse_error_context(no_stages_error()));
+    return Err(parse_error(path, "No stages"));
   }
+  check_stages(&hmd_yml.stages)
+    .map_err(|err| parse_error(path, err))?;
   Ok(hmd_yml)
 }
 
+/// Fails on empty stage command or name unsafe for shell
+/// and `logs/<stage>.log`, i.e. not of letters, digits, `_` and `-`
+fn check_stages(
+  stages: &IndexMap<String, Stage>,
+) -> Result<(), String> {
+  for (name, stage) in stages {
+    let valid_name = !name.is_empty()
+      && name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_name {
+      return Err(format!(
+        "Invalid stage name `{name}`, use letters, digits, `_` and `-`"
+      ));
+    }
+    if stage.cmd().trim().is_empty() {
+      return Err(format!("Stage `{name}` has empty command"));
+    }
+  }
+  Ok(())
+}
+
+/// Merges `yml` of file at `path` into base file of its `extends` key
+///
+/// Base path is relative to `path` dir and can extend other file.
+/// Local fields and stages override base ones,
+/// `artifacts` are appended to base ones
+/// and `settings` are merged by key.
+fn resolve_extends(
+  path: &Path,
+  yml: Value,
+  depth: usize,
+) -> io::Result<Value> {
+  let Value::Mapping(mut yml) = yml else {
+    return Ok(yml);
+  };
+  let Some(extends) = yml.remove(EXTENDS) else {
+    return Ok(Value::Mapping(yml));
+  };
+  let Value::String(extends) = extends else {
+    return Err(parse_error(path, "Field `extends` must be a path"));
+  };
+  if depth == 0 {
+    return Err(parse_error(
+      path,
+      format!("Too deep `extends` chain at `{extends}`"),
+    ));
+  }
+  let base_path =
+    path.parent().unwrap_or(Path::new("")).join(&extends);
+  let base = fs::read_to_string(&base_path).map_err(|err| {
+    io::Error::new(
+      err.kind(),
+      format!(
+        "Can't read `{extends}` extended by {}: {err}",
+        path.display()
+      ),
+    )
+  })?;
+  let base = Format::of(&base_path).parse(&base_path, &base)?;
+  let Value::Mapping(mut merged) =
+    resolve_extends(&base_path, base, depth - 1)?
+  else {
+    return Err(parse_error(
+      &base_path,
+      "File extended by `extends` must be a mapping",
+    ));
+  };
+  for (key, value) in yml {
+    match (merged.get_mut(&key), value) {
+      (Some(Value::Sequence(base)), Value::Sequence(local))
+        if key.as_str() == Some("artifacts") =>
+      {
+        for artifact in local {
+          if !base.contains(&artifact) {
+            base.push(artifact);
+          }
+        }
+      }
+      (Some(Value::Mapping(base)), Value::Mapping(local))
+        if key.as_str() == Some(SETTINGS) =>
+      {
+        base.extend(local);
+      }
+      (_, value) => {
+        merged.insert(key, value);
+      }
+    }
+  }
+  Ok(Value::Mapping(merged))
+}
+
+/// Writes `hmd.yml` with `project` and `ssh_address` to current dir
+///
+/// Existing `hmd.toml` is written instead, keeping its format.
+/// Keeps other fields and comments of valid existing config
+/// unless `force`, otherwise stages and artifacts are seeded
+/// from `options`.
 pub(crate) fn write(
   project: &str,
   ssh_address: &str,
+  options: &InitOptions,
+) -> io::Result<()> {
+  write_in(Path::new(""), project, ssh_address, options)
+}
+
+fn write_in(
+  dir: &Path,
+  project: &str,
+  ssh_address: &str,
+  options: &InitOptions,
 ) -> io::Result<()> {
+  let path = config_in(dir).unwrap_or_else(|| dir.join(HMD_YML));
+  let format = Format::of(&path);
+  if !options.force && read_from(&path).is_ok() {
+    let yml = fs::read_to_string(&path)?;
+    let yml = set_field(format, &yml, "project", project)?;
+    let yml = set_field(format, &yml, "ssh_address", ssh_address)?;
+    serde_yaml::from_value::<HmdYml>(format.parse(&path, &yml)?)
+      .map_err(|err| parse_error(&path, err))?;
+    fs::write(&path, yml)?;
+    return Ok(());
+  }
   let hmd_yml = HmdYml {
     project: project.to_owned(),
     ssh_address: ssh_address.to_owned(),
-    ..read().unwrap_or_default()
+    artifacts: options.artifacts.clone(),
+    settings: Settings {
+      vcs: options.vcs,
+      ..Settings::default()
+    },
+    stages: seed_stages(options),
   };
-  let hmd_yml = serde_yaml::to_string(&hmd_yml).map_err(other_err)?;
-  fs::write(HMD_YML, hmd_yml)?;
+  fs::write(&path, format.serialize(&hmd_yml)?)?;
   Ok(())
 }
 
+/// Sets top-level scalar `key` of config text in `format` to `value`
+///
+/// Rest of text including comments stays untouched.
+/// Missing key is prepended.
+fn set_field(
+  format: Format,
+  yml: &str,
+  key: &str,
+  value: &str,
+) -> io::Result<String> {
+  let field = format.field(key, value)?;
+  let mut found = false;
+  let mut lines = yml
+    .lines()
+    .map(|line| {
+      if found || !format.is_field(line, key) {
+        return line.to_owned();
+      }
+      found = true;
+      match comment_start(line) {
+        Some(i) => format!("{field}{}", &line[i..]),
+        None => field.clone(),
+      }
+    })
+    .collect::<Vec<_>>();
+  if !found {
+    lines.insert(0, field);
+  }
+  Ok(lines.join("\n") + "\n")
+}
+
+/// Index of space before trailing ` #` comment of `line`
+///
+/// `#` inside value quoted by `"` or `'` doesn't start comment.
+fn comment_start(line: &str) -> Option<usize> {
+  let mut quote = None;
+  let mut prev = '\0';
+  let mut chars = line.char_indices().peekable();
+  while let Some((i, c)) = chars.next() {
+    // `\x` escapes in `"` quotes, `''` is escaped `'` in `'` ones
+    let escapes_next = match quote {
+      Some('"') => c == '\\',
+      Some(_) => {
+        c == '\'' && chars.peek().is_some_and(|&(_, c)| c == '\'')
+      }
+      None => false,
+    };
+    match quote {
+      _ if escapes_next => {
+        chars.next();
+      }
+      Some(open) if c == open => quote = None,
+      None if prev == ' ' && matches!(c, '"' | '\'') => {
+        quote = Some(c);
+      }
+      None if prev == ' ' && c == '#' => return Some(i - 1),
+      _ => {}
+    }
+    prev = c;
+  }
+  None
+}
+
 fn not_found_context(err: io::Error) -> io::Error {
   match err.kind() {
     io::ErrorKind::NotFound => io::Error::new(
@@ -73,20 +785,443 @@ fn not_found_context(err: io::Error) -> io::Error {
   }
 }
 
-fn parse_error_context(err: impl Error) -> io::Error {
-  io::Error::new(
-io::ErrorKind::InvalidData,
-format!("Can't read hmd.yml file. Invalid format. Try `hmd init` to overwrite: {err}"),
-  )
+/// Invalid format `err` of config at `path`
+fn parse_error(path: &Path, err: impl Display) -> io::Error {
+  HmdError::ConfigParse {
+    file: path.display().to_string(),
+    message: err.to_string(),
+  }
+  .into()
+}
+
+/// Number of first line indented with tab, which YAML forbids
+fn tab_indented_line(text: &str) -> Option<usize> {
+  let indented = |line: &str| {
+    line
+      .chars()
+      .take_while(|c| c.is_whitespace())
+      .any(|c| c == '\t')
+  };
+  Some(text.lines().position(indented)? + 1)
 }
 
-fn empty_project_error() -> io::Error {
-  io::Error::new(
-    io::ErrorKind::InvalidData,
-    "Field `project` in hmd.yml can't be empty",
-  )
+/// Config found by [`find_hmd_yml`], `hmd.yml` if it's gone
+fn found_config() -> PathBuf {
+  find_hmd_yml().unwrap_or_else(|_| PathBuf::from(HMD_YML))
 }
 
-fn no_stages_error() -> io::Error {
-  io::Error::new(io::ErrorKind::InvalidData, "No stages in hmd.yml")
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_dir::TestDir;
+
+  fn parse(format: Format, text: &str) -> HmdYml {
+    let path = Path::new(HMD_YML);
+    serde_yaml::from_value(format.parse(path, text).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn stages_named_like_settings_stay_stages() {
+    let hmd_yml = parse(
+      Format::Yaml,
+      "project: app\nssh_address: host\n\
+      clean: make clean\ntimeout: sleep 1\nverify_local: make check\n",
+    );
+    assert_eq!(
+      hmd_yml.stages.keys().collect::<Vec<_>>(),
+      ["clean", "timeout", "verify_local"]
+    );
+    assert_eq!(hmd_yml.stages["clean"].cmd(), "make clean");
+    assert_eq!(hmd_yml.settings, Settings::default());
+  }
+
+  #[test]
+  fn settings_are_read_from_own_key() {
+    let hmd_yml = parse(
+      Format::Yaml,
+      "project: app\nssh_address: host\n\
+      settings:\n  clean: make clean\n  timeout: 60\n\
+      run: ./app\n",
+    );
+    assert_eq!(hmd_yml.settings.clean.as_deref(), Some("make clean"));
+    assert_eq!(hmd_yml.settings.timeout, Some(60));
+    assert_eq!(hmd_yml.stages.keys().collect::<Vec<_>>(), ["run"]);
+  }
+
+  #[test]
+  fn toml_stage_named_like_setting_stays_stage() {
+    let hmd_yml = parse(
+      Format::Toml,
+      "project = \"app\"\nssh_address = \"host\"\n\
+      timeout = \"sleep 1\"\n\n[settings]\ntimeout = 60\n",
+    );
+    assert_eq!(hmd_yml.stages["timeout"].cmd(), "sleep 1");
+    assert_eq!(hmd_yml.settings.timeout, Some(60));
+  }
+
+  #[test]
+  fn serialized_settings_are_read_back() {
+    for format in [Format::Yaml, Format::Toml] {
+      let mut hmd_yml = HmdYml {
+        project: "app".to_owned(),
+        ssh_address: "host".to_owned(),
+        ..HmdYml::default()
+      };
+      hmd_yml.settings.timeout = Some(60);
+      let text = format.serialize(&hmd_yml).unwrap();
+      let read = parse(format, &text);
+      assert_eq!(read.settings.timeout, Some(60), "{text}");
+      assert_eq!(read.stages.len(), hmd_yml.stages.len(), "{text}");
+    }
+  }
+
+  fn with_excludes(
+    artifacts: &[String],
+    excludes: &[&str],
+  ) -> HmdYml {
+    HmdYml {
+      artifacts: artifacts.to_vec(),
+      settings: Settings {
+        artifact_excludes: excludes
+          .iter()
+          .map(|&exclude| exclude.to_owned())
+          .collect(),
+        ..Settings::default()
+      },
+      ..HmdYml::default()
+    }
+  }
+
+  #[test]
+  fn excludes_top_level_artifact() {
+    let dir = TestDir::new("exclude-top");
+    let app = dir.file("app.js");
+    let modules = dir.file("node_modules/x.js");
+    let hmd_yml = with_excludes(
+      &[app.clone(), dir.path("node_modules")],
+      &["node_modules"],
+    );
+    assert_eq!(
+      hmd_yml.uploaded_artifacts().unwrap(),
+      [Artifact {
+        local: app,
+        remote: "app.js".to_owned()
+      }]
+    );
+    assert!(Path::new(&modules).exists());
+  }
+
+  #[test]
+  fn excludes_nested_paths_of_dir_artifact() {
+    let dir = TestDir::new("exclude-nested");
+    let main = dir.file("src/main.js");
+    let util = dir.file("src/lib/util.js");
+    dir.file("src/node_modules/x.js");
+    dir.file("src/lib/node_modules/y.js");
+    dir.file("src/tmp/z.log");
+    let hmd_yml = with_excludes(
+      &[format!("{}:app", dir.path("src"))],
+      &["node_modules/", &dir.path("src/tmp")],
+    );
+    assert_eq!(
+      hmd_yml.uploaded_artifacts().unwrap(),
+      [
+        Artifact {
+          local: util,
+          remote: "app/lib/util.js".to_owned()
+        },
+        Artifact {
+          local: main,
+          remote: "app/main.js".to_owned()
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn keeps_dir_artifact_without_excluded_paths() {
+    let dir = TestDir::new("exclude-none");
+    dir.file("dist/app.js");
+    let hmd_yml =
+      with_excludes(&[dir.path("dist")], &["node_modules"]);
+    assert_eq!(
+      hmd_yml.uploaded_artifacts().unwrap(),
+      [Artifact {
+        local: dir.path("dist"),
+        remote: "dist".to_owned()
+      }]
+    );
+  }
+
+  #[test]
+  fn extends_merges_settings_by_key() {
+    let dir = TestDir::new("extends");
+    fs::write(
+      dir.join("base.yml"),
+      "ssh_address: host\nsettings:\n  timeout: 60\n  clean: make clean\n",
+    )
+    .unwrap();
+    let path = dir.join(HMD_YML);
+    fs::write(
+      &path,
+      "extends: base.yml\nproject: app\nsettings:\n  timeout: 5\nrun: ./app\n",
+    )
+    .unwrap();
+    let settings = read_from(&path).unwrap().settings;
+    assert_eq!(settings.timeout, Some(5));
+    assert_eq!(settings.clean.as_deref(), Some("make clean"));
+  }
+
+  #[test]
+  fn tab_indented_yaml_fails_with_line_of_tab() {
+    let text =
+      "project: app\nsettings:\n  timeout: 60\n\tclean: make clean\n";
+    let err =
+      Format::Yaml.parse(Path::new(HMD_YML), text).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Can't read hmd.yml. Invalid format: \
+      YAML uses spaces, not tabs (line 4)"
+    );
+    let text = "project: app\nrun: printf 'a\tb'\n";
+    assert!(Format::Yaml.parse(Path::new(HMD_YML), text).is_ok());
+  }
+
+  #[test]
+  fn parse_error_names_config_path_without_overwrite_hint() {
+    let dir = TestDir::new("parse-error");
+    let path = dir.path("hmd.toml");
+    fs::write(&path, "project = [").unwrap();
+    let err = read_from(Path::new(&path)).unwrap_err();
+    let message = err.to_string();
+    assert!(message.starts_with(&format!("Can't read {path}.")));
+    assert!(!message.contains("--force"), "{message}");
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::ConfigParse { .. })
+    ));
+  }
+
+  #[test]
+  fn rejects_empty_stage_command() {
+    let dir = TestDir::new("empty-stage");
+    let path = dir.path(HMD_YML);
+    for yml in ["build: ''\n", "build:\n  cmd: ' '\n  dir: web\n"] {
+      fs::write(
+        &path,
+        format!("ssh_address: host\nproject: app\nrun: ./app\n{yml}"),
+      )
+      .unwrap();
+      let err = read_from(Path::new(&path)).unwrap_err();
+      assert!(
+        err.to_string().ends_with("Stage `build` has empty command"),
+        "{err}"
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_stage_names_unsafe_for_log_path() {
+    let dir = TestDir::new("stage-name");
+    let path = dir.path(HMD_YML);
+    for name in ["web/build", "build web", "'../x'"] {
+      fs::write(
+        &path,
+        format!("ssh_address: host\nproject: app\n{name}: make\n"),
+      )
+      .unwrap();
+      let err = read_from(Path::new(&path)).unwrap_err();
+      assert!(
+        err.to_string().contains("Invalid stage name"),
+        "{name}: {err}"
+      );
+    }
+    fs::write(
+      &path,
+      "ssh_address: host\nproject: app\nbuild_web-2: make\n",
+    )
+    .unwrap();
+    assert!(read_from(Path::new(&path)).is_ok());
+  }
+
+  #[test]
+  fn empty_config_hints_overwrite() {
+    let dir = TestDir::new("empty-config");
+    let path = dir.file("hmd.yml");
+    let err = read_from(Path::new(&path)).unwrap_err();
+    assert!(err.to_string().contains("hmd init --force"));
+  }
+
+  #[test]
+  fn sets_field_keeping_comment() {
+    let yml = "# app\nproject: old # name\nrun: ./app\n";
+    assert_eq!(
+      set_field(Format::Yaml, yml, "project", "new").unwrap(),
+      "# app\nproject: new # name\nrun: ./app\n"
+    );
+    assert_eq!(
+      set_field(Format::Yaml, "run: ./app\n", "project", "app")
+        .unwrap(),
+      "project: app\nrun: ./app\n"
+    );
+    assert_eq!(
+      set_field(
+        Format::Toml,
+        "project = \"old\"\n",
+        "project",
+        "new"
+      )
+      .unwrap(),
+      "project = \"new\"\n"
+    );
+  }
+
+  #[test]
+  fn splits_artifact_keeping_windows_drive() {
+    assert_eq!(split_artifact("dist"), ("dist", None));
+    assert_eq!(split_artifact("dist:www/"), ("dist", Some("www/")));
+    assert_eq!(split_artifact(r"C:\dist"), (r"C:\dist", None));
+    assert_eq!(
+      split_artifact(r"C:\dist\app.exe:bin/app"),
+      (r"C:\dist\app.exe", Some("bin/app"))
+    );
+    assert_eq!(
+      split_artifact("c:/dist:www"),
+      ("c:/dist", Some("www"))
+    );
+  }
+
+  #[test]
+  fn finds_comment_outside_quotes() {
+    assert_eq!(comment_start("project: app # name"), Some(12));
+    assert_eq!(comment_start("project: app#1"), None);
+    assert_eq!(comment_start(r#"run: "a # b" # c"#), Some(12));
+    assert_eq!(comment_start(r#"run: "a \" # b""#), None);
+    assert_eq!(comment_start("run: 'it''s # x' # c"), Some(16));
+    assert_eq!(comment_start("run: echo it's # c"), Some(14));
+  }
+
+  #[test]
+  fn sets_field_keeping_quoted_hash() {
+    let yml = "project: \"a # b\" # name\nrun: ./app\n";
+    assert_eq!(
+      set_field(Format::Yaml, yml, "project", "app").unwrap(),
+      "project: app # name\nrun: ./app\n"
+    );
+    let yml = "project: 'a # b'\nrun: ./app\n";
+    assert_eq!(
+      set_field(Format::Yaml, yml, "project", "app").unwrap(),
+      "project: app\nrun: ./app\n"
+    );
+  }
+
+  #[test]
+  fn detects_template_by_project_files() {
+    let detect = |name: &str, files: &[&str]| {
+      let dir = TestDir::new(&format!("template-{name}"));
+      for file in files {
+        dir.file(file);
+      }
+      detect_template_in(&dir)
+    };
+    assert_eq!(detect("rust", &["Cargo.toml"]), Template::Rust);
+    assert_eq!(detect("node", &["package.json"]), Template::Node);
+    assert_eq!(
+      detect("python", &["pyproject.toml"]),
+      Template::Python
+    );
+    assert_eq!(
+      detect("pip", &["requirements.txt"]),
+      Template::Python
+    );
+    assert_eq!(detect("static", &["index.html"]), Template::Static);
+    assert_eq!(
+      detect("both", &["package.json", "Cargo.toml"]),
+      Template::Rust
+    );
+    assert_eq!(detect("empty", &[]), Template::Rust);
+  }
+
+  #[test]
+  fn finds_hmd_yml_of_parent_dir() {
+    let dir = TestDir::new("find-hmd-yml");
+    let hmd_yml = dir.file(HMD_YML);
+    let nested = dir.join("src/bin");
+    fs::create_dir_all(&nested).unwrap();
+    let found = find_hmd_yml_in(&nested).unwrap();
+    assert_eq!(found, Path::new(&hmd_yml));
+    let hmd_toml = dir.file(&format!("src/{HMD_TOML}"));
+    let found = find_hmd_yml_in(&nested).unwrap();
+    assert_eq!(found, Path::new(&hmd_toml));
+  }
+
+  fn init_options(force: bool) -> InitOptions {
+    InitOptions {
+      force,
+      template: Template::Static,
+      stages: Vec::new(),
+      artifacts: Vec::new(),
+      vcs: Vcs::Git,
+    }
+  }
+
+  #[test]
+  fn forced_init_resets_stages_to_defaults() {
+    let dir = TestDir::new("init-force");
+    let path = dir.join(HMD_YML);
+    let stages = |path: &Path| {
+      let hmd_yml = read_from(path).unwrap();
+      hmd_yml.stages.into_keys().collect::<Vec<_>>()
+    };
+    fs::write(
+      &path,
+      "project: app\nssh_address: host\ncustom: make\n",
+    )
+    .unwrap();
+    write_in(&dir, "app", "host", &init_options(false)).unwrap();
+    assert_eq!(stages(&path), ["custom"]);
+    write_in(&dir, "app", "host", &init_options(true)).unwrap();
+    assert_eq!(stages(&path), ["run"]);
+    assert_eq!(read_from(&path).unwrap().project, "app");
+  }
+
+  #[test]
+  fn templates_have_their_stages() {
+    let stages = |template| {
+      default_stages(template)
+        .into_iter()
+        .map(|(name, stage)| format!("{name}: {}", stage.cmd()))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      stages(Template::Rust),
+      [
+        "lint: cargo clippy",
+        "test: cargo test",
+        "build: cargo build --release",
+        "run: cargo run --release",
+      ]
+    );
+    assert_eq!(
+      stages(Template::Node),
+      [
+        "install: npm ci",
+        "test: npm test",
+        "build: npm run build",
+        "run: npm start",
+      ]
+    );
+    assert_eq!(
+      stages(Template::Python),
+      [
+        "install: pip install -r requirements.txt",
+        "test: python -m pytest",
+        "run: python main.py",
+      ]
+    );
+    assert_eq!(
+      stages(Template::Static),
+      ["run: python3 -m http.server 8080"]
+    );
+  }
 }
@@ -0,0 +1,62 @@
+use std::{
+  io,
+  sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+  thread,
+  time::{Duration, Instant},
+};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static CATCHING: AtomicUsize = AtomicUsize::new(0);
+
+/// Time `settle` waits for Ctrl-C handler
+const HANDLER_GRACE: Duration = Duration::from_millis(100);
+
+/// Message printed when streaming of remote pipeline is interrupted
+pub(crate) const STILL_RUNNING_HINT: &str =
+  "Pipeline is still running on server, use `hmd stop` to terminate";
+
+/// Installs Ctrl-C handler exiting with code 130,
+/// unless Ctrl-C is caught by `catching` run
+///
+/// Handler is reset for spawned commands, so they're still
+/// interrupted by default.
+pub fn init() {
+  // Without handler Ctrl-C just terminates hmd, losing only hint
+  let _ = ctrlc::set_handler(|| {
+    if CATCHING.load(Ordering::SeqCst) > 0 {
+      INTERRUPTED.store(true, Ordering::SeqCst);
+    } else {
+      std::process::exit(130);
+    }
+  });
+}
+
+/// Runs `run` catching Ctrl-C, so hmd outlives interrupted ssh child
+/// and `is_interrupted` tells its failure apart
+pub(crate) fn catching<T>(
+  run: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+  CATCHING.fetch_add(1, Ordering::SeqCst);
+  let result = run();
+  if result.is_err() {
+    settle();
+  }
+  CATCHING.fetch_sub(1, Ordering::SeqCst);
+  result
+}
+
+/// Waits shortly for pending Ctrl-C handler
+///
+/// Ctrl-C reaches children and handler thread together,
+/// so failure of interrupted child comes before handler runs.
+pub fn settle() {
+  let start = Instant::now();
+  while !is_interrupted() && start.elapsed() < HANDLER_GRACE {
+    thread::sleep(Duration::from_millis(5));
+  }
+}
+
+/// Whether Ctrl-C was caught
+pub(crate) fn is_interrupted() -> bool {
+  INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,4534 @@
+//! Home Deploy Tool
+//!
+//! Deploys pet projects to ssh server by git push, scp and ssh.
+//! [`Hmd`] runs commands of one project, `hmd` binary is a thin
+//! CLI over it.
+
+pub mod color;
+pub mod dotenv;
+mod env;
+mod error;
+mod glob;
+pub mod hmd_config_yml;
+pub mod hmd_yml;
+pub mod interrupt;
+pub mod output;
+mod runner;
+#[cfg(feature = "self-update")]
+pub mod self_update;
+mod shell;
+mod spinner;
+mod systemd;
+#[cfg(test)]
+mod test_dir;
+mod watch;
+mod webhook;
+
+use std::{
+  error::Error,
+  fmt, fs,
+  io::{self, BufRead, IsTerminal, Write},
+  path::{Path, PathBuf},
+  process::{Command as Cmd, Stdio},
+  sync::atomic::{AtomicU32, Ordering},
+  thread,
+  time::Duration,
+};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub use crate::{
+  env::Env,
+  error::HmdError,
+  runner::{CommandRunner, RecordingRunner, VerboseRunner},
+};
+use crate::{
+  env::{is_ipv6, ssh_host},
+  hmd_config_yml::{HmdConfigYml, Target, HMD_CONFIG_YML},
+  hmd_yml::{
+    Artifact, HealthCheck, HmdYml, Stage, Supervisor, Template, Vcs,
+  },
+  shell::shell_quote,
+  spinner::Spinner,
+};
+
+const HMD_ROOT: &str = "~/.hmd";
+const SCRIPT: &str = include_str!("../script.sh");
+/// Seconds to wait for pipeline with timeout to save its pid
+const PIPELINE_START_SECS: u32 = 10;
+/// Seconds to sleep before retry of failed stage
+const STAGE_RETRY_SECS: u32 = 5;
+/// Remote branch to push `deploy --ref`, `--dirty`
+/// and detached HEAD revisions to
+const DEPLOY_BRANCH: &str = "hmd-deploy";
+const HMD_SSH_ADDRESS: &str = "HMD_SSH_ADDRESS";
+const HMD_PROJECT: &str = "HMD_PROJECT";
+/// Prefix of temp commit message of `deploy --dirty`
+const TEMP_COMMIT_PREFIX: &str = "hmd-temp:";
+/// Dirs of upload dir staging artifacts mapped
+/// to absolute and `~/` paths
+const UPLOAD_ROOT_DIR: &str = ".root";
+const UPLOAD_HOME_DIR: &str = ".home";
+/// Name prefix of archives of `deploy --compress-artifacts`
+const ARTIFACTS_ARCHIVE: &str = ".hmd-artifacts";
+/// Exit code of remote check failing on missing git repo
+const NOT_INITIALIZED_EXIT: i32 = 66;
+/// Prefix of line starting output of each pipeline run
+const DEPLOY_MARKER: &str = "===== deploy";
+
+/// Commands of one project at ssh server
+///
+/// Processes are spawned by `runner`, so the whole flow
+/// can be driven by a test double.
+pub struct Hmd<'a> {
+  runner: &'a dyn CommandRunner,
+  env: Env,
+}
+
+impl<'a> Hmd<'a> {
+  #[must_use]
+  pub fn new(runner: &'a dyn CommandRunner, env: Env) -> Self {
+    Self { runner, env }
+  }
+
+  #[must_use]
+  pub fn env(&self) -> &Env {
+    &self.env
+  }
+
+  /// Creates project repository at server and `hmd.yml`
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh, git or writing `hmd.yml` fails
+  pub fn init(&self, options: &InitOptions) -> io::Result<()> {
+    init(self.runner, &self.env, options)
+  }
+
+  /// Checks server is reachable and writable without writing
+  /// project repository, `hmd.yml` or config
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if any check fails
+  pub fn check_init(&self) -> io::Result<()> {
+    check_init(self.runner, &self.env)
+  }
+
+  /// Pushes commit, uploads artifacts and runs pipeline
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if any step fails or project is locked
+  /// by other deploy
+  pub fn deploy(
+    &self,
+    hmd_yml: &HmdYml,
+    options: &DeployOptions,
+  ) -> io::Result<()> {
+    deploy(self.runner, &self.env, hmd_yml, options)
+  }
+
+  /// Deploys and redeploys on every change of files
+  /// in current dir until Ctrl-C
+  ///
+  /// Changes are debounced, gitignored and `artifact_excludes`
+  /// ones are skipped. Change during deploy stops it at next step
+  /// and starts new one. Failed deploy is reported and the next
+  /// change retries it. Each deploy restarts pipeline,
+  /// so `options` are expected to be `dirty`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if files can't be watched
+  pub fn watch_deploy(
+    &self,
+    hmd_yml: &HmdYml,
+    options: &DeployOptions,
+  ) -> io::Result<()> {
+    let deploy = || {
+      let deployed = self.deploy(hmd_yml, options);
+      if interrupt::is_interrupted() {
+        return Ok(());
+      }
+      if watch::is_superseded() {
+        output::println("Deploy superseded by newer change");
+        return Ok(());
+      }
+      if let Err(err) = deployed {
+        eprintln!("{}", color::stderr().red(&err));
+      }
+      output::println("Watching for changes, Ctrl-C to stop");
+      Ok(())
+    };
+    deploy()?;
+    let is_excluded =
+      |path: &Path| hmd_yml.is_excluded(&path.to_string_lossy());
+    interrupt::catching(|| {
+      watch::watch(Path::new("."), is_excluded, deploy)
+    })
+  }
+
+  /// Text of `pipeline.sh` deploy would run at server
+  #[must_use]
+  pub fn pipeline_script(
+    &self,
+    hmd_yml: &HmdYml,
+    env_vars: &[(String, String)],
+  ) -> String {
+    build_pipeline_script(&self.env, hmd_yml, env_vars)
+  }
+
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn stop(&self, supervisor: Supervisor) -> io::Result<()> {
+    stop(self.runner, &self.env, supervisor)
+  }
+
+  /// Restarts pipeline, only running one if `if_running`
+  ///
+  /// With `verify` restart fails if `pipeline.sh` at server
+  /// isn't the one last deploy launched, e.g. left
+  /// by interrupted deploy.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails or script differs
+  pub fn restart(
+    &self,
+    timeout: Option<u64>,
+    if_running: bool,
+    verify: bool,
+  ) -> io::Result<()> {
+    restart_pipeline(
+      self.runner,
+      &self.env,
+      timeout,
+      if_running,
+      verify,
+    )
+  }
+
+  /// Streams pipeline status, exits with its code if `watch_exit`
+  ///
+  /// Only last `tail` entries are printed before streaming if set.
+  /// Status of systemd unit follows for `Supervisor::Systemd`.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh or finished pipeline fails
+  pub fn status(
+    &self,
+    watch_exit: bool,
+    tail: Option<usize>,
+    supervisor: Supervisor,
+  ) -> io::Result<()> {
+    status(self.runner, &self.env, watch_exit, tail, supervisor)
+  }
+
+  /// Running state of pipeline without streaming
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn status_summary(&self) -> io::Result<StatusSummary> {
+    status_summary(self.runner, &self.env)
+  }
+
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn ps(&self) -> io::Result<()> {
+    ps(self.runner, &self.env)
+  }
+
+  /// Streams pipeline log from `start` or of single `stage`
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn log(
+    &self,
+    start: &LogStart,
+    stage: Option<&str>,
+  ) -> io::Result<()> {
+    log(self.runner, &self.env, start, stage)
+  }
+
+  /// Deploys recorded at server, oldest first
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn history(&self) -> io::Result<Vec<DeployMeta>> {
+    history(self.runner, &self.env)
+  }
+
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn events(&self) -> io::Result<()> {
+    events(self.runner, &self.env)
+  }
+
+  /// Downloads `what` logs to local `dest`
+  ///
+  /// Single log is saved as `dest` file, `./<project>-out.log`
+  /// by default, both ones into `dest` dir.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if scp fails
+  pub fn download(
+    &self,
+    what: LogFile,
+    dest: Option<&Path>,
+  ) -> io::Result<()> {
+    download(self.runner, &self.env, what, dest)
+  }
+
+  /// Opens interactive shell at work tree
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn open(&self) -> io::Result<()> {
+    open(self.runner, &self.env)
+  }
+
+  /// Runs `args` command at work tree
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh or command fails
+  pub fn exec(&self, args: &[String]) -> io::Result<()> {
+    exec(self.runner, &self.env, args)
+  }
+
+  /// Stops pipeline, removes logs and runs `clean_cmd`
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh or `clean_cmd` fails
+  pub fn clean(&self, clean_cmd: Option<&str>) -> io::Result<()> {
+    clean(self.runner, &self.env, clean_cmd)
+  }
+
+  /// Removes rotated logs except `keep` recent ones
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh fails
+  pub fn prune(&self, keep: usize) -> io::Result<()> {
+    prune(self.runner, &self.env, keep)
+  }
+
+  /// Removes project from server, asks to confirm unless `yes`
+  ///
+  /// Logs are downloaded to `keep_logs` dir before.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if ssh or logs download fails
+  /// or confirmation needs terminal
+  pub fn remove(
+    &self,
+    yes: bool,
+    keep_logs: Option<&Path>,
+  ) -> io::Result<()> {
+    remove(self.runner, &self.env, yes, keep_logs)
+  }
+}
+
+/// Searches target in `~/.hmd/config.yml` by `target` name
+/// or ssh address in `ssh_address`, `HMD_SSH_ADDRESS` env var,
+/// `hmd.yml` and default target of `~/.hmd/config.yml`
+///
+/// `projects` entry of `project` in `~/.hmd/config.yml` selects
+/// its target when no address is found before default target.
+/// `ssh_address` overrides address of named target,
+/// `ssh_options` are appended to configured ones
+/// and `user` overrides one of address, see [`Target::login`].
+///
+/// # Errors
+///
+/// Returns an error if target not found or ssh address not provided
+pub fn get_target(
+  ssh_address: Option<String>,
+  ssh_options: &[String],
+  target: Option<&str>,
+  scope: Option<&str>,
+  user: Option<&str>,
+  project: Option<&str>,
+) -> io::Result<Target> {
+  let config = hmd_config_yml::read();
+  let mut target = if let Some(name) = target {
+    let config = config?;
+    let mut target = config.target(Some(name))?;
+    if let Some(ssh_address) = ssh_address {
+      target.ssh_address = ssh_address;
+    }
+    config.with_project(project, target)
+  } else {
+    let config = config.unwrap_or_default();
+    let target = ssh_address
+      .or_else(|| env_var(HMD_SSH_ADDRESS))
+      .or_else(|| Some(hmd_yml::read().ok()?.ssh_address))
+      .map(|ssh_address| config.target_at(ssh_address))
+      .or_else(|| config.project_target(project).ok())
+      .ok_or(HmdError::SshAddressNotProvided)?;
+    config.with_project(project, target)
+  };
+  if let Some(scope) = scope {
+    target.scope = Some(scope.to_owned());
+  }
+  if let Some(user) = user {
+    target.user = Some(user.to_owned());
+  }
+  target.ssh_options.extend_from_slice(ssh_options);
+  Ok(target)
+}
+
+/// Target mapped to current git branch by `branches`
+/// of `~/.hmd/config.yml`, none without them
+///
+/// # Errors
+///
+/// Returns an error if branch can't be read or matches no target
+pub fn branch_target(
+  runner: &dyn CommandRunner,
+) -> io::Result<Option<String>> {
+  let config = hmd_config_yml::read().unwrap_or_default();
+  if config.branches.is_empty() {
+    return Ok(None);
+  }
+  let branch = git_output(runner, &["branch", "--show-current"])?;
+  Ok(config.branch_target(&branch)?.map(str::to_owned))
+}
+
+/// Searches project in `project`, `HMD_PROJECT` env var
+/// and `hmd.yml`
+///
+/// # Errors
+///
+/// Returns an error if project not provided
+pub fn get_project(project: Option<String>) -> io::Result<String> {
+  project
+    .or_else(|| env_var(HMD_PROJECT))
+    .or_else(|| Some(hmd_yml::read().ok()?.project))
+    .ok_or(HmdError::ProjectNotProvided.into())
+}
+
+/// Searches project like `get_project` for `init`
+/// falling back to current dir name
+///
+/// # Errors
+///
+/// Returns an error if project not provided
+/// and current dir name isn't valid
+pub fn get_init_project(
+  project: Option<String>,
+) -> io::Result<String> {
+  get_project(project).or_else(|err| current_dir().map_err(|_| err))
+}
+
+/// Non-empty value of env var `name`
+fn env_var(name: &str) -> Option<String> {
+  std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn current_dir() -> io::Result<String> {
+  let error =
+    || other_err("Can't parse project name from current dir");
+  let project = std::env::current_dir()?
+    .file_name()
+    .ok_or_else(error)?
+    .to_str()
+    .ok_or_else(error)?
+    .to_string();
+  Ok(project)
+}
+
+fn init(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  options: &InitOptions,
+) -> io::Result<()> {
+  init_srv_repo(runner, env, options.vcs)?;
+  hmd_yml::write(&env.project, &env.ssh_address, options)?;
+  if hmd_config_yml::read().is_err() {
+    hmd_config_yml::write(&HmdConfigYml::new(
+      env.ssh_address.clone(),
+    ))?;
+    output::println(color::green(format!(
+      "Config created at {HMD_CONFIG_YML}"
+    )));
+  }
+  Ok(())
+}
+
+/// Reports result of each check, writability one needs connection
+fn check_init(
+  runner: &dyn CommandRunner,
+  env: &Env,
+) -> io::Result<()> {
+  let mut connect = ssh(env);
+  connect.arg("true");
+  let mut write = ssh(env);
+  write.arg(write_probe_cmd(&env.project_dir));
+  let checks = [
+    (format!("Connect to {}", env.ssh_address), connect),
+    (format!("Write to {}", env.project_dir), write),
+  ];
+  for (check, mut ssh) in checks {
+    if let Err(err) = runner.run_remote(&mut ssh, env.retries) {
+      eprintln!(
+        "{}",
+        color::stderr().red(format!("❌ {check}: {err}"))
+      );
+      return Err(other_err("Init check failed"));
+    }
+    output::println(color::green(format!("✅ {check}")));
+  }
+  Ok(())
+}
+
+/// Touches temp file in `dir` or its nearest existing parent
+fn write_probe_cmd(dir: &str) -> String {
+  let dir = shell_quote(dir);
+  format!(
+    "dir={dir}; while [ ! -d \"$dir\" ]; do dir=`dirname \"$dir\"`; done; \
+    probe=\"$dir/.hmd-write-check\"; touch \"$probe\" && rm \"$probe\""
+  )
+}
+
+fn init_srv_repo(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  vcs: Vcs,
+) -> io::Result<()> {
+  let git_dir = &shell_quote(&env.git_dir);
+  let work_tree = &shell_quote(&env.work_tree);
+  let ssh = &mut ssh(env);
+  if let Vcs::None = vcs {
+    ssh.arg(format!("mkdir -p {work_tree};"));
+    runner.run_remote(ssh, env.retries)?;
+    return Ok(());
+  }
+  ssh
+    .arg(format!("mkdir -p {git_dir} {work_tree};"))
+    .arg(format!("cd {git_dir};"))
+    .arg("git init --bare;");
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Ssh to project server
+fn ssh(env: &Env) -> Cmd {
+  ssh_with(env, &[])
+}
+
+/// Ssh to project server with `flags` before address
+fn ssh_with(env: &Env, flags: &[&str]) -> Cmd {
+  ssh_to(
+    &env.ssh_address,
+    env.user.as_deref(),
+    env.port,
+    env.jump_host.as_deref(),
+    env.compress,
+    &env.ssh_options,
+    flags,
+  )
+}
+
+/// Ssh to `target` server
+fn target_ssh(target: &Target) -> Cmd {
+  let (ssh_address, user) = target.login();
+  ssh_to(
+    &ssh_address,
+    user.as_deref(),
+    target.port,
+    target.jump_host.as_deref(),
+    target.compress.unwrap_or_default(),
+    &target.ssh_options,
+    &[],
+  )
+}
+
+fn ssh_to(
+  ssh_address: &str,
+  user: Option<&str>,
+  port: Option<u16>,
+  jump_host: Option<&str>,
+  compress: bool,
+  options: &[String],
+  flags: &[&str],
+) -> Cmd {
+  let mut ssh = Cmd::new("ssh");
+  ssh.args(flags);
+  if is_ipv6(ssh_address) {
+    ssh.arg("-6");
+  }
+  if let Some(user) = user {
+    ssh.args(["-l", user]);
+  }
+  if let Some(port) = port {
+    ssh.args(["-p", &port.to_string()]);
+  }
+  if let Some(jump_host) = jump_host {
+    ssh.args(["-J", jump_host]);
+  }
+  if compress {
+    ssh.args(["-o", "Compression=yes"]);
+  }
+  for option in options {
+    ssh.arg("-o").arg(option);
+  }
+  ssh.arg(ssh_host(ssh_address));
+  ssh
+}
+
+/// Options of `Hmd::init`
+pub struct InitOptions {
+  /// Overwrite existing `hmd.yml` with defaults
+  pub force: bool,
+  /// Project type of default stages
+  pub template: Template,
+  /// Stage names with commands replacing `template` ones
+  pub stages: Vec<(String, String)>,
+  /// Artifacts of new `hmd.yml`
+  pub artifacts: Vec<String>,
+  /// Version control of project, server repo isn't created without it
+  pub vcs: Vcs,
+}
+
+/// Running state of pipeline of `status --json`
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct StatusSummary {
+  pub project: String,
+  pub running: bool,
+  /// Pid of running pipeline
+  pub pid: Option<u32>,
+  /// Stage running, failed or completed last
+  pub last_stage: Option<String>,
+  /// Time of last stage event, like `2024-01-31T12:00:00`
+  pub last_event_ts: Option<String>,
+}
+
+impl StatusSummary {
+  /// # Errors
+  ///
+  /// Returns an error if summary can't be serialized
+  pub fn to_json(&self) -> io::Result<String> {
+    serde_json::to_string(self).map_err(|err| {
+      other_err(format!("Can't serialize status: {err}"))
+    })
+  }
+}
+
+/// Record of one deploy in `deploy.meta`
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeployMeta {
+  /// Deployed commit, none without git
+  #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+  pub git_ref: Option<String>,
+  /// `deploy --tag` label, short commit by default
+  pub label: String,
+  /// Local user who deployed
+  pub user: String,
+  /// Local time of deploy, like `2024-01-31T12:00:00`
+  pub ts: String,
+}
+
+/// One line of `history`
+impl fmt::Display for DeployMeta {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let Self {
+      git_ref,
+      label,
+      user,
+      ts,
+    } = self;
+    write!(f, "{ts} {label}")?;
+    if let Some(git_ref) = git_ref {
+      write!(f, " {}", short_sha(git_ref))?;
+    }
+    write!(f, " by {user}")
+  }
+}
+
+/// Lines of log printed before streaming new ones
+pub enum LogStart {
+  /// Last lines
+  Tail(usize),
+  /// Lines since first stage started not earlier than duration ago
+  Since(Duration),
+  /// Whole log of last pipeline run, previous ones are rotated
+  LastDeploy,
+}
+
+impl Default for LogStart {
+  /// Last 50 lines
+  fn default() -> Self {
+    Self::Tail(50)
+  }
+}
+
+/// Pipeline logs downloaded by `Hmd::download`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFile {
+  /// `out.log` of stages output
+  Out,
+  /// `status.log` of stages status
+  Status,
+  /// Both of them
+  All,
+}
+
+/// Options of `Hmd::deploy`
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Default)]
+pub struct DeployOptions<'a> {
+  /// Push work tree with uncommitted and untracked changes
+  pub dirty: bool,
+  /// Message of temp commit of dirty deploy
+  pub message: Option<&'a str>,
+  /// Branch to push deployed commit to at ssh server
+  pub remote_branch: Option<&'a str>,
+  /// Number of concurrent scp to upload artifacts
+  pub parallel: usize,
+  /// Upload artifacts as tar.gz archives instead of plain scp
+  pub compress_artifacts: bool,
+  /// Tag, branch or commit to deploy instead of HEAD
+  pub git_ref: Option<&'a str>,
+  /// Stream pipeline and fail if it fails
+  pub wait: bool,
+  /// Push and upload artifacts without running pipeline
+  pub only_upload: bool,
+  /// Run pipeline on existing work tree without checkout
+  pub no_checkout: bool,
+  /// Skip check of free disk space at server before upload
+  pub no_disk_check: bool,
+  /// Remove lock of other deploy
+  pub force_unlock: bool,
+  /// Push without `--force`, so diverged server branch fails deploy
+  pub no_force_push: bool,
+  /// Don't remove generated `pipeline.sh` after upload
+  pub keep_pipeline_sh: bool,
+  /// Seconds to kill pipeline after
+  pub timeout: Option<u64>,
+  /// Variables exported by pipeline, e.g. read by `dotenv::read`
+  pub env_vars: &'a [(String, String)],
+  /// Label of deploy in `deploy.meta`, short commit by default
+  pub tag: Option<&'a str>,
+}
+
+/// Commit to deploy and remote branch to push it to
+struct DeployRef {
+  checkout: String,
+  remote_branch: String,
+}
+
+/// Deploys holding remote lock, so concurrent deploy of project fails
+///
+/// Deployed revision is resolved once before, so HEAD moved
+/// during deploy doesn't change what is checked out at server.
+fn deploy(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  hmd_yml: &HmdYml,
+  options: &DeployOptions,
+) -> io::Result<()> {
+  // Missing artifact fails before server is locked
+  let artifacts = hmd_yml.uploaded_artifacts()?;
+  check_artifacts(&artifacts)?;
+  if let Some(verify_local) = &hmd_yml.settings.verify_local {
+    verify_locally(runner, verify_local)?;
+  }
+  let vcs = hmd_yml.settings.vcs;
+  let deploy_ref = deploy_ref(runner, env, options, vcs)?;
+  let ssh = &mut ssh(env);
+  ssh
+    .arg(check_initialized_cmd(env, vcs))
+    .arg(lock_cmd(&env.deploy_lock(), options.force_unlock));
+  runner
+    .run_remote(ssh, env.retries)
+    .map_err(|err| not_initialized_context(env, err))?;
+  let deployed = deploy_locked(
+    runner,
+    env,
+    hmd_yml,
+    options,
+    deploy_ref.as_ref(),
+    artifacts,
+  );
+  let ssh = &mut self::ssh(env);
+  ssh.arg(format!("rm -rf {};", shell_quote(&env.deploy_lock())));
+  let unlocked = runner.run_remote(ssh, env.retries);
+  deployed?;
+  unlocked
+}
+
+/// Runs `verify_local` command by local shell, so broken code
+/// isn't pushed
+fn verify_locally(
+  runner: &dyn CommandRunner,
+  verify_local: &str,
+) -> io::Result<()> {
+  output::println(format!("Verifying: {verify_local}"));
+  runner
+    .run(Cmd::new("sh").args(["-c", verify_local]))
+    .map_err(|err| {
+      other_err(format!(
+        "Deploy aborted, `verify_local` failed: {err}"
+      ))
+    })
+}
+
+/// Fails if newer change of `watch_deploy` came,
+/// so deploy stops before next step
+fn check_superseded() -> io::Result<()> {
+  if watch::is_superseded() {
+    return Err(other_err("Deploy superseded by newer change"));
+  }
+  Ok(())
+}
+
+/// Fails with `NOT_INITIALIZED_EXIT` if project has no git repo
+/// or no work tree without git
+fn check_initialized_cmd(env: &Env, vcs: Vcs) -> String {
+  let dir = match vcs {
+    Vcs::Git => &env.git_dir,
+    Vcs::None => &env.work_tree,
+  };
+  let dir = shell_quote(dir);
+  format!("test -d {dir} || exit {NOT_INITIALIZED_EXIT};")
+}
+
+fn not_initialized_context(env: &Env, err: io::Error) -> io::Error {
+  match HmdError::downcast(&err) {
+    Some(HmdError::CommandFailed { status })
+      if status.code() == Some(NOT_INITIALIZED_EXIT) =>
+    {
+      let Env {
+        project,
+        ssh_address,
+        ..
+      } = env;
+      other_err(format!(
+        "No project `{project}` at {ssh_address}. Try `hmd init`"
+      ))
+    }
+    _ => err,
+  }
+}
+
+/// Takes lock by atomic `mkdir` saving time it's taken at
+///
+/// Held lock fails command with time it was taken,
+/// unless `force_unlock` removes it before.
+fn lock_cmd(lock: &str, force_unlock: bool) -> String {
+  let lock = shell_quote(lock);
+  let unlock = if force_unlock {
+    format!("rm -rf {lock}; ")
+  } else {
+    String::new()
+  };
+  format!(
+    "{unlock}mkdir {lock} 2>/dev/null && date +%FT%T > {lock}/taken || {{ \
+    echo \"Deploy is locked since `cat {lock}/taken 2>/dev/null`, \
+    use --force-unlock if it's stale\" >&2; exit 1; }};"
+  )
+}
+
+/// Resolves commit and remote branch of deploy `options`,
+/// none without git
+fn deploy_ref(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  options: &DeployOptions,
+  vcs: Vcs,
+) -> io::Result<Option<DeployRef>> {
+  if let Vcs::None = vcs {
+    if options.git_ref.is_some() {
+      return Err(other_err("Can't deploy `--ref` with `vcs: none`"));
+    }
+    return Ok(None);
+  }
+  let checkout = match options.git_ref {
+    Some(git_ref) => git_rev_parse(runner, git_ref)?,
+    None if options.dirty => {
+      git_temp_commit(runner, env, options.message)?
+    }
+    None => git_rev_parse(runner, "HEAD")?,
+  };
+  let remote_branch = match options.remote_branch {
+    Some(remote_branch) => remote_branch.to_owned(),
+    None if options.git_ref.is_none() && !options.dirty => {
+      git_branch(runner)?
+    }
+    None => DEPLOY_BRANCH.to_owned(),
+  };
+  Ok(Some(DeployRef {
+    checkout,
+    remote_branch,
+  }))
+}
+
+fn deploy_locked(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  hmd_yml: &HmdYml,
+  options: &DeployOptions,
+  deploy_ref: Option<&DeployRef>,
+  mut artifacts: Vec<Artifact>,
+) -> io::Result<()> {
+  check_superseded()?;
+  if !options.no_disk_check {
+    check_disk_space(runner, env, &artifacts)?;
+  }
+  if let Some(DeployRef {
+    checkout,
+    remote_branch,
+  }) = deploy_ref
+  {
+    git_push(
+      runner,
+      env,
+      &format!("{checkout}:refs/heads/{remote_branch}"),
+      !options.no_force_push,
+    )?;
+  }
+  check_superseded()?;
+  if options.only_upload {
+    return upload_artifacts(runner, env, &artifacts, options);
+  }
+  if let Supervisor::Systemd = hmd_yml.settings.supervisor {
+    install_unit(runner, env, hmd_yml)?;
+  }
+  let script = build_pipeline_script(env, hmd_yml, options.env_vars);
+  let script_hash = script_hash(&script);
+  let pipeline_sh = PipelineSh::write(env, &script)?;
+  artifacts.push(Artifact {
+    local: pipeline_sh.path.to_string_lossy().into_owned(),
+    remote: Env::PIPELINE_SH.to_owned(),
+  });
+  // Ctrl-C fails upload instead of exit, so script is removed
+  let uploaded = interrupt::catching(|| {
+    upload_artifacts(runner, env, &artifacts, options)
+  });
+  if options.keep_pipeline_sh {
+    let path = pipeline_sh.keep();
+    output::println(format!("Kept {}", path.display()));
+  } else {
+    drop(pipeline_sh);
+  }
+  if uploaded.is_err() && interrupt::is_interrupted() {
+    return Err(HmdError::Interrupted.into());
+  }
+  uploaded?;
+  check_superseded()?;
+  let checkout =
+    deploy_ref.map(|deploy_ref| deploy_ref.checkout.as_str());
+  run_pipeline(
+    runner,
+    env,
+    options,
+    checkout,
+    hmd_yml.settings.supervisor,
+    &script_hash,
+  )?;
+  record_deploy(runner, env, checkout, options.tag)?;
+  if let Some(health_check) = &hmd_yml.settings.health_check {
+    check_health(runner, env, health_check)?;
+  }
+  Ok(())
+}
+
+/// Appends `DeployMeta` of launched deploy to `deploy.meta`
+fn record_deploy(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  checkout: Option<&str>,
+  tag: Option<&str>,
+) -> io::Result<()> {
+  let label = match (tag, checkout) {
+    (Some(tag), _) => tag.to_owned(),
+    (None, Some(checkout)) => short_sha(checkout).to_owned(),
+    (None, None) => "-".to_owned(),
+  };
+  let meta = DeployMeta {
+    git_ref: checkout.map(str::to_owned),
+    label,
+    user: local_user(),
+    ts: runner
+      .output(Cmd::new("date").arg("+%FT%T"))?
+      .trim()
+      .to_owned(),
+  };
+  let ssh = &mut ssh(env);
+  ssh.arg(record_deploy_cmd(env, &meta)?);
+  runner.run_remote(ssh, env.retries)
+}
+
+fn record_deploy_cmd(
+  env: &Env,
+  meta: &DeployMeta,
+) -> io::Result<String> {
+  let line = serde_json::to_string(meta).map_err(|err| {
+    other_err(format!("Can't serialize deploy meta: {err}"))
+  })?;
+  Ok(format!(
+    "echo {} >> {};",
+    shell_quote(&line),
+    shell_quote(&env.deploy_meta())
+  ))
+}
+
+/// Name of local user from environment
+fn local_user() -> String {
+  std::env::var("USER")
+    .or_else(|_| std::env::var("USERNAME"))
+    .unwrap_or_else(|_| "unknown".to_owned())
+}
+
+/// Commit abbreviated like `git log --oneline`
+fn short_sha(sha: &str) -> &str {
+  sha.get(..7).unwrap_or(sha)
+}
+
+/// Deploys recorded in `deploy.meta`, oldest first
+fn history(
+  runner: &dyn CommandRunner,
+  env: &Env,
+) -> io::Result<Vec<DeployMeta>> {
+  let deploy_meta = shell_quote(&env.deploy_meta());
+  let ssh = &mut ssh(env);
+  ssh.arg(format!("cat {deploy_meta} 2>/dev/null; true"));
+  Ok(parse_history(&runner.output(ssh)?))
+}
+
+/// Parses json lines of `deploy.meta` skipping broken ones,
+/// e.g. cut by full disk
+fn parse_history(output: &str) -> Vec<DeployMeta> {
+  output
+    .lines()
+    .filter_map(|line| serde_json::from_str(line).ok())
+    .collect()
+}
+
+/// Fails if `artifacts` don't fit free space of project filesystem
+///
+/// Unknown `df` output skips check, so odd servers still deploy.
+fn check_disk_space(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  artifacts: &[Artifact],
+) -> io::Result<()> {
+  if artifacts.is_empty() {
+    return Ok(());
+  }
+  let ssh = &mut ssh(env);
+  let project_dir = shell_quote(&env.project_dir);
+  ssh.arg(format!("df -Pk {project_dir}"));
+  let Some(available) = parse_df_available(&runner.output(ssh)?)
+  else {
+    output::println(color::dim(
+      "Free disk space is unknown, skipped",
+    ));
+    return Ok(());
+  };
+  let needed = artifacts
+    .iter()
+    .map(|artifact| local_size(Path::new(&artifact.local)))
+    .sum::<io::Result<u64>>()?
+    .div_ceil(1024);
+  check_fits(needed, available)
+}
+
+/// Available KB of `df -Pk` output
+fn parse_df_available(df: &str) -> Option<u64> {
+  let line = df.lines().nth(1)?;
+  line.split_whitespace().nth(3)?.parse().ok()
+}
+
+/// Fails if `needed` KB exceed `available` ones
+fn check_fits(needed: u64, available: u64) -> io::Result<()> {
+  if needed > available {
+    return Err(other_err(format!(
+      "Insufficient disk space: artifacts need {}, {} available. \
+      Use --no-disk-check to skip check",
+      human_size(needed),
+      human_size(available)
+    )));
+  }
+  Ok(())
+}
+
+/// Size in bytes of local file or dir with all its files
+fn local_size(path: &Path) -> io::Result<u64> {
+  let metadata = fs::metadata(path)?;
+  if !metadata.is_dir() {
+    return Ok(metadata.len());
+  }
+  fs::read_dir(path)?
+    .map(|entry| local_size(&entry?.path()))
+    .sum()
+}
+
+/// Requests health check url from ssh server until it responds with 2xx
+///
+/// # Errors
+///
+/// Returns an error if all attempts failed
+fn check_health(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  health_check: &HealthCheck,
+) -> io::Result<()> {
+  let url = shell_quote(&health_check.url);
+  let interval = Duration::from_secs(health_check.interval);
+  let attempt = retry(health_check.retries, interval, || {
+    let ssh = &mut ssh(env);
+    ssh.arg(format!(
+      "curl -sS -o /dev/null -w '%{{http_code}}' {url} | grep -q '^2'"
+    ));
+    runner.run(ssh)
+  })
+  .map_err(|err| other_err(format!("Health check failed: {err}")))?;
+  output::println(color::green(format!(
+    "Health check passed at attempt {attempt}"
+  )));
+  Ok(())
+}
+
+/// Calls `f` until it succeeds, at most `retries + 1` times
+/// with `interval` between attempts
+///
+/// Returns number of successful attempt starting from 1.
+/// Caught Ctrl-C stops retrying.
+///
+/// # Errors
+///
+/// Returns last error if all attempts failed
+fn retry(
+  retries: u32,
+  interval: Duration,
+  mut f: impl FnMut() -> io::Result<()>,
+) -> io::Result<u32> {
+  let mut attempt = 1;
+  loop {
+    match f() {
+      Ok(()) => return Ok(attempt),
+      Err(err)
+        if attempt > retries || interrupt::is_interrupted() =>
+      {
+        return Err(err);
+      }
+      Err(_) => {
+        attempt += 1;
+        thread::sleep(interval);
+      }
+    }
+  }
+}
+
+/// Checks that all `artifacts` exist locally
+///
+/// # Errors
+///
+/// Returns an error naming first missing artifact
+fn check_artifacts(artifacts: &[Artifact]) -> io::Result<()> {
+  match artifacts
+    .iter()
+    .find(|artifact| !Path::new(&artifact.local).exists())
+  {
+    Some(artifact) => {
+      Err(HmdError::ArtifactNotFound(artifact.local.clone()).into())
+    }
+    None => Ok(()),
+  }
+}
+
+/// Commits work tree with untracked files on top of HEAD
+/// and returns its SHA
+///
+/// Tree is built by temp index, so HEAD, branch, index
+/// and work tree stay untouched and interrupted deploy leaves
+/// no temporary commits. Gitignored files aren't included.
+fn git_temp_commit(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  message: Option<&str>,
+) -> io::Result<String> {
+  let message = message.unwrap_or("dirty");
+  let message = format!("{TEMP_COMMIT_PREFIX} {message}");
+  let dir = create_temp_dir(env)?;
+  let commit = commit_by_index(runner, &dir.join("index"), &message);
+  remove_temp_dir(&dir)?;
+  commit
+}
+
+/// Adds work tree to `index` and commits its tree on top of HEAD
+fn commit_by_index(
+  runner: &dyn CommandRunner,
+  index: &Path,
+  message: &str,
+) -> io::Result<String> {
+  let git = |args: &[&str]| {
+    let mut git = Cmd::new("git");
+    git.args(args).env("GIT_INDEX_FILE", index);
+    git
+  };
+  runner.run(&mut git(&["add", "-A"]))?;
+  let tree = runner.output(&mut git(&["write-tree"]))?;
+  let commit = &mut git(&[
+    "commit-tree",
+    tree.trim(),
+    "-p",
+    "HEAD",
+    "-m",
+    message,
+  ]);
+  Ok(runner.output(commit)?.trim().to_owned())
+}
+
+fn git_push(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  refspec: &str,
+  force: bool,
+) -> io::Result<()> {
+  let git = &mut git_push_cmd(env, refspec, force);
+  runner.run_remote_git(git, env.retries).map_err(|err| {
+    match HmdError::downcast(&err) {
+      // Git exits with 1 on rejected push, fatal errors are 128
+      Some(HmdError::CommandFailed { status })
+        if !force && status.code() == Some(1) =>
+      {
+        other_err(format!(
+          "Push to {} rejected, server branch has commits missing \
+          locally. Check target or deploy without --no-force-push",
+          env.git_remote()
+        ))
+      }
+      _ => err,
+    }
+  })
+}
+
+/// Git push to project repository with ssh options of `env`,
+/// overwriting server branch if `force`
+fn git_push_cmd(env: &Env, refspec: &str, force: bool) -> Cmd {
+  let mut git = Cmd::new("git");
+  let jump =
+    env.jump_host.iter().map(|jump| format!("ProxyJump={jump}"));
+  let user = env.user.iter().map(|user| format!("User={user}"));
+  let ssh_options =
+    env.ssh_options.iter().cloned().chain(jump).chain(user);
+  let ssh_options = ssh_options.collect::<Vec<_>>();
+  if !ssh_options.is_empty() {
+    let options = ssh_options
+      .iter()
+      .map(|option| format!("-o {}", shell_quote(option)))
+      .collect::<Vec<_>>()
+      .join(" ");
+    git.env("GIT_SSH_COMMAND", format!("ssh {options}"));
+  }
+  git.arg("push");
+  if force {
+    git.arg("--force");
+  }
+  git.args([&env.git_remote(), refspec]);
+  git
+}
+
+/// Writes pipeline `script` to unique temp dir and returns its path
+///
+/// Script keeps `Env::PIPELINE_SH` name to be uploaded as is.
+/// Writes `script` readable by owner only, as it may export secrets
+fn write_pipeline_sh(env: &Env, script: &str) -> io::Result<PathBuf> {
+  let pipeline_sh = create_temp_dir(env)?.join(Env::PIPELINE_SH);
+  let mut file = fs::OpenOptions::new();
+  file.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+  file.open(&pipeline_sh)?.write_all(script.as_bytes())?;
+  Ok(pipeline_sh)
+}
+
+/// Generated `pipeline.sh` removed with its temp dir on drop,
+/// so failed or interrupted deploy leaves no stray script
+struct PipelineSh {
+  path: PathBuf,
+  kept: bool,
+}
+
+impl PipelineSh {
+  fn write(env: &Env, script: &str) -> io::Result<Self> {
+    let path = write_pipeline_sh(env, script)?;
+    Ok(Self { path, kept: false })
+  }
+
+  /// Leaves script to inspect it after deploy
+  fn keep(mut self) -> PathBuf {
+    self.kept = true;
+    self.path.clone()
+  }
+}
+
+impl Drop for PipelineSh {
+  fn drop(&mut self) {
+    if !self.kept {
+      // Stray temp file isn't worth failing deploy
+      let _ = remove_pipeline_sh(&self.path);
+    }
+  }
+}
+
+/// Hex sha256 of `pipeline.sh` text
+fn script_hash(script: &str) -> String {
+  format!("{:x}", Sha256::digest(script))
+}
+
+/// Saves sha256 `hash` of `pipeline.sh` to work tree,
+/// so restart checks script against last deploy
+fn record_script_hash_cmd(hash: &str) -> String {
+  let pipeline_sh = Env::PIPELINE_SH;
+  let pipeline_sha256 = Env::PIPELINE_SHA256;
+  format!("echo '{hash}  {pipeline_sh}' > {pipeline_sha256};")
+}
+
+/// Fails if `pipeline.sh` of work tree doesn't match recorded
+/// hash, so stale script isn't run
+fn verify_script_cmd() -> String {
+  let pipeline_sh = Env::PIPELINE_SH;
+  let pipeline_sha256 = Env::PIPELINE_SHA256;
+  format!(
+    "sha256sum -c --status {pipeline_sha256} || {{ \
+    echo '{pipeline_sh} at server differs from deployed one, deploy to update it' >&2; \
+    exit 1; }};"
+  )
+}
+
+/// Text of `pipeline.sh` running stages of `hmd_yml`
+///
+/// It doesn't touch filesystem, so it's shared by deploy
+/// and `--print-pipeline`. `env_vars` are exported before
+/// tracing starts to keep secrets out of trace.
+fn build_pipeline_script(
+  env: &Env,
+  hmd_yml: &HmdYml,
+  env_vars: &[(String, String)],
+) -> String {
+  let notify = |status: &str| match &hmd_yml.settings.webhook_url {
+    Some(url) => {
+      let project = &env.project;
+      let ssh_address = &env.ssh_address;
+      let text = format!("{project} at {ssh_address}: {status}");
+      // Webhook url is a secret, so it's kept out of trace
+      format!(
+        "trace_off; {} trace_on;",
+        webhook::notify_cmd(url, &text)
+      )
+    }
+    None => String::new(),
+  };
+  let stages = &mut hmd_yml.stages.clone();
+  if let Supervisor::Systemd = hmd_yml.settings.supervisor {
+    if let Some((_, stage)) = stages.last_mut() {
+      *stage = Stage::Cmd(systemd::restart_cmd(&env.project));
+    }
+  }
+  let pipeline =
+    stage_commands(stages, hmd_yml.settings.stage_retries, &notify)
+      .join("\n\n");
+  let succeeded = notify("✅ Pipeline succeeded");
+  let stages = stages.keys().cloned().collect::<Vec<_>>().join(" ");
+  let trace = u8::from(output::is_tracing());
+  let marker =
+    format!("echo \"{DEPLOY_MARKER} `date +%FT%T` =====\";");
+  let exports = dotenv::exports_cmd(env_vars);
+  format!(
+    "{SCRIPT}\n\ntrace={trace};\nstages=({stages});\n{exports}\n\
+    {marker}\ntrace_on;\n\n{pipeline}\n\n{succeeded}"
+  )
+}
+
+/// Counter making temp dirs of one process unique
+static TEMP_DIRS: AtomicU32 = AtomicU32::new(0);
+
+/// Creates unique temp dir of `env` project
+///
+/// Name has pid and counter, existing dir, e.g. kept one
+/// of process with reused pid, is skipped.
+fn create_temp_dir(env: &Env) -> io::Result<PathBuf> {
+  let project = &env.project;
+  let pid = std::process::id();
+  loop {
+    let n = TEMP_DIRS.fetch_add(1, Ordering::Relaxed);
+    let path =
+      std::env::temp_dir().join(format!("hmd-{project}-{pid}-{n}"));
+    match fs::create_dir(&path) {
+      Ok(()) => return Ok(path),
+      Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+      Err(err) => return Err(err),
+    }
+  }
+}
+
+/// Removes pipeline script with its temp dir
+fn remove_pipeline_sh(pipeline_sh: &Path) -> io::Result<()> {
+  remove_temp_dir(pipeline_sh.parent().unwrap_or(pipeline_sh))
+}
+
+fn remove_temp_dir(dir: &Path) -> io::Result<()> {
+  match fs::remove_dir_all(dir) {
+    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+    _ => Ok(()),
+  }
+}
+
+/// Installs systemd unit running last stage
+fn install_unit(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  hmd_yml: &HmdYml,
+) -> io::Result<()> {
+  let Some((_, stage)) = hmd_yml.stages.last() else {
+    return Ok(());
+  };
+  let ssh = &mut ssh(env);
+  ssh.arg(systemd::install_cmd(env, &stage.script()));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Uploads `artifacts` by archives or by scp as `options` say
+fn upload_artifacts(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  artifacts: &[Artifact],
+  options: &DeployOptions,
+) -> io::Result<()> {
+  if options.compress_artifacts {
+    upload_archives(runner, env, artifacts)
+  } else {
+    upload(runner, env, artifacts, options.parallel)
+  }
+}
+
+/// Uploads `artifacts` packed by local tar, one gzipped archive
+/// per upload dir, by single scp and unpacks them remotely
+///
+/// One archive is much faster than scp of many small files.
+/// Archives are removed with upload dir after move.
+fn upload_archives(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  artifacts: &[Artifact],
+) -> io::Result<()> {
+  if artifacts.is_empty() {
+    return Ok(());
+  }
+  let ssh = &mut ssh(env);
+  ssh.arg(prepare_upload_dir_cmd(env, artifacts));
+  runner.run_remote(ssh, env.retries)?;
+  let transfers = upload_buckets(artifacts, 1).concat();
+  let temp_dir = create_temp_dir(env)?;
+  let uploaded = pack_and_scp(runner, env, &transfers, &temp_dir);
+  remove_temp_dir(&temp_dir)?;
+  uploaded?;
+  let ssh = &mut self::ssh(env);
+  ssh.arg(format!(
+    "{} && {}",
+    unpack_archives_cmd(env, &transfers),
+    move_uploaded_cmd(env, artifacts)
+  ));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Packs each of `transfers` to archive in `temp_dir`
+/// and copies them to upload dir
+fn pack_and_scp(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  transfers: &[Transfer],
+  temp_dir: &Path,
+) -> io::Result<()> {
+  let mut archives = Vec::new();
+  for (i, (_, locals)) in transfers.iter().enumerate() {
+    let archive = temp_dir.join(archive_name(i));
+    runner.run(&mut tar_cmd(&archive, locals))?;
+    archives.push(archive.to_string_lossy().into_owned());
+  }
+  let scp = &mut scp(env, "", &archives, 1, None);
+  runner.run_remote(scp, env.retries)
+}
+
+/// Name of archive of `i`-th transfer
+fn archive_name(i: usize) -> String {
+  format!("{ARTIFACTS_ARCHIVE}-{i}.tar.gz")
+}
+
+/// Packs `locals` to gzipped `archive` by their file names,
+/// like scp puts them to remote dir
+fn tar_cmd(archive: &Path, locals: &[String]) -> Cmd {
+  let mut tar = Cmd::new("tar");
+  tar.arg("-czf").arg(archive);
+  for local in locals {
+    let path = Path::new(local);
+    match (path.parent(), path.file_name()) {
+      (Some(dir), Some(name)) if !dir.as_os_str().is_empty() => {
+        tar.arg("-C").arg(dir).arg(name);
+      }
+      _ => {
+        tar.arg(local);
+      }
+    }
+  }
+  tar
+}
+
+/// Unpacks archives of `transfers` to their dirs of upload dir
+fn unpack_archives_cmd(env: &Env, transfers: &[Transfer]) -> String {
+  let upload_dir = &env.upload_dir;
+  transfers
+    .iter()
+    .enumerate()
+    .map(|(i, (dir, _))| {
+      let archive =
+        shell_quote(&format!("{upload_dir}/{}", archive_name(i)));
+      let dir = match dir.as_str() {
+        "" => shell_quote(upload_dir),
+        dir => shell_quote(&format!("{upload_dir}/{dir}")),
+      };
+      format!("tar -xzf {archive} -C {dir}")
+    })
+    .collect::<Vec<_>>()
+    .join(" && ")
+}
+
+/// Uploads `artifacts` to their remote paths
+/// by at most `parallel` concurrent scp
+fn upload(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  artifacts: &[Artifact],
+  parallel: usize,
+) -> io::Result<()> {
+  if artifacts.is_empty() {
+    return Ok(());
+  }
+  let ssh = &mut ssh(env);
+  ssh.arg(prepare_upload_dir_cmd(env, artifacts));
+  runner.run_remote(ssh, env.retries)?;
+  let buckets = upload_buckets(artifacts, parallel);
+  // Concurrent scp would race to become master,
+  // so it's opened before them
+  let master = if buckets.len() > 1 {
+    Some(SshMaster::open(runner, env)?)
+  } else {
+    None
+  };
+  let control_path =
+    master.as_ref().map(|master| master.control_path.as_str());
+  let spinner = Spinner::start("Uploading artifacts");
+  let errors = thread::scope(|scope| {
+    let uploads = buckets
+      .iter()
+      .map(|bucket| {
+        scope.spawn(|| {
+          bucket.iter().try_for_each(|(dir, chunk)| {
+            let scp =
+              &mut scp(env, dir, chunk, buckets.len(), control_path);
+            runner.run_remote(scp, env.retries)
+          })
+        })
+      })
+      .collect::<Vec<_>>();
+    uploads
+      .into_iter()
+      .filter_map(|upload| match upload.join() {
+        Ok(result) => result.err(),
+        Err(_) => Some(other_err("Upload thread panicked")),
+      })
+      .collect::<Vec<_>>()
+  });
+  drop(spinner);
+  if let Some(err) = upload_error(errors, buckets.len()) {
+    return Err(err);
+  }
+  let ssh = &mut self::ssh(env);
+  ssh.arg(move_uploaded_cmd(env, artifacts));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Error of failed uploads of `uploads`, if any failed
+///
+/// Single failure is returned as is.
+fn upload_error(
+  errors: Vec<io::Error>,
+  uploads: usize,
+) -> Option<io::Error> {
+  if errors.len() > 1 {
+    let failed = errors.len();
+    let errors = errors
+      .iter()
+      .map(ToString::to_string)
+      .collect::<Vec<_>>()
+      .join("; ");
+    return Some(other_err(format!(
+      "{failed} of {uploads} uploads failed: {errors}"
+    )));
+  }
+  errors.into_iter().next()
+}
+
+/// Ssh master connection shared by concurrent scp,
+/// it's closed on drop
+struct SshMaster<'a> {
+  runner: &'a dyn CommandRunner,
+  env: &'a Env,
+  /// `ControlPath` option of master
+  control_path: String,
+}
+
+impl<'a> SshMaster<'a> {
+  /// Opens master in background by `ssh -MNf`,
+  /// control path is unique per process
+  fn open(
+    runner: &'a dyn CommandRunner,
+    env: &'a Env,
+  ) -> io::Result<Self> {
+    let control_path =
+      format!("ControlPath=~/.ssh/hmd-{}-%C", std::process::id());
+    let master = Self {
+      runner,
+      env,
+      control_path,
+    };
+    let ssh = &mut master.ssh(&["-M", "-N", "-f"]);
+    runner.run_remote(ssh, env.retries)?;
+    Ok(master)
+  }
+
+  /// Ssh to master server with `flags` and control path
+  fn ssh(&self, flags: &[&str]) -> Cmd {
+    let flags = [flags, &["-o", &self.control_path]].concat();
+    ssh_with(self.env, &flags)
+  }
+}
+
+impl Drop for SshMaster<'_> {
+  fn drop(&mut self) {
+    let ssh = &mut self.ssh(&["-O", "exit"]);
+    // Master exits with ssh anyway if it fails
+    let _ = self.runner.status(ssh.stderr(Stdio::null()));
+  }
+}
+
+/// Remote dir and artifacts copied to it by one scp
+type Transfer = (String, Vec<String>);
+
+/// Splits `artifacts` to at most `parallel` buckets of transfers
+///
+/// Artifacts are grouped by remote dir, since one scp copies
+/// to single dir, and groups are split to `parallel` chunks.
+fn upload_buckets(
+  artifacts: &[Artifact],
+  parallel: usize,
+) -> Vec<Vec<Transfer>> {
+  let parallel = parallel.max(1);
+  let mut groups = IndexMap::<String, Vec<String>>::new();
+  for artifact in artifacts {
+    groups
+      .entry(staging_dir(artifact))
+      .or_default()
+      .push(artifact.local.clone());
+  }
+  let mut buckets = vec![Vec::new(); parallel];
+  let transfers = groups.into_iter().flat_map(|(dir, group)| {
+    let size = group.len().div_ceil(parallel);
+    group
+      .chunks(size)
+      .map(|chunk| (dir.clone(), chunk.to_vec()))
+      .collect::<Vec<_>>()
+  });
+  for (i, transfer) in transfers.enumerate() {
+    buckets[i % parallel].push(transfer);
+  }
+  buckets.retain(|bucket| !bucket.is_empty());
+  buckets
+}
+
+/// Dir of upload dir `artifact` is copied to before move
+fn staging_dir(artifact: &Artifact) -> String {
+  let remote = &artifact.remote;
+  let staged = if let Some(path) = remote.strip_prefix("~/") {
+    format!("{UPLOAD_HOME_DIR}/{path}")
+  } else if let Some(path) = remote.strip_prefix('/') {
+    format!("{UPLOAD_ROOT_DIR}/{path}")
+  } else {
+    remote.clone()
+  };
+  Path::new(&staged)
+    .parent()
+    .map(|dir| dir.to_string_lossy().into_owned())
+    .unwrap_or_default()
+}
+
+/// Path of uploaded `artifact` relative to upload dir
+///
+/// Scp keeps local file name, it's renamed by move.
+fn staged_path(artifact: &Artifact) -> String {
+  let name = Path::new(&artifact.local)
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_default();
+  match staging_dir(artifact).as_str() {
+    "" => name,
+    dir => format!("{dir}/{name}"),
+  }
+}
+
+/// Remote path of `artifact`, relative one is inside work tree
+fn artifact_destination(env: &Env, artifact: &Artifact) -> String {
+  let remote = &artifact.remote;
+  if remote.starts_with('/') || remote.starts_with("~/") {
+    remote.clone()
+  } else {
+    format!("{}/{remote}", env.work_tree)
+  }
+}
+
+/// Scp with connection options of project server
+fn scp_cmd(env: &Env) -> Cmd {
+  let mut scp = Cmd::new("scp");
+  if is_ipv6(&env.ssh_address) {
+    scp.arg("-6");
+  }
+  if let Some(port) = env.port {
+    scp.args(["-P", &port.to_string()]);
+  }
+  if env.compress {
+    scp.arg("-C");
+  }
+  if let Some(jump_host) = &env.jump_host {
+    scp.arg("-o").arg(format!("ProxyJump={jump_host}"));
+  }
+  // `-l` of scp is bandwidth limit
+  if let Some(user) = &env.user {
+    scp.arg("-o").arg(format!("User={user}"));
+  }
+  for option in &env.ssh_options {
+    scp.arg("-o").arg(option);
+  }
+  scp
+}
+
+/// Copies `artifacts` to `dir` of upload dir
+///
+/// Upload limit is split between `concurrent` ones.
+/// They share connection by `control_path` of [`SshMaster`].
+fn scp(
+  env: &Env,
+  dir: &str,
+  artifacts: &[String],
+  concurrent: usize,
+  control_path: Option<&str>,
+) -> Cmd {
+  let mut scp = scp_cmd(env);
+  scp.arg("-r");
+  if let Some(limit) = env.upload_limit {
+    // scp limit is in Kbit/s
+    let limit = u64::from(limit) * 8 / concurrent.max(1) as u64;
+    scp.args(["-l", &limit.max(1).to_string()]);
+  }
+  if let Some(control_path) = control_path {
+    scp.args(["-o", control_path]);
+  }
+  let upload_dir = &env.upload_dir;
+  let dest = match dir {
+    "" => env.remote_path(upload_dir),
+    dir => env.remote_path(&format!("{upload_dir}/{dir}")),
+  };
+  scp.args(artifacts).arg(dest);
+  scp
+}
+
+/// Recreates empty upload dir with dirs of `artifacts`,
+/// so artifacts are uploaded aside work tree
+/// and interrupted upload leaves it untouched
+fn prepare_upload_dir_cmd(
+  env: &Env,
+  artifacts: &[Artifact],
+) -> String {
+  let upload_dir = &env.upload_dir;
+  let dirs = artifacts
+    .iter()
+    .filter_map(|artifact| {
+      let dir = staging_dir(artifact);
+      (!dir.is_empty())
+        .then(|| shell_quote(&format!("{upload_dir}/{dir}")))
+    })
+    .collect::<Vec<_>>()
+    .join(" ");
+  let upload_dir = shell_quote(upload_dir);
+  format!("rm -rf {upload_dir}; mkdir -p {upload_dir} {dirs};")
+}
+
+/// Moves uploaded artifacts from upload dir to their remote paths
+///
+/// Remote path is taken as artifact itself, like by `mv -T`,
+/// so only previous dir of dir artifact is removed, while file
+/// never replaces dir and never lands inside it.
+fn move_uploaded_cmd(env: &Env, artifacts: &[Artifact]) -> String {
+  let moves = artifacts
+    .iter()
+    .map(|artifact| {
+      let staged = staged_path(artifact);
+      let from = shell_quote(&format!("{}/{staged}", env.upload_dir));
+      let to = artifact_destination(env, artifact);
+      let dir = Path::new(&to)
+        .parent()
+        .map(|dir| shell_quote(&dir.to_string_lossy()))
+        .unwrap_or_default();
+      let to = shell_quote(&to);
+      format!(
+        "mkdir -p {dir} && {{ [ -d {from} ] && [ -d {to} ] \
+        && rm -rf {to}; mv -fT {from} {to}; }}"
+      )
+    })
+    .collect::<Vec<_>>()
+    .join(" && ");
+  let upload_dir = shell_quote(&env.upload_dir);
+  format!("{moves} && rm -rf {upload_dir};")
+}
+
+/// Checks out `checkout` ref at work tree and launches pipeline
+///
+/// With `no_checkout` option work tree is only checked
+/// to be not empty, without `checkout` it's left as is.
+/// With `wait` option streams pipeline output
+/// and fails if pipeline fails.
+fn run_pipeline(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  options: &DeployOptions,
+  checkout: Option<&str>,
+  supervisor: Supervisor,
+  script_hash: &str,
+) -> io::Result<()> {
+  let pipeline_pid = &env.pipeline_pid_file();
+  let work_tree = &shell_quote(&env.work_tree);
+  // FIXME: Why git doesn't recognize ~ path?
+  let git_dir = shell_quote(&env.git_dir);
+  let git_dir = match git_dir.strip_prefix('~') {
+    Some(path) => format!("$HOME{path}"),
+    None => git_dir,
+  };
+  let checkout = match checkout {
+    _ if options.no_checkout => Some(check_work_tree_cmd()),
+    Some(checkout) => {
+      let checkout = shell_quote(checkout);
+      Some(format!(
+        "git --git-dir={git_dir} --work-tree=. checkout --force {checkout};"
+      ))
+    }
+    // Work tree is made of uploaded artifacts only
+    None => None,
+  };
+  let ssh = &mut ssh(env);
+  ssh
+    .args(source_profile_cmd(&env.profile_file))
+    .arg(format!("cd {work_tree};"))
+    .arg(record_script_hash_cmd(script_hash))
+    .arg(verify_script_cmd())
+    .arg(kill_and_wait_cmd(pipeline_pid))
+    .args(checkout)
+    .arg(nohup_pipeline_cmd(
+      &env.remote_shell,
+      env.instance.as_deref(),
+      options.timeout,
+    ));
+  if options.wait {
+    // Not retried to not launch pipeline twice
+    ssh.arg(wait_pipeline_cmd(&env.out_log_file()));
+    let waited = interrupt::catching(|| runner.run(ssh));
+    if waited.is_err() && interrupt::is_interrupted() {
+      let stop = confirm_stop(env)?;
+      return Err(interrupted_wait(runner, env, supervisor, stop));
+    }
+    waited?;
+  } else {
+    let _spinner = Spinner::start("Launching pipeline");
+    runner.run_remote(ssh, env.retries)?;
+  }
+  Ok(())
+}
+
+/// Stops pipeline of interrupted `deploy --wait` if `stop`,
+/// otherwise prints hint that it still runs
+///
+/// Returns `Interrupted` error, or error of failed stop.
+fn interrupted_wait(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  supervisor: Supervisor,
+  stop: bool,
+) -> io::Error {
+  if !stop {
+    output::println(interrupt::STILL_RUNNING_HINT);
+  } else if let Err(err) = self::stop(runner, env, supervisor) {
+    return err;
+  }
+  HmdError::Interrupted.into()
+}
+
+/// Fails if work tree has no files except ones of hmd
+fn check_work_tree_cmd() -> String {
+  let own_files = [
+    Env::DEPLOY_META,
+    Env::EVENTS_LOG,
+    Env::EXIT_CODE,
+    Env::OUT_LOG,
+    Env::PIPELINE_PID,
+    Env::PIPELINE_SH,
+    Env::PIPELINE_SHA256,
+    Env::STATUS_LOG,
+    Env::STAGE_LOGS,
+  ]
+  .map(|file| file.replace('.', r"\."))
+  .join("|");
+  format!(
+    "ls -A | grep -Evq '^({own_files})(\\..*)?$' || {{ \
+    echo 'Work tree is empty, deploy without --no-checkout' >&2; \
+    exit 1; }};"
+  )
+}
+
+/// Relaunches pipeline, only running one if `if_running`
+///
+/// With `verify` script at server is checked against hash
+/// recorded by last deploy.
+fn restart_pipeline(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  timeout: Option<u64>,
+  if_running: bool,
+  verify: bool,
+) -> io::Result<()> {
+  let work_tree = &shell_quote(&env.work_tree);
+  let ssh = &mut ssh(env);
+  ssh
+    .args(source_profile_cmd(&env.profile_file))
+    .arg(format!("cd {work_tree};"))
+    .args(verify.then(verify_script_cmd))
+    .arg(restart_cmd(
+      &env.remote_shell,
+      env.instance.as_deref(),
+      timeout,
+      if_running,
+    ));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Relaunches pipeline of `instance` in current work tree
+/// by `shell`
+fn restart_cmd(
+  shell: &str,
+  instance: Option<&str>,
+  timeout: Option<u64>,
+  if_running: bool,
+) -> String {
+  let pipeline_pid = &Env::instance_file(Env::PIPELINE_PID, instance);
+  let restart = format!(
+    "{} {}",
+    kill_and_wait_cmd(pipeline_pid),
+    nohup_pipeline_cmd(shell, instance, timeout)
+  );
+  if if_running {
+    format!(
+      "if {}; then {restart} else echo \"nothing to restart\"; fi;",
+      is_running_cmd(pipeline_pid)
+    )
+  } else {
+    restart
+  }
+}
+
+/// Sources `profile_file` of login environment, unless it's empty
+fn source_profile_cmd(profile_file: &str) -> Option<String> {
+  (!profile_file.is_empty())
+    .then(|| format!("source {};", shell_quote(profile_file)))
+}
+
+/// Launches `pipeline.sh` by `shell` in background and saves its pid
+///
+/// Pid and logs of named `instance` are suffixed by its name,
+/// `HMD_STATUS_LOG`, `HMD_EXIT_CODE` and `HMD_EVENTS_LOG` tell
+/// pipeline its `status.log`, `exit.code` and `events.log`.
+/// Previous `out.log` is rotated by `rotate_log_cmd`.
+/// With `timeout` pipeline is wrapped by `timeout` command.
+/// Pid of pipeline shell is saved instead of `timeout` one,
+/// so `kill_and_wait_cmd` still interrupts stages.
+/// Waiting for it gives up printing `out_log` if `timeout` exits
+/// or pid isn't written in `PIPELINE_START_SECS`, e.g. of missing shell.
+fn nohup_pipeline_cmd(
+  shell: &str,
+  instance: Option<&str>,
+  timeout: Option<u64>,
+) -> String {
+  let out_log = &Env::instance_file(Env::OUT_LOG, instance);
+  let pipeline_sh = Env::PIPELINE_SH;
+  let pipeline_pid = Env::instance_file(Env::PIPELINE_PID, instance);
+  let rotate_log = rotate_log_cmd(out_log);
+  let nohup = match instance {
+    Some(_) => format!(
+      "HMD_STATUS_LOG={} HMD_EXIT_CODE={} HMD_EVENTS_LOG={} nohup",
+      Env::instance_file(Env::STATUS_LOG, instance),
+      Env::instance_file(Env::EXIT_CODE, instance),
+      Env::instance_file(Env::EVENTS_LOG, instance),
+    ),
+    None => "nohup".to_owned(),
+  };
+  let nohup = match timeout {
+    None => format!(
+      "{nohup} {shell} {pipeline_sh} > {out_log} 2>&1 & echo $! > {pipeline_pid};"
+    ),
+    Some(secs) => {
+      let ticks = PIPELINE_START_SECS * 10;
+      format!(
+        "rm -f {pipeline_pid}; \
+        {nohup} timeout {secs} {shell} -c 'echo $$ > {pipeline_pid}; exec {shell} {pipeline_sh}' > {out_log} 2>&1 & \
+        timeout_pid=$!; tick=0; \
+        until [ -s {pipeline_pid} ]; do \
+        if ! kill -0 $timeout_pid 2>/dev/null || [ $tick -ge {ticks} ]; then \
+        [ -s {pipeline_pid} ] && break; \
+        echo 'Pipeline failed to start:' >&2; cat {out_log} >&2; exit 1; fi; \
+        tick=$((tick + 1)); sleep 0.1; done;"
+      )
+    }
+  };
+  format!("{rotate_log} {nohup}")
+}
+
+/// Renames `out_log` to `<out_log>.<%Y%m%dT%H%M%S>` of its mtime
+fn rotate_log_cmd(out_log: &str) -> String {
+  format!(
+    "if [ -f {out_log} ]; then \
+    mv {out_log} {out_log}.`date -r {out_log} +%Y%m%dT%H%M%S`; fi;"
+  )
+}
+
+/// Removes rotated logs except `keep` most recent ones
+fn prune(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  keep: usize,
+) -> io::Result<()> {
+  let work_tree = &shell_quote(&env.work_tree);
+  let ssh = &mut ssh(env);
+  ssh
+    .arg(format!("cd {work_tree};"))
+    .arg(prune_logs_cmd(&env.out_log_file(), keep));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Rotated log names sort by time, so newest are listed first
+/// by reversed sort
+fn prune_logs_cmd(out_log: &str, keep: usize) -> String {
+  let skip = keep + 1;
+  format!(
+    "ls -1 {out_log}.* 2>/dev/null | sort -r | tail -n +{skip} \
+    | xargs -r rm -fv;"
+  )
+}
+
+/// Streams output of pipeline launched by `nohup_pipeline_cmd`
+/// and exits with its status
+fn wait_pipeline_cmd(out_log: &str) -> String {
+  format!("tail -n +1 -f {out_log} --pid $!; wait $!")
+}
+
+/// Current branch or `DEPLOY_BRANCH` in detached HEAD state
+fn git_branch(runner: &dyn CommandRunner) -> io::Result<String> {
+  let branch = git_output(runner, &["branch", "--show-current"])?;
+  if branch.is_empty() {
+    return Ok(DEPLOY_BRANCH.to_owned());
+  }
+  Ok(branch)
+}
+
+/// Resolves `git_ref` to commit SHA
+///
+/// # Errors
+///
+/// Returns an error if `git_ref` doesn't exist locally
+fn git_rev_parse(
+  runner: &dyn CommandRunner,
+  git_ref: &str,
+) -> io::Result<String> {
+  let not_found = || HmdError::RefNotFound(git_ref.to_owned()).into();
+  let sha = git_output(
+    runner,
+    &[
+      "rev-parse",
+      "--verify",
+      "--quiet",
+      &format!("{git_ref}^{{commit}}"),
+    ],
+  )
+  .map_err(|err| match HmdError::downcast(&err) {
+    Some(HmdError::CommandFailed { .. }) => not_found(),
+    _ => err,
+  })?;
+  if sha.is_empty() {
+    return Err(not_found());
+  }
+  Ok(sha)
+}
+
+fn git_output(
+  runner: &dyn CommandRunner,
+  args: &[&str],
+) -> io::Result<String> {
+  let output = runner.output(Cmd::new("git").args(args))?;
+  Ok(output.trim().to_owned())
+}
+
+/// Builds stage commands
+///
+/// Failed stage is retried by its own `retries` or `retries`
+/// of all stages. `notify` builds command to report failure of stage.
+fn stage_commands(
+  stages: &IndexMap<String, Stage>,
+  retries: Option<u32>,
+  notify: &dyn Fn(&str) -> String,
+) -> Vec<String> {
+  stages
+    .iter()
+    .enumerate()
+    .map(|(i, (stage, options))| {
+      let failed = notify(&format!("❌ Failed {stage}"));
+      let cmd = options.cmd();
+      let script = match options.retries().or(retries) {
+        Some(retries) if retries > 0 => {
+          retry_cmd(i, stage, &options.script(), retries)
+        }
+        _ => options.script(),
+      };
+      let stage_log = shell_quote(&Env::stage_log_file(stage));
+      // Process substitution keeps stage in pipeline shell
+      // and its exit status
+      format!(
+        r#"
+          echo -e "\n🟩 [`date +%FT%T`] > Start {stage}\n{cmd}\n";
+          run {i} && {{ {script}; }} > >(tee {stage_log}) 2>&1 \
+            && complete {i} || {{
+            echo -e "\n❌ [`date +%FT%T`] > Failed {stage}\n";
+            {failed}
+            panic {i};
+            exit 1;
+          }};
+          echo -e "\n🟩 [`date +%FT%T`] > End {stage}\n";
+        "#
+      )
+    })
+    .collect()
+}
+
+/// Runs `script` of stage `i` again until it succeeds
+/// or fails `retries` more times
+///
+/// Each attempt starts at the same dir, as failed `script`
+/// may have left it.
+fn retry_cmd(
+  i: usize,
+  stage: &str,
+  script: &str,
+  retries: u32,
+) -> String {
+  format!(
+    r#"stage_dir=$PWD; for attempt in {{0..{retries}}}; do
+            if (( attempt > 0 )); then
+              cd "$stage_dir";
+              echo -e "\n🔁 [`date +%FT%T`] > Retry {stage} $attempt/{retries}\n";
+              event {i} retry;
+              sleep {STAGE_RETRY_SECS};
+            fi;
+            {{ {script}; }} && break;
+            (( attempt < {retries} ));
+          done"#
+  )
+}
+
+fn stop(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  supervisor: Supervisor,
+) -> io::Result<()> {
+  let ssh = &mut ssh(env);
+  ssh.arg(kill_and_wait_cmd(&env.pipeline_pid()));
+  if let Supervisor::Systemd = supervisor {
+    ssh.arg(systemd::stop_cmd(&env.project));
+  }
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Stops pipelines of all projects of `target`
+///
+/// # Errors
+///
+/// Returns an error if ssh fails
+pub fn stop_all(
+  runner: &dyn CommandRunner,
+  target: &Target,
+) -> io::Result<()> {
+  let ssh = &mut target_ssh(target);
+  ssh.arg(stop_all_cmd(&target.projects_dir()));
+  runner.run_remote(ssh, target.retries.unwrap_or_default())?;
+  Ok(())
+}
+
+/// Stops pipeline of every project in `projects_dir`
+/// reporting whether it was stopped or already idle
+fn stop_all_cmd(projects_dir: &str) -> String {
+  let projects_dir = shell_quote(projects_dir);
+  let pipeline_pid = Env::PIPELINE_PID;
+  let kill_and_wait = kill_and_wait_cmd(pipeline_pid);
+  format!(
+    "for project in `ls {projects_dir}`; do \
+    (cd {projects_dir}/\"$project\"/work-tree 2>/dev/null || exit; \
+    if pgrep -P `cat {pipeline_pid} 2>/dev/null` > /dev/null 2>&1; \
+    then {kill_and_wait} echo \"$project: stopped\"; \
+    else echo \"$project: idle\"; fi); done;"
+  )
+}
+
+/// Restarts pipelines of all deployed projects of `target`
+///
+/// # Errors
+///
+/// Returns an error if ssh fails
+pub fn restart_all(
+  runner: &dyn CommandRunner,
+  target: &Target,
+  timeout: Option<u64>,
+  if_running: bool,
+) -> io::Result<()> {
+  let ssh = &mut target_ssh(target);
+  ssh.args(source_profile_cmd(target.profile_file())).arg(
+    restart_all_cmd(
+      &target.projects_dir(),
+      target.remote_shell(),
+      timeout,
+      if_running,
+    ),
+  );
+  runner.run_remote(ssh, target.retries.unwrap_or_default())?;
+  Ok(())
+}
+
+/// Restarts pipeline of every project in `projects_dir`
+/// skipping ones never deployed, i.e. without `pipeline.sh`
+fn restart_all_cmd(
+  projects_dir: &str,
+  shell: &str,
+  timeout: Option<u64>,
+  if_running: bool,
+) -> String {
+  let projects_dir = shell_quote(projects_dir);
+  let pipeline_sh = Env::PIPELINE_SH;
+  let restart = restart_cmd(shell, None, timeout, if_running);
+  format!(
+    "for project in `ls {projects_dir}`; do \
+    (cd {projects_dir}/\"$project\"/work-tree 2>/dev/null || exit; \
+    if [ -f {pipeline_sh} ]; \
+    then echo \"$project: restarting\"; {restart} \
+    else echo \"$project: never deployed\"; fi); done;"
+  )
+}
+
+fn kill_and_wait_cmd(pipeline_pid: &str) -> String {
+  let pipeline_pid = shell_quote(pipeline_pid);
+  format!(
+    "while pkill -SIGINT -P `cat {pipeline_pid}` 2>/dev/null; do sleep 1; done;"
+  )
+}
+
+/// Streams status until pipeline exits
+///
+/// With `watch_exit` fails with exit status of pipeline.
+fn status(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  watch_exit: bool,
+  tail: Option<usize>,
+  supervisor: Supervisor,
+) -> io::Result<()> {
+  let ssh = &mut ssh(env);
+  ssh.arg(status_cmd(env, tail));
+  if let Supervisor::Systemd = supervisor {
+    ssh.arg(systemd::status_cmd(&env.project));
+  }
+  if watch_exit {
+    ssh.arg(exit_code_cmd(&env.exit_code()));
+  }
+  stream_remote(runner, env, ssh)
+}
+
+/// Follows `status.log` printing last `tail` entries before
+fn status_cmd(env: &Env, tail: Option<usize>) -> String {
+  let status_log = shell_quote(&env.status_log());
+  let pid = shell_quote(&env.pipeline_pid());
+  let lines = tail.map(|n| format!("-n {n} ")).unwrap_or_default();
+  format!("tail {lines}-f {status_log} --pid `cat {pid}`;")
+}
+
+fn status_summary(
+  runner: &dyn CommandRunner,
+  env: &Env,
+) -> io::Result<StatusSummary> {
+  let ssh = &mut ssh(env);
+  ssh.arg(status_summary_cmd(env));
+  let output = runner.output(ssh)?;
+  Ok(parse_status_summary(&env.project, &output))
+}
+
+/// Prints pid of running pipeline or empty line
+/// and then `status.log`
+fn status_summary_cmd(env: &Env) -> String {
+  let is_running = is_running_cmd(&env.pipeline_pid());
+  let status_log = shell_quote(&env.status_log());
+  format!(
+    r#"if {is_running}; then echo "$pid"; else echo; fi; cat {status_log} 2>/dev/null; true"#
+  )
+}
+
+/// Parses output of `status_summary_cmd`
+///
+/// Stage lines of `status.log` are marked by emoji,
+/// pending ones by yellow and red squares, and the last line
+/// is time of last event.
+fn parse_status_summary(
+  project: &str,
+  output: &str,
+) -> StatusSummary {
+  let mut lines = output.lines();
+  let pid = lines.next().and_then(|pid| pid.trim().parse().ok());
+  let lines = lines
+    .map(str::trim)
+    .filter(|line| !line.is_empty())
+    .collect::<Vec<_>>();
+  let last_stage = lines
+    .iter()
+    .rev()
+    .filter_map(|line| line.split_once(' '))
+    .filter(|(mark, _)| ["✅", "🟩", "❌"].contains(mark))
+    .map(|(_, stage)| stage.to_owned())
+    .next();
+  let last_event_ts = lines
+    .last()
+    .filter(|line| !line.contains(' '))
+    .map(|&line| line.to_owned());
+  StatusSummary {
+    project: project.to_owned(),
+    running: pid.is_some(),
+    pid,
+    last_stage,
+    last_event_ts,
+  }
+}
+
+/// Exits with code recorded by pipeline or 1 if it's missing
+fn exit_code_cmd(exit_code: &str) -> String {
+  let exit_code = shell_quote(exit_code);
+  format!("exit `cat {exit_code} 2>/dev/null || echo 1`;")
+}
+
+fn ps(runner: &dyn CommandRunner, env: &Env) -> io::Result<()> {
+  let ssh = &mut ssh(env);
+  ssh.arg(ps_cmd(&env.pipeline_pid()));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Prints pipeline process and its children usage
+/// or `not running` if pipeline pid isn't alive
+fn ps_cmd(pipeline_pid: &str) -> String {
+  let is_running = is_running_cmd(pipeline_pid);
+  format!(
+    r#"if {is_running}; then
+      ps -o pid,pcpu,pmem,etime,cmd -p "$pid" --ppid "$pid";
+    else
+      echo "not running";
+    fi"#
+  )
+}
+
+/// Checks pipeline is alive, sets `pid` variable
+fn is_running_cmd(pipeline_pid: &str) -> String {
+  let pipeline_pid = shell_quote(pipeline_pid);
+  format!(
+    r#"pid=`cat {pipeline_pid} 2>/dev/null`; [ -n "$pid" ] && kill -0 "$pid" 2>/dev/null"#
+  )
+}
+
+/// Streams log printing its lines from `start` before
+///
+/// With `stage` streams its own log instead of combined one.
+fn log(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  start: &LogStart,
+  stage: Option<&str>,
+) -> io::Result<()> {
+  let ssh = &mut ssh(env);
+  let log = match stage {
+    Some(stage) => shell_quote(&env.stage_log(stage)),
+    None => shell_quote(&env.out_log()),
+  };
+  let pid = shell_quote(&env.pipeline_pid());
+  match start {
+    LogStart::Tail(lines) => {
+      ssh.arg(format!("tail -n {lines} -f {log} --pid `cat {pid}`"))
+    }
+    LogStart::Since(since) => ssh
+      .arg(since_filter_cmd(&log, *since))
+      .arg(format!("tail -n 0 -f {log} --pid `cat {pid}`")),
+    LogStart::LastDeploy => {
+      ssh.arg(format!("tail -n +1 -f {log} --pid `cat {pid}`"))
+    }
+  };
+  stream_remote(runner, env, ssh)
+}
+
+/// Runs remote streaming `ssh` catching Ctrl-C
+fn stream_remote(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  ssh: &mut Cmd,
+) -> io::Result<()> {
+  let streamed =
+    interrupt::catching(|| runner.run_remote(ssh, env.retries));
+  interrupted_context(streamed, interrupt::is_interrupted())
+}
+
+/// Replaces failure of streaming `interrupted` by Ctrl-C
+/// with `Interrupted` error printing hint that pipeline still runs
+fn interrupted_context(
+  result: io::Result<()>,
+  interrupted: bool,
+) -> io::Result<()> {
+  match result {
+    Err(_) if interrupted => {
+      output::println(interrupt::STILL_RUNNING_HINT);
+      Err(HmdError::Interrupted.into())
+    }
+    result => result,
+  }
+}
+
+fn events(runner: &dyn CommandRunner, env: &Env) -> io::Result<()> {
+  let ssh = &mut ssh(env);
+  let events_log = shell_quote(&env.events_log());
+  let pid = shell_quote(&env.pipeline_pid());
+  ssh.arg(format!("tail -n 50 -f {events_log} --pid `cat {pid}`"));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Prints `log` lines starting from first `[%FT%T]` stage marker
+/// not older than `since`
+fn since_filter_cmd(log: &str, since: Duration) -> String {
+  let secs = since.as_secs();
+  let date = r"[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]";
+  let time = r"[0-9][0-9]:[0-9][0-9]:[0-9][0-9]";
+  format!(
+    r#"since=`date -d "-{secs} seconds" +%FT%T`;
+    awk -v since="$since" '
+      match($0, /\[{date}T{time}\]/) {{
+        if (substr($0, RSTART + 1, 19) >= since) show = 1
+      }}
+      show
+    ' {log};"#
+  )
+}
+
+fn exec(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  args: &[String],
+) -> io::Result<()> {
+  let work_tree = &shell_quote(&env.work_tree);
+  let ssh = &mut ssh(env);
+  ssh
+    .args(source_profile_cmd(&env.profile_file))
+    .arg(format!("cd {work_tree};"))
+    .arg(exec_cmd(args));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+fn exec_cmd(args: &[String]) -> String {
+  args
+    .iter()
+    .map(|arg| shell_quote(arg))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+fn clean(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  clean_cmd: Option<&str>,
+) -> io::Result<()> {
+  let work_tree = &shell_quote(&env.work_tree);
+  let ssh = &mut ssh(env);
+  ssh
+    .arg(kill_and_wait_cmd(&env.pipeline_pid()))
+    .arg(rm_logs_cmd(env));
+  if let Some(clean_cmd) = clean_cmd {
+    ssh
+      .args(source_profile_cmd(&env.profile_file))
+      .arg(format!("cd {work_tree};"))
+      .arg(format!("{clean_cmd};"));
+  }
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+fn rm_logs_cmd(env: &Env) -> String {
+  let files = [
+    env.out_log(),
+    env.events_log(),
+    env.exit_code(),
+    env.status_log(),
+    env.pipeline_sh(),
+    env.pipeline_sha256(),
+    env.pipeline_pid(),
+  ];
+  let files = files.map(|file| shell_quote(&file)).join(" ");
+  // Glob of rotated logs stays unquoted
+  let rotated = format!("{}.*", shell_quote(&env.out_log()));
+  let stage_logs = shell_quote(&env.stage_logs());
+  format!("rm -f {files} {rotated}; rm -rf {stage_logs};")
+}
+
+/// Prints projects of `target` under its `remote_root`
+///
+/// # Errors
+///
+/// Returns an error if `remote_root` is empty or ssh fails
+pub fn list(
+  runner: &dyn CommandRunner,
+  target: &Target,
+) -> io::Result<()> {
+  let projects_dir = shell_quote(&listed_dir(target)?);
+  let ssh = &mut target_ssh(target);
+  ssh.arg(format!("ls {projects_dir}"));
+  runner.run_remote(ssh, target.retries.unwrap_or_default())?;
+  Ok(())
+}
+
+/// Prints projects with their disk usage
+///
+/// Falls back to plain `list` if `du` isn't available at server.
+///
+/// # Errors
+///
+/// Returns an error if `remote_root` is empty or ssh fails
+pub fn list_sizes(
+  runner: &dyn CommandRunner,
+  target: &Target,
+) -> io::Result<()> {
+  let projects_dir = shell_quote(&listed_dir(target)?);
+  let ssh = &mut target_ssh(target);
+  ssh.arg(format!(
+    "command -v du > /dev/null || exit 0; cd {projects_dir} && du -sk -- */"
+  ));
+  let du = runner.output(ssh)?;
+  let sizes = parse_du(&du);
+  if sizes.is_empty() {
+    output::println("Disk usage isn't available");
+    return list(runner, target);
+  }
+  let width = sizes
+    .iter()
+    .map(|(size, _)| size.len())
+    .max()
+    .unwrap_or_default();
+  for (size, project) in sizes {
+    println!("{size:>width$}  {project}");
+  }
+  Ok(())
+}
+
+/// Projects dir of `target` checked to have non-empty root
+fn listed_dir(target: &Target) -> io::Result<String> {
+  if target.remote_root().trim().is_empty() {
+    return Err(other_err("`remote_root` can't be empty"));
+  }
+  Ok(target.projects_dir())
+}
+
+/// Parses `du -sk` lines to human readable sizes and names
+fn parse_du(du: &str) -> Vec<(String, String)> {
+  du.lines()
+    .filter_map(|line| {
+      let (kb, path) = line.split_once('\t')?;
+      let kb = kb.trim().parse::<u64>().ok()?;
+      let name = path.trim().trim_end_matches('/');
+      Some((human_size(kb), name.to_owned()))
+    })
+    .collect()
+}
+
+/// Formats size in KB with `K`, `M`, `G` or `T` suffix
+fn human_size(kb: u64) -> String {
+  let units = ["K", "M", "G", "T"];
+  let mut size = kb;
+  let mut unit = 0;
+  while size >= 1024 && unit < units.len() - 1 {
+    size /= 1024;
+    unit += 1;
+  }
+  format!("{size}{}", units[unit])
+}
+
+fn open(runner: &dyn CommandRunner, env: &Env) -> io::Result<()> {
+  let ssh = &mut ssh(env);
+  let work_tree = &shell_quote(&env.work_tree);
+  ssh.arg("-t").arg(format!("cd {work_tree}; bash --login"));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Asks user to confirm project removal at stdin
+///
+/// # Errors
+///
+/// Returns an error if stdin isn't a terminal
+fn confirm_remove(env: &Env) -> io::Result<bool> {
+  let stdin = io::stdin();
+  if !stdin.is_terminal() {
+    return Err(other_err(
+      "Can't confirm removal without terminal. Use `--yes` flag",
+    ));
+  }
+  let project = &env.project;
+  let ssh_address = &env.ssh_address;
+  confirm(
+    &mut stdin.lock(),
+    &mut io::stdout(),
+    &format!("Remove project '{project}' from {ssh_address}?"),
+  )
+}
+
+/// Asks to stop pipeline after interrupted `deploy --wait`
+///
+/// Without terminal pipeline is left running.
+fn confirm_stop(env: &Env) -> io::Result<bool> {
+  let stdin = io::stdin();
+  if !stdin.is_terminal() {
+    return Ok(false);
+  }
+  let project = &env.project;
+  let ssh_address = &env.ssh_address;
+  confirm(
+    &mut stdin.lock(),
+    &mut io::stdout(),
+    &format!("\nStop pipeline of '{project}' at {ssh_address}?"),
+  )
+}
+
+/// Writes `question` to `writer` and reads answer from `reader`
+///
+/// Only `y` and `yes` answers are accepted, case insensitive.
+fn confirm(
+  reader: &mut impl BufRead,
+  writer: &mut impl Write,
+  question: &str,
+) -> io::Result<bool> {
+  write!(writer, "{question} [y/N] ")?;
+  writer.flush()?;
+  let mut answer = String::new();
+  reader.read_line(&mut answer)?;
+  let answer = answer.trim().to_lowercase();
+  Ok(matches!(answer.as_str(), "y" | "yes"))
+}
+
+/// Removes project from server
+///
+/// With `keep_logs` downloads logs there first,
+/// failed download cancels removal.
+fn remove(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  yes: bool,
+  keep_logs: Option<&Path>,
+) -> io::Result<()> {
+  if !yes && !confirm_remove(env)? {
+    output::println("Canceled");
+    return Ok(());
+  }
+  if let Some(dir) = keep_logs {
+    prepare_logs_dir(dir)?;
+    download_logs(runner, env, dir).map_err(|err| {
+      other_err(format!(
+        "Can't keep logs, project isn't removed: {err}"
+      ))
+    })?;
+  }
+  let project_dir = &shell_quote(&env.project_dir);
+  let ssh = &mut ssh(env);
+  ssh
+    .arg(kill_and_wait_cmd(&env.pipeline_pid()))
+    .arg(systemd::uninstall_cmd(&env.project))
+    .arg(format!("rm -rf {project_dir}"));
+  runner.run_remote(ssh, env.retries)?;
+  Ok(())
+}
+
+/// Creates missing local `dir` of kept logs and checks
+/// it's writable, so scp doesn't fail on it
+fn prepare_logs_dir(dir: &Path) -> io::Result<()> {
+  let shown = dir.display();
+  if !dir.is_dir() {
+    fs::create_dir_all(dir).map_err(|err| {
+      other_err(format!("Can't create logs dir `{shown}`: {err}"))
+    })?;
+    output::println(format!("Created logs dir `{shown}`"));
+  }
+  let probe = dir.join(".hmd-write-check");
+  fs::write(&probe, "")
+    .and_then(|()| fs::remove_file(&probe))
+    .map_err(|err| {
+      other_err(format!("Logs dir `{shown}` isn't writable: {err}"))
+    })
+}
+
+/// Copies `out.log` and `status.log` of project to local `dir`
+fn download_logs(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  dir: &Path,
+) -> io::Result<()> {
+  let scp = &mut scp_cmd(env);
+  scp
+    .arg(env.remote_path(&env.out_log()))
+    .arg(env.remote_path(&env.status_log()))
+    .arg(dir);
+  runner.run_remote(scp, env.retries)
+}
+
+fn download(
+  runner: &dyn CommandRunner,
+  env: &Env,
+  what: LogFile,
+  dest: Option<&Path>,
+) -> io::Result<()> {
+  if let (LogFile::All, Some(dir)) = (what, dest) {
+    prepare_logs_dir(dir)?;
+  }
+  for (remote, local) in download_paths(env, what, dest) {
+    let scp = &mut scp_cmd(env);
+    scp.arg(env.remote_path(&remote)).arg(&local);
+    runner.run_remote(scp, env.retries)?;
+    output::println(format!("Saved {}", local.display()));
+  }
+  Ok(())
+}
+
+/// Remote logs of `what` paired with local paths to save them
+///
+/// Logs are named `<project>-<log>` unless single one has `dest`.
+fn download_paths(
+  env: &Env,
+  what: LogFile,
+  dest: Option<&Path>,
+) -> Vec<(String, PathBuf)> {
+  let logs = match what {
+    LogFile::Out => vec![(env.out_log(), Env::OUT_LOG)],
+    LogFile::Status => vec![(env.status_log(), Env::STATUS_LOG)],
+    LogFile::All => vec![
+      (env.out_log(), Env::OUT_LOG),
+      (env.status_log(), Env::STATUS_LOG),
+    ],
+  };
+  let project = &env.project;
+  let dir = match (what, dest) {
+    (LogFile::All, Some(dir)) => dir,
+    (_, Some(file)) => {
+      return logs
+        .into_iter()
+        .map(|(remote, _)| (remote, file.to_path_buf()))
+        .collect();
+    }
+    (_, None) => Path::new("."),
+  };
+  logs
+    .into_iter()
+    .map(|(remote, log)| {
+      (remote, dir.join(format!("{project}-{log}")))
+    })
+    .collect()
+}
+
+fn other_err<E>(err: E) -> io::Error
+where
+  E: Into<Box<dyn Error + Send + Sync>>,
+{
+  io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs,
+    process::{ExitStatus, Output},
+  };
+
+  use super::*;
+  use crate::test_dir::TestDir;
+
+  const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+  fn answer(input: &str) -> (bool, String) {
+    let mut written = Vec::new();
+    let confirmed =
+      confirm(&mut input.as_bytes(), &mut written, "Remove?")
+        .unwrap();
+    (confirmed, String::from_utf8(written).unwrap())
+  }
+
+  #[test]
+  fn confirm_accepts_yes_answers() {
+    assert_eq!(answer("y\n"), (true, "Remove? [y/N] ".to_owned()));
+    assert!(answer("yes\n").0);
+    assert!(answer(" YES \n").0);
+  }
+
+  #[test]
+  fn confirm_declines_other_answers() {
+    assert!(!answer("\n").0);
+    assert!(!answer("n\n").0);
+    assert!(!answer("yep\n").0);
+    // EOF of closed stdin
+    assert!(!answer("").0);
+  }
+
+  fn env() -> Env {
+    Env::new("app", &Target::new("user@host".to_owned()))
+  }
+
+  fn deploy_runner() -> RecordingRunner {
+    RecordingRunner::new()
+      .with_output("rev-parse", SHA)
+      .with_output("branch --show-current", "main")
+  }
+
+  fn deploy_options() -> DeployOptions<'static> {
+    DeployOptions {
+      parallel: 1,
+      ..DeployOptions::default()
+    }
+  }
+
+  fn strings(argv: &[&str]) -> Vec<String> {
+    argv.iter().map(|&arg| arg.to_owned()).collect()
+  }
+
+  fn deploy_meta(label: &str) -> DeployMeta {
+    DeployMeta {
+      git_ref: Some(SHA.to_owned()),
+      label: label.to_owned(),
+      user: "alice".to_owned(),
+      ts: "2024-01-31T12:00:00".to_owned(),
+    }
+  }
+
+  #[test]
+  fn deploy_meta_is_appended_as_json_line() {
+    assert_eq!(
+      record_deploy_cmd(&env(), &deploy_meta("v1's")).unwrap(),
+      format!(
+        "echo '{{\"ref\":\"{SHA}\",\"label\":\"v1'\\''s\",\
+        \"user\":\"alice\",\"ts\":\"2024-01-31T12:00:00\"}}' \
+        >> ~/'.hmd/app/work-tree/deploy.meta';"
+      )
+    );
+    let runner = RecordingRunner::new()
+      .with_output("date", "2024-01-31T12:00:00\n");
+    record_deploy(&runner, &env(), Some(SHA), None).unwrap();
+    let label = format!("\"label\":\"{}\"", short_sha(SHA));
+    assert!(runner.command_lines()[1].contains(&label));
+  }
+
+  #[test]
+  fn history_reads_deploy_meta_lines() {
+    let meta = serde_json::to_string(&deploy_meta("v1")).unwrap();
+    let output = format!(
+      "{meta}\n{{\"label\":\"-\",\"user\":\"bob\",\
+      \"ts\":\"2024-02-01T09:30:00\"}}\n{{\"label\":\"cut"
+    );
+    let runner =
+      RecordingRunner::new().with_output("deploy.meta", &output);
+    let history = history(&runner, &env()).unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0], deploy_meta("v1"));
+    assert_eq!(
+      history[0].to_string(),
+      format!("2024-01-31T12:00:00 v1 {} by alice", short_sha(SHA))
+    );
+    assert_eq!(history[1].git_ref, None);
+    assert_eq!(
+      history[1].to_string(),
+      "2024-02-01T09:30:00 - by bob"
+    );
+  }
+
+  #[test]
+  fn deploy_aborts_when_local_verify_fails() {
+    let runner = &deploy_runner().with_failure("cargo test", 101);
+    let mut hmd_yml = HmdYml::default();
+    hmd_yml.artifacts.clear();
+    hmd_yml.settings.verify_local = Some("cargo test".to_owned());
+    let err = deploy(runner, &env(), &hmd_yml, &deploy_options())
+      .unwrap_err();
+    assert!(err.to_string().contains("`verify_local` failed"));
+    assert_eq!(runner.command_lines(), ["sh -c cargo test"]);
+  }
+
+  #[test]
+  fn deploy_pushes_uploads_and_runs_pipeline() {
+    let runner = &deploy_runner();
+    let hmd_yml = HmdYml {
+      artifacts: Vec::new(),
+      ..HmdYml::default()
+    };
+    deploy(runner, &env(), &hmd_yml, &deploy_options()).unwrap();
+    let commands = runner.commands();
+    assert_eq!(commands.len(), 11);
+    assert_eq!(
+      commands[0],
+      strings(&[
+        "git",
+        "rev-parse",
+        "--verify",
+        "--quiet",
+        "HEAD^{commit}"
+      ])
+    );
+    assert_eq!(
+      commands[1],
+      strings(&["git", "branch", "--show-current"])
+    );
+    assert_eq!(
+      commands[2],
+      strings(&[
+        "ssh",
+        "user@host",
+        "test -d ~/'.hmd/app/git' || exit 66;",
+        &lock_cmd("~/.hmd/app/deploy.lock", false),
+      ])
+    );
+    assert_eq!(
+      commands[3],
+      strings(&[
+        "git",
+        "push",
+        "--force",
+        "user@host:~/.hmd/app/git",
+        &format!("{SHA}:refs/heads/main"),
+      ])
+    );
+    let scp = &commands[5];
+    assert_eq!(scp[..2], strings(&["scp", "-r"]));
+    assert!(scp[scp.len() - 2].ends_with("/pipeline.sh"));
+    assert_eq!(scp[scp.len() - 1], "user@host:~/.hmd/app/upload");
+    let run = commands[7].join(" ");
+    let script = build_pipeline_script(&env(), &hmd_yml, &[]);
+    let hash = script_hash(&script);
+    assert!(run.contains(&record_script_hash_cmd(&hash)));
+    assert!(run.contains(&verify_script_cmd()));
+    assert!(run.contains(&format!("checkout --force '{SHA}';")));
+    assert!(run.contains("nohup bash pipeline.sh > out.log"));
+    assert_eq!(commands[8], strings(&["date", "+%FT%T"]));
+    let meta = commands[9].join(" ");
+    assert!(meta.contains(&format!("\"ref\":\"{SHA}\"")));
+    assert!(meta.ends_with(">> ~/'.hmd/app/work-tree/deploy.meta';"));
+    assert_eq!(
+      commands[10],
+      strings(&[
+        "ssh",
+        "user@host",
+        "rm -rf ~/'.hmd/app/deploy.lock';"
+      ])
+    );
+  }
+
+  #[test]
+  fn checkout_gets_commit_with_attached_or_detached_head() {
+    let deployed = |runner: &RecordingRunner| {
+      deploy(runner, &env(), &HmdYml::default(), &deploy_options())
+        .unwrap();
+      runner.command_lines()
+    };
+    let attached = deployed(&deploy_runner());
+    let detached =
+      deployed(&RecordingRunner::new().with_output("rev-parse", SHA));
+    for (lines, branch) in
+      [(attached, "main"), (detached, "hmd-deploy")]
+    {
+      let push = format!("{SHA}:refs/heads/{branch}");
+      assert!(lines.iter().any(|line| line.ends_with(&push)));
+      let checkout = format!("checkout --force '{SHA}';");
+      assert!(lines.iter().any(|line| line.contains(&checkout)));
+    }
+  }
+
+  #[test]
+  fn ref_deploy_pushes_and_checks_out_its_commit() {
+    let runner = &deploy_runner();
+    let options = DeployOptions {
+      git_ref: Some("v1.0"),
+      ..deploy_options()
+    };
+    deploy(runner, &env(), &HmdYml::default(), &options).unwrap();
+    let lines = runner.command_lines();
+    assert_eq!(
+      lines[0],
+      "git rev-parse --verify --quiet v1.0^{commit}"
+    );
+    assert!(lines.contains(&format!(
+      "git push --force user@host:~/.hmd/app/git \
+      {SHA}:refs/heads/hmd-deploy"
+    )));
+    let checkout = format!("checkout --force '{SHA}';");
+    assert!(lines.iter().any(|line| line.contains(&checkout)));
+    let runner = &RecordingRunner::new().with_failure("rev-parse", 1);
+    let err = deploy(runner, &env(), &HmdYml::default(), &options)
+      .unwrap_err();
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::RefNotFound(git_ref)) if git_ref == "v1.0"
+    ));
+    assert_eq!(runner.commands().len(), 1);
+  }
+
+  #[test]
+  fn remote_branch_differs_from_local_one() {
+    let runner = &deploy_runner();
+    let options = DeployOptions {
+      remote_branch: Some("prod"),
+      ..deploy_options()
+    };
+    deploy(runner, &env(), &HmdYml::default(), &options).unwrap();
+    let lines = runner.command_lines();
+    assert!(lines.contains(&format!(
+      "git push --force user@host:~/.hmd/app/git {SHA}:refs/heads/prod"
+    )));
+    let checkout = format!("checkout --force '{SHA}';");
+    assert!(lines.iter().any(|line| line.contains(&checkout)));
+    assert!(!lines.iter().any(|line| line.contains("heads/main")));
+  }
+
+  #[test]
+  fn no_checkout_deploy_runs_pipeline_in_work_tree_as_is() {
+    let runner = &deploy_runner();
+    let options = DeployOptions {
+      no_checkout: true,
+      ..deploy_options()
+    };
+    deploy(runner, &env(), &HmdYml::default(), &options).unwrap();
+    let lines = runner.command_lines();
+    let run =
+      lines.iter().find(|line| line.contains("nohup")).unwrap();
+    assert!(run.contains(&check_work_tree_cmd()));
+    assert!(!run.contains("checkout --force"));
+    let dir = TestDir::new("no-checkout");
+    let check = || {
+      Cmd::new("sh")
+        .args(["-c", &check_work_tree_cmd()])
+        .current_dir(&dir)
+        .output()
+        .unwrap()
+    };
+    dir.file(Env::PIPELINE_SH);
+    dir.file("out.log.20240101T000000");
+    let empty = check();
+    dir.file("main.rs");
+    assert!(!empty.status.success());
+    assert!(String::from_utf8_lossy(&empty.stderr)
+      .contains("Work tree is empty"));
+    assert!(check().status.success());
+  }
+
+  #[test]
+  fn work_tree_of_stage_and_rotated_logs_is_empty() {
+    let dir = TestDir::new("stage-logs-only");
+    dir.file(&Env::stage_log_file("build"));
+    dir.file("out.log.20240101T000000");
+    let output = Cmd::new("sh")
+      .args(["-c", &check_work_tree_cmd()])
+      .current_dir(&dir)
+      .output()
+      .unwrap();
+    assert!(!output.status.success());
+  }
+
+  #[test]
+  fn only_upload_deploy_launches_no_pipeline() {
+    let runner = &deploy_runner();
+    let hmd_yml = HmdYml {
+      artifacts: vec!["Cargo.toml".to_owned()],
+      ..HmdYml::default()
+    };
+    let options = DeployOptions {
+      only_upload: true,
+      ..deploy_options()
+    };
+    deploy(runner, &env(), &hmd_yml, &options).unwrap();
+    let lines = runner.command_lines();
+    assert!(lines.iter().any(|line| line.starts_with("git push")));
+    assert!(lines.contains(
+      &"scp -r Cargo.toml user@host:~/.hmd/app/upload".to_owned()
+    ));
+    assert!(!lines.iter().any(|line| line.contains("pipeline.sh")));
+    assert!(!lines.iter().any(|line| line.contains("nohup")));
+    assert!(!lines.iter().any(|line| line.contains("deploy.meta")));
+  }
+
+  #[test]
+  fn waited_deploy_fails_with_exit_code_of_pipeline() {
+    let runner = &deploy_runner().with_failure("wait $!", 3);
+    let options = DeployOptions {
+      wait: true,
+      ..deploy_options()
+    };
+    let err = deploy(runner, &env(), &HmdYml::default(), &options)
+      .unwrap_err();
+    assert_eq!(HmdError::downcast(&err).unwrap().exit_code(), 3);
+    let lines = runner.command_lines();
+    let waits = lines.iter().filter(|line| line.contains("wait $!"));
+    assert_eq!(waits.count(), 1);
+    assert!(!lines.iter().any(|line| line.contains("deploy.meta")));
+  }
+
+  #[test]
+  fn run_pipeline_uses_remote_shell_and_profile_file() {
+    let runner = RecordingRunner::new();
+    let mut env = env();
+    env.remote_shell = "sh".to_owned();
+    env.profile_file = ".bashrc".to_owned();
+    restart_pipeline(&runner, &env, Some(60), false, false).unwrap();
+    let ssh = &runner.commands()[0];
+    assert!(ssh.contains(&"source '.bashrc';".to_owned()));
+    let restart = ssh.last().unwrap();
+    assert!(restart.contains("nohup timeout 60 sh -c"));
+    assert!(restart.contains("exec sh pipeline.sh"));
+    env.profile_file.clear();
+    let runner = RecordingRunner::new();
+    run_pipeline(
+      &runner,
+      &env,
+      &deploy_options(),
+      None,
+      Supervisor::Nohup,
+      "hash",
+    )
+    .unwrap();
+    let ssh = &runner.commands()[0];
+    assert!(!ssh.iter().any(|arg| arg.starts_with("source")));
+    assert!(ssh
+      .last()
+      .unwrap()
+      .contains("nohup sh pipeline.sh > out.log"));
+  }
+
+  #[test]
+  fn verify_script_cmd_compares_recorded_hash_of_script() {
+    let dir = TestDir::new("verify-script");
+    let pipeline_sh = dir.join(Env::PIPELINE_SH);
+    let sh = |cmd: &str| {
+      Cmd::new("sh")
+        .args(["-c", cmd])
+        .current_dir(&dir)
+        .output()
+        .unwrap()
+    };
+    let missing = sh(&verify_script_cmd());
+    fs::write(&pipeline_sh, "echo fresh\n").unwrap();
+    let record = record_script_hash_cmd(&script_hash("echo fresh\n"));
+    assert!(sh(&record).status.success());
+    let fresh = sh(&verify_script_cmd());
+    fs::write(&pipeline_sh, "echo stale\n").unwrap();
+    let stale = sh(&verify_script_cmd());
+    assert!(!missing.status.success());
+    assert!(fresh.status.success());
+    assert!(!stale.status.success());
+    assert!(String::from_utf8_lossy(&stale.stderr)
+      .contains("differs from deployed one"));
+  }
+
+  #[test]
+  fn restart_verifies_script_only_if_asked() {
+    let runner = RecordingRunner::new();
+    restart_pipeline(&runner, &env(), None, false, false).unwrap();
+    restart_pipeline(&runner, &env(), None, false, true).unwrap();
+    let commands = runner.commands();
+    assert_eq!(commands.len(), 2);
+    assert!(!commands[0].contains(&verify_script_cmd()));
+    assert!(commands[1].contains(&verify_script_cmd()));
+  }
+
+  #[test]
+  fn exec_runs_quoted_args_in_work_tree() {
+    let runner = RecordingRunner::new();
+    let args = strings(&["tail", "-n100", "out.log; rm -rf ~"]);
+    exec(&runner, &env(), &args).unwrap();
+    assert_eq!(
+      runner.commands(),
+      [strings(&[
+        "ssh",
+        "user@host",
+        "source '.profile';",
+        "cd ~/'.hmd/app/work-tree';",
+        "'tail' '-n100' 'out.log; rm -rf ~'",
+      ])]
+    );
+  }
+
+  #[test]
+  fn restart_if_running_skips_stopped_pipeline() {
+    let restart = restart_cmd("bash", None, None, false);
+    let guarded = restart_cmd("bash", None, None, true);
+    assert_eq!(
+      guarded,
+      format!(
+        "if {}; then {restart} else echo \"nothing to restart\"; fi;",
+        is_running_cmd(Env::PIPELINE_PID)
+      )
+    );
+    let dir = TestDir::new("restart-if-running");
+    fs::write(dir.join(Env::PIPELINE_SH), "true\n").unwrap();
+    let output = Cmd::new("bash")
+      .args(["-c", &guarded])
+      .current_dir(&dir)
+      .output()
+      .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+      String::from_utf8_lossy(&output.stdout),
+      "nothing to restart\n"
+    );
+    assert!(!dir.join(Env::PIPELINE_PID).exists());
+  }
+
+  #[test]
+  fn rotated_logs_are_named_by_time_and_pruned_to_newest() {
+    let dir = TestDir::new("rotate-logs");
+    let sh = |cmd: &str| {
+      let output = Cmd::new("sh")
+        .args(["-c", cmd])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+      assert!(output.status.success(), "{output:?}");
+    };
+    let logs = || {
+      let mut logs = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| {
+          entry.unwrap().file_name().into_string().unwrap()
+        })
+        .collect::<Vec<_>>();
+      logs.sort();
+      logs
+    };
+    dir.file(Env::OUT_LOG);
+    sh(&rotate_log_cmd(Env::OUT_LOG));
+    let rotated = logs();
+    assert_eq!(rotated.len(), 1);
+    let stamp = rotated[0].strip_prefix("out.log.").unwrap();
+    assert_eq!(stamp.len(), "20240131T120000".len());
+    assert_eq!(stamp.as_bytes()[8], b'T');
+    fs::remove_file(dir.join(&rotated[0])).unwrap();
+    for stamp in
+      ["20240101T000000", "20240301T000000", "20240201T000000"]
+    {
+      dir.file(&format!("out.log.{stamp}"));
+    }
+    sh(&prune_logs_cmd(Env::OUT_LOG, 2));
+    assert_eq!(
+      logs(),
+      ["out.log.20240201T000000", "out.log.20240301T000000"]
+    );
+  }
+
+  #[test]
+  fn last_deploy_log_starts_from_deploy_delimiter() {
+    let script =
+      build_pipeline_script(&env(), &HmdYml::default(), &[]);
+    let trace = script.find("\ntrace_on;").unwrap();
+    assert!(script[..trace]
+      .ends_with("echo \"===== deploy `date +%FT%T` =====\";"));
+    let runner = RecordingRunner::new();
+    log(&runner, &env(), &LogStart::LastDeploy, None).unwrap();
+    let dir = "~/'.hmd/app/work-tree";
+    assert_eq!(
+      runner.command_lines(),
+      [format!(
+        "ssh user@host tail -n +1 -f {dir}/out.log' \
+        --pid `cat {dir}/pipeline.pid'`"
+      )]
+    );
+  }
+
+  #[test]
+  fn exit_code_recorded_by_pipeline_is_read_by_watch_exit() {
+    let runner = RecordingRunner::new();
+    status(&runner, &env(), true, None, Supervisor::Nohup).unwrap();
+    let ssh = &runner.commands()[0];
+    assert_eq!(
+      ssh.last().unwrap(),
+      &exit_code_cmd("~/.hmd/app/work-tree/exit.code")
+    );
+    let dir = TestDir::new("exit-code");
+    let bash = |cmd: &str| {
+      Cmd::new("bash")
+        .args(["-c", cmd])
+        .current_dir(&dir)
+        .status()
+        .unwrap()
+        .code()
+    };
+    let read_exit_code = exit_code_cmd(Env::EXIT_CODE);
+    assert_eq!(bash(&read_exit_code), Some(1));
+    fs::write(
+      dir.join(Env::PIPELINE_SH),
+      format!("{SCRIPT}\nexit 3\n"),
+    )
+    .unwrap();
+    assert_eq!(bash("bash pipeline.sh > out.log"), Some(3));
+    assert_eq!(
+      fs::read_to_string(dir.join(Env::EXIT_CODE)).unwrap(),
+      "3\n"
+    );
+    assert_eq!(bash(&read_exit_code), Some(3));
+  }
+
+  #[test]
+  fn rm_logs_cmd_keeps_git_repo_and_sources() {
+    let dir = "~/'.hmd/app/work-tree";
+    assert_eq!(
+      rm_logs_cmd(&env()),
+      format!(
+        "rm -f {dir}/out.log' {dir}/events.log' {dir}/exit.code' \
+        {dir}/status.log' {dir}/pipeline.sh' {dir}/pipeline.sha256' \
+        {dir}/pipeline.pid' \
+        {dir}/out.log'.*; rm -rf {dir}/logs';"
+      )
+    );
+  }
+
+  /// Runs `nohup_pipeline_cmd` with timeout in temp dir
+  /// with `pipeline.sh` of `script`
+  fn start_pipeline(name: &str, script: &str) -> Output {
+    let dir = TestDir::new(name);
+    fs::write(dir.join(Env::PIPELINE_SH), script).unwrap();
+    let cmd = nohup_pipeline_cmd("bash", None, Some(60));
+    Cmd::new("sh")
+      .args(["-c", &cmd])
+      .current_dir(&dir)
+      .output()
+      .unwrap()
+  }
+
+  #[test]
+  fn pipeline_with_timeout_saves_pid() {
+    let output = start_pipeline("started", "sleep 1");
+    assert!(output.status.success());
+  }
+
+  /// Args of `cmd` without program
+  fn args(cmd: &Cmd) -> Vec<String> {
+    cmd
+      .get_args()
+      .map(|arg| arg.to_string_lossy().into_owned())
+      .collect()
+  }
+
+  #[test]
+  fn user_of_alias_is_passed_to_ssh_scp_and_git() {
+    let mut target = Target::new("box".to_owned());
+    target.user = Some("root".to_owned());
+    let env = Env::new("app", &target);
+    assert_eq!(args(&ssh(&env)), ["-l", "root", "box"]);
+    assert_eq!(args(&scp_cmd(&env)), ["-o", "User=root"]);
+    let git = git_push_cmd(&env, "HEAD", true);
+    let ssh_command = git
+      .get_envs()
+      .find(|(key, _)| *key == "GIT_SSH_COMMAND")
+      .and_then(|(_, value)| value);
+    assert_eq!(ssh_command, Some("ssh -o 'User=root'".as_ref()));
+  }
+
+  #[test]
+  fn compress_is_passed_to_ssh_and_scp() {
+    let mut target = Target::new("box".to_owned());
+    assert_eq!(args(&ssh(&Env::new("app", &target))), ["box"]);
+    assert!(args(&scp_cmd(&Env::new("app", &target))).is_empty());
+    target.compress = Some(true);
+    let env = Env::new("app", &target);
+    assert_eq!(args(&ssh(&env)), ["-o", "Compression=yes", "box"]);
+    assert_eq!(args(&scp_cmd(&env)), ["-C"]);
+    assert_eq!(args(&target_ssh(&target)), args(&ssh(&env)));
+  }
+
+  #[test]
+  fn jump_host_is_passed_to_ssh_and_scp() {
+    let mut target = Target::new("box".to_owned());
+    target.jump_host = Some("admin@bastion:2222".to_owned());
+    let env = Env::new("app", &target);
+    assert_eq!(args(&ssh(&env)), ["-J", "admin@bastion:2222", "box"]);
+    assert_eq!(
+      args(&scp_cmd(&env)),
+      ["-o", "ProxyJump=admin@bastion:2222"]
+    );
+    assert_eq!(args(&target_ssh(&target)), args(&ssh(&env)));
+  }
+
+  #[test]
+  fn init_check_probes_connection_and_write_access() {
+    let runner = RecordingRunner::new();
+    check_init(&runner, &env()).unwrap();
+    let lines = runner.command_lines();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "ssh user@host true");
+    assert_eq!(
+      lines[1],
+      format!("ssh user@host {}", write_probe_cmd("~/.hmd/app"))
+    );
+    let failed = RecordingRunner::new().with_failure(" true", 255);
+    assert!(check_init(&failed, &env()).is_err());
+    assert_eq!(failed.commands().len(), 1);
+  }
+
+  #[test]
+  fn write_probe_falls_back_to_existing_parent() {
+    let root = TestDir::new("write-probe");
+    let missing = root.join("hmd/app");
+    let probed = Cmd::new("bash")
+      .args(["-c", &write_probe_cmd(&missing.to_string_lossy())])
+      .status()
+      .unwrap();
+    let untouched = fs::read_dir(&root).unwrap().count() == 0;
+    assert!(probed.success());
+    assert!(untouched);
+  }
+
+  #[test]
+  fn git_push_retries_connection_failure() {
+    let runner =
+      &RecordingRunner::new().with_failure("git push", 128);
+    let mut env = env();
+    env.retries = 1;
+    assert!(git_push(runner, &env, "HEAD", true).is_err());
+    assert_eq!(runner.commands().len(), 2);
+  }
+
+  #[test]
+  fn no_force_push_omits_force_and_explains_rejection() {
+    let runner = &deploy_runner().with_failure("git push", 1);
+    let mut hmd_yml = HmdYml::default();
+    hmd_yml.artifacts.clear();
+    let options = DeployOptions {
+      no_force_push: true,
+      ..deploy_options()
+    };
+    let err = deploy(runner, &env(), &hmd_yml, &options).unwrap_err();
+    assert!(err.to_string().contains("rejected"), "{err}");
+    let push = runner
+      .commands()
+      .into_iter()
+      .find(|argv| argv[..2] == ["git", "push"])
+      .unwrap();
+    assert_eq!(
+      push,
+      strings(&[
+        "git",
+        "push",
+        "user@host:~/.hmd/app/git",
+        &format!("{SHA}:refs/heads/main"),
+      ])
+    );
+  }
+
+  const TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+  #[test]
+  fn dirty_deploy_pushes_temp_commit_to_deploy_branch() {
+    let runner = &RecordingRunner::new()
+      .with_output("write-tree", TREE)
+      .with_output("commit-tree", SHA);
+    let hmd_yml = HmdYml {
+      artifacts: Vec::new(),
+      ..HmdYml::default()
+    };
+    let options = DeployOptions {
+      dirty: true,
+      only_upload: true,
+      ..deploy_options()
+    };
+    deploy(runner, &env(), &hmd_yml, &options).unwrap();
+    let lines = runner.command_lines();
+    assert_eq!(
+      lines[..3],
+      [
+        "git add -A".to_owned(),
+        "git write-tree".to_owned(),
+        format!("git commit-tree {TREE} -p HEAD -m hmd-temp: dirty"),
+      ]
+    );
+    assert!(lines.contains(&format!(
+      "git push --force user@host:~/.hmd/app/git {SHA}:refs/heads/{DEPLOY_BRANCH}"
+    )));
+    // HEAD, branch and index are left as they are
+    let touching = [
+      "reset",
+      "checkout",
+      "update-ref",
+      "stash",
+      "commit ",
+      "branch",
+    ];
+    let local_git = lines.iter().filter(|line| {
+      line.starts_with("git ") && !line.starts_with("git push")
+    });
+    for line in local_git {
+      assert!(
+        !touching.iter().any(|arg| line.contains(arg)),
+        "{line}"
+      );
+    }
+  }
+
+  /// Runs commands in `dir` with git identity of tests
+  struct InDir<'a>(&'a Path);
+
+  impl InDir<'_> {
+    fn prepare<'c>(&self, cmd: &'c mut Cmd) -> &'c mut Cmd {
+      cmd
+        .current_dir(self.0)
+        .env("GIT_AUTHOR_NAME", "hmd")
+        .env("GIT_AUTHOR_EMAIL", "hmd@localhost")
+        .env("GIT_COMMITTER_NAME", "hmd")
+        .env("GIT_COMMITTER_EMAIL", "hmd@localhost")
+    }
+  }
+
+  impl CommandRunner for InDir<'_> {
+    fn status(&self, cmd: &mut Cmd) -> io::Result<ExitStatus> {
+      self.prepare(cmd).stdout(Stdio::null()).status()
+    }
+
+    fn output(&self, cmd: &mut Cmd) -> io::Result<String> {
+      let output = self.prepare(cmd).output()?;
+      assert!(output.status.success(), "{cmd:?}");
+      Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+  }
+
+  #[test]
+  fn temp_commit_has_untracked_files_and_keeps_head() {
+    let dir = TestDir::new("temp-commit");
+    let git = InDir(&dir);
+    let run = |args: &[&str]| git_output(&git, args).unwrap();
+    run(&["init", "-q", "-b", "main"]);
+    dir.file("tracked.txt");
+    run(&["add", "tracked.txt"]);
+    run(&["commit", "-q", "-m", "init"]);
+    let head = run(&["rev-parse", "HEAD"]);
+    fs::write(dir.join("tracked.txt"), "changed").unwrap();
+    dir.file("new.txt");
+    let status = run(&["status", "--porcelain"]);
+    let commit = git_temp_commit(&git, &env(), None).unwrap();
+    assert_eq!(
+      run(&["ls-tree", "--name-only", &commit]),
+      "new.txt\ntracked.txt"
+    );
+    assert_eq!(run(&["rev-parse", &format!("{commit}^")]), head);
+    assert_eq!(run(&["rev-parse", "HEAD"]), head);
+    assert_eq!(run(&["branch", "--show-current"]), "main");
+    assert_eq!(run(&["status", "--porcelain"]), status);
+  }
+
+  #[test]
+  fn deploy_of_missing_artifact_fails_before_ssh() {
+    let runner = &RecordingRunner::new();
+    let hmd_yml = HmdYml {
+      artifacts: vec!["no-such-artifact".to_owned()],
+      ..HmdYml::default()
+    };
+    let err =
+      deploy(runner, &env(), &hmd_yml, &DeployOptions::default())
+        .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Artifact `no-such-artifact` not found"
+    );
+    assert!(runner.commands().is_empty());
+  }
+
+  #[test]
+  fn stop_touches_systemd_only_under_it() {
+    let runner = &RecordingRunner::new();
+    stop(runner, &env(), Supervisor::Nohup).unwrap();
+    stop(runner, &env(), Supervisor::Systemd).unwrap();
+    let lines = runner.command_lines();
+    assert!(!lines[0].contains("systemctl"));
+    assert!(
+      lines[1].contains("systemctl --user stop hmd-app.service")
+    );
+  }
+
+  #[test]
+  fn retry_returns_successful_attempt() {
+    let mut calls = 0;
+    let attempt = retry(5, Duration::ZERO, || {
+      calls += 1;
+      if calls < 3 {
+        Err(other_err("down"))
+      } else {
+        Ok(())
+      }
+    });
+    assert_eq!(attempt.unwrap(), 3);
+    assert_eq!(calls, 3);
+  }
+
+  #[test]
+  fn retry_fails_with_last_error_after_all_attempts() {
+    let mut calls = 0;
+    let err = retry(2, Duration::ZERO, || {
+      calls += 1;
+      Err(other_err(format!("down {calls}")))
+    })
+    .unwrap_err();
+    assert_eq!(err.to_string(), "down 3");
+    assert_eq!(calls, 3);
+  }
+
+  #[test]
+  fn concurrent_pipeline_scripts_do_not_collide() {
+    let env = &env();
+    let paths = thread::scope(|scope| {
+      let generated = (0..8)
+        .map(|i| {
+          scope.spawn(move || {
+            let path = create_temp_dir(env).unwrap().join("script");
+            let script = format!("echo {i}");
+            fs::write(&path, &script).unwrap();
+            let written = fs::read_to_string(&path).unwrap();
+            (path, written == script)
+          })
+        })
+        .collect::<Vec<_>>();
+      generated
+        .into_iter()
+        .map(|generated| generated.join().unwrap())
+        .collect::<Vec<_>>()
+    });
+    assert!(paths.iter().all(|(_, kept)| *kept));
+    let mut dirs =
+      paths.iter().map(|(path, _)| path).collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    assert_eq!(dirs.len(), 8);
+    for (path, _) in &paths {
+      remove_pipeline_sh(path).unwrap();
+      assert!(!path.exists());
+    }
+  }
+
+  #[test]
+  fn failed_stages_are_retried_by_configured_count() {
+    let mut hmd_yml = HmdYml::default();
+    hmd_yml.settings.stage_retries = Some(2);
+    hmd_yml.stages.clear();
+    hmd_yml
+      .stages
+      .insert("test".to_owned(), Stage::Cmd("cargo test".to_owned()));
+    hmd_yml.stages.insert(
+      "run".to_owned(),
+      Stage::Options {
+        cmd: "./app".to_owned(),
+        dir: None,
+        shell: None,
+        retries: Some(0),
+      },
+    );
+    let script = build_pipeline_script(&env(), &hmd_yml, &[]);
+    assert!(script.contains(&retry_cmd(0, "test", "cargo test", 2)));
+    assert!(script.contains("for attempt in {0..2}; do"));
+    assert!(script.contains("Retry test $attempt/2"));
+    assert!(script.contains("(( attempt < 2 ));"));
+    assert!(!script.contains("Retry run"));
+    let syntax =
+      Cmd::new("bash").args(["-n", "-c", &script]).output();
+    assert!(syntax.unwrap().status.success());
+  }
+
+  #[test]
+  fn failed_stage_with_dir_records_status_in_work_tree() {
+    let dir = TestDir::new("stage-dir");
+    fs::create_dir(dir.join("sub")).unwrap();
+    let hmd_yml = HmdYml {
+      stages: IndexMap::from([(
+        "build".to_owned(),
+        Stage::Options {
+          cmd: "false".to_owned(),
+          dir: Some("sub".to_owned()),
+          shell: None,
+          retries: None,
+        },
+      )]),
+      ..HmdYml::default()
+    };
+    let script = build_pipeline_script(&env(), &hmd_yml, &[]);
+    let output = Cmd::new("bash")
+      .args(["-c", &script])
+      .current_dir(&dir)
+      .output()
+      .unwrap();
+    assert!(!output.status.success());
+    let exit_code = fs::read_to_string(dir.join(Env::EXIT_CODE));
+    assert_eq!(exit_code.unwrap(), "1\n");
+    assert!(dir.join(Env::STATUS_LOG).is_file());
+    assert!(!dir.join("sub").join(Env::EXIT_CODE).exists());
+    assert!(!dir.join("sub").join(Env::STATUS_LOG).exists());
+  }
+
+  #[test]
+  fn env_vars_are_exported_before_tracing() {
+    let env_vars = [("TOKEN".to_owned(), "s3cr3t".to_owned())];
+    let script =
+      build_pipeline_script(&env(), &HmdYml::default(), &env_vars);
+    let export = script.find("export TOKEN='s3cr3t';").unwrap();
+    assert!(export < script.rfind("trace_on;").unwrap());
+    assert!(export > script.find("stages=(").unwrap());
+  }
+
+  #[test]
+  fn pipeline_script_runs_stages_in_order() {
+    let hmd_yml = HmdYml {
+      stages: IndexMap::from([
+        ("build".to_owned(), Stage::Cmd("make".to_owned())),
+        ("run".to_owned(), Stage::Cmd("./app".to_owned())),
+      ]),
+      ..HmdYml::default()
+    };
+    let script = build_pipeline_script(&env(), &hmd_yml, &[]);
+    let stage = |i: usize, name: &str, cmd: &str| {
+      format!(
+        r#"
+          echo -e "\n🟩 [`date +%FT%T`] > Start {name}\n{cmd}\n";
+          run {i} && {{ {cmd}; }} > >(tee 'logs/{name}.log') 2>&1 \
+            && complete {i} || {{
+            echo -e "\n❌ [`date +%FT%T`] > Failed {name}\n";
+            {notify}
+            panic {i};
+            exit 1;
+          }};
+          echo -e "\n🟩 [`date +%FT%T`] > End {name}\n";
+        "#,
+        notify = "",
+      )
+    };
+    let expected = format!(
+      "{SCRIPT}\n\ntrace=0;\nstages=(build run);\n\n\
+      echo \"===== deploy `date +%FT%T` =====\";\ntrace_on;\n\n\
+      {}\n\n{}\n\n",
+      stage(0, "build", "make"),
+      stage(1, "run", "./app"),
+    );
+    assert_eq!(script, expected);
+  }
+
+  #[test]
+  fn pipeline_events_are_json_lines() {
+    let dir = TestDir::new("events");
+    let script = format!(
+      "{SCRIPT}\nstages=(build 'odd\"\\name');\n\
+      event 0 start; event 0 complete; event 1 fail;"
+    );
+    let output = Cmd::new("bash")
+      .args(["-c", &script])
+      .current_dir(&dir)
+      .output()
+      .unwrap();
+    let events = fs::read_to_string(dir.join(Env::EVENTS_LOG));
+    assert!(output.status.success());
+    let events = events
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect::<Vec<serde_json::Value>>();
+    let stages = events
+      .iter()
+      .map(|event| [&event["stage"], &event["event"]])
+      .collect::<Vec<_>>();
+    assert_eq!(
+      stages,
+      [
+        ["build", "start"],
+        ["build", "complete"],
+        [r#"odd"\name"#, "fail"],
+      ]
+    );
+    assert!(events.iter().all(|event| event["ts"].is_string()));
+  }
+
+  #[test]
+  fn stage_output_is_teed_to_its_own_log() {
+    let stages = IndexMap::from([
+      ("build".to_owned(), Stage::Cmd("make".to_owned())),
+      ("run".to_owned(), Stage::Cmd("./app".to_owned())),
+    ]);
+    let commands = stage_commands(&stages, None, &|_| String::new());
+    assert!(commands[0]
+      .contains("{ make; } > >(tee 'logs/build.log') 2>&1"));
+    assert!(
+      commands[1].contains("{ ./app; } > >(tee 'logs/run.log') 2>&1")
+    );
+    let runner = RecordingRunner::new();
+    log(&runner, &env(), &LogStart::Tail(10), Some("build")).unwrap();
+    let dir = "~/'.hmd/app/work-tree";
+    assert_eq!(
+      runner.command_lines(),
+      [format!(
+        "ssh user@host tail -n 10 -f {dir}/logs/build.log' \
+        --pid `cat {dir}/pipeline.pid'`"
+      )]
+    );
+  }
+
+  fn artifacts(remotes: &[&str]) -> Vec<Artifact> {
+    remotes
+      .iter()
+      .map(|&remote| Artifact {
+        local: remote.to_owned(),
+        remote: remote.to_owned(),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn parallel_upload_shares_master_connection() {
+    let runner = &RecordingRunner::new();
+    let artifacts = artifacts(&["web/index.html", "bin/app"]);
+    upload(runner, &env(), &artifacts, 2).unwrap();
+    let lines = runner.command_lines();
+    let control_path =
+      format!("ControlPath=~/.ssh/hmd-{}-%C", std::process::id());
+    assert_eq!(
+      lines[1],
+      format!("ssh -M -N -f -o {control_path} user@host")
+    );
+    let scps = lines.iter().filter(|line| line.starts_with("scp"));
+    assert_eq!(scps.clone().count(), 2);
+    assert!(scps
+      .into_iter()
+      .all(|line| line.contains(&control_path)));
+    assert_eq!(
+      lines.last().unwrap(),
+      &format!("ssh -O exit -o {control_path} user@host")
+    );
+  }
+
+  #[test]
+  fn single_upload_connects_without_master() {
+    let runner = &RecordingRunner::new();
+    let artifacts = artifacts(&["web/index.html", "bin/app"]);
+    upload(runner, &env(), &artifacts, 1).unwrap();
+    let lines = runner.command_lines();
+    assert_eq!(lines.len(), 4);
+    assert!(!lines.iter().any(|line| line.contains("Control")));
+  }
+
+  #[test]
+  fn upload_limit_is_passed_to_scp_in_kbits() {
+    let limit = |upload_limit, concurrent| {
+      let mut env = env();
+      env.upload_limit = upload_limit;
+      let scp = scp(&env, "", &strings(&["app"]), concurrent, None);
+      let args = args(&scp);
+      let i = args.iter().position(|arg| arg == "-l")?;
+      Some(args[i + 1].clone())
+    };
+    assert_eq!(limit(None, 1), None);
+    assert_eq!(limit(Some(100), 1).as_deref(), Some("800"));
+    assert_eq!(limit(Some(100), 2).as_deref(), Some("400"));
+    assert_eq!(limit(Some(0), 1).as_deref(), Some("1"));
+  }
+
+  #[test]
+  fn upload_stages_artifacts_then_moves_them() {
+    let runner = &RecordingRunner::new();
+    let artifacts = artifacts(&["bin/app"]);
+    upload(runner, &env(), &artifacts, 1).unwrap();
+    let upload_dir = "~/'.hmd/app/upload";
+    let work_tree = "~/'.hmd/app/work-tree";
+    assert_eq!(
+      runner.command_lines(),
+      [
+        format!(
+          "ssh user@host rm -rf {upload_dir}'; \
+          mkdir -p {upload_dir}' {upload_dir}/bin';"
+        ),
+        "scp -r bin/app user@host:~/.hmd/app/upload/bin".to_owned(),
+        format!(
+          "ssh user@host mkdir -p {work_tree}/bin' && \
+          {{ [ -d {upload_dir}/bin/app' ] && [ -d {work_tree}/bin/app' ] \
+          && rm -rf {work_tree}/bin/app'; \
+          mv -fT {upload_dir}/bin/app' {work_tree}/bin/app'; }} \
+          && rm -rf {upload_dir}';"
+        ),
+      ]
+    );
+    let runner = &RecordingRunner::new().with_failure("scp", 1);
+    upload(runner, &env(), &artifacts, 1).unwrap_err();
+    let lines = runner.command_lines();
+    assert_eq!(lines.len(), 2);
+    assert!(!lines.iter().any(|line| line.contains("mv -fT")));
+  }
+
+  #[test]
+  fn compressed_upload_packs_copies_and_unpacks_archives() {
+    let runner = &RecordingRunner::new();
+    let artifacts = artifacts(&["web/index.html", "bin/app"]);
+    upload_archives(runner, &env(), &artifacts).unwrap();
+    let commands = runner.commands();
+    assert_eq!(commands.len(), 5);
+    assert!(commands[0].join(" ").contains("mkdir -p"));
+    let tars = &commands[1..3];
+    assert_eq!(tars[0][..2], strings(&["tar", "-czf"]));
+    assert!(tars[0][2].ends_with(".hmd-artifacts-0.tar.gz"));
+    assert_eq!(tars[0][3..], strings(&["-C", "web", "index.html"]));
+    assert_eq!(tars[1][3..], strings(&["-C", "bin", "app"]));
+    let scp = &commands[3];
+    assert_eq!(scp[..2], strings(&["scp", "-r"]));
+    assert_eq!(scp[2..4], [tars[0][2].clone(), tars[1][2].clone()]);
+    assert_eq!(scp[4], "user@host:~/.hmd/app/upload");
+    let unpack = &commands[4][2];
+    let upload = "~/'.hmd/app/upload";
+    assert!(unpack.starts_with(&format!(
+      "tar -xzf {upload}/.hmd-artifacts-0.tar.gz' -C {upload}/web' \
+      && tar -xzf {upload}/.hmd-artifacts-1.tar.gz' -C {upload}/bin' \
+      && mkdir -p"
+    )));
+    assert!(!Path::new(&tars[0][2]).exists());
+  }
+
+  #[test]
+  fn failed_upload_removes_pipeline_sh() {
+    let runner = &deploy_runner().with_failure("scp", 1);
+    let hmd_yml = HmdYml {
+      artifacts: Vec::new(),
+      ..HmdYml::default()
+    };
+    assert!(
+      deploy(runner, &env(), &hmd_yml, &deploy_options()).is_err()
+    );
+    let commands = runner.commands();
+    let scp = commands.iter().find(|cmd| cmd[0] == "scp").unwrap();
+    let pipeline_sh = Path::new(&scp[scp.len() - 2]);
+    assert!(pipeline_sh.ends_with(Env::PIPELINE_SH));
+    assert!(!pipeline_sh.exists());
+    assert!(!pipeline_sh.parent().unwrap().exists());
+  }
+
+  #[test]
+  fn kept_pipeline_sh_stays_after_deploy() {
+    let deployed_pipeline_sh = |keep_pipeline_sh| {
+      let runner = &deploy_runner();
+      let options = DeployOptions {
+        keep_pipeline_sh,
+        ..deploy_options()
+      };
+      deploy(runner, &env(), &HmdYml::default(), &options).unwrap();
+      let commands = runner.commands();
+      let scp = commands.iter().find(|cmd| cmd[0] == "scp").unwrap();
+      PathBuf::from(&scp[scp.len() - 2])
+    };
+    assert!(!deployed_pipeline_sh(false).exists());
+    let pipeline_sh = deployed_pipeline_sh(true);
+    let script = fs::read_to_string(&pipeline_sh);
+    fs::remove_dir_all(pipeline_sh.parent().unwrap()).unwrap();
+    let expected =
+      build_pipeline_script(&env(), &HmdYml::default(), &[]);
+    assert_eq!(script.unwrap(), expected);
+  }
+
+  #[test]
+  fn deploy_without_vcs_issues_no_git_commands() {
+    let runner = &RecordingRunner::new();
+    let mut hmd_yml = HmdYml {
+      artifacts: Vec::new(),
+      ..HmdYml::default()
+    };
+    hmd_yml.settings.vcs = Vcs::None;
+    deploy(runner, &env(), &hmd_yml, &deploy_options()).unwrap();
+    let lines = runner.command_lines();
+    assert!(lines[0].contains("test -d ~/'.hmd/app/work-tree' ||"));
+    assert!(
+      lines.iter().all(|line| !line.contains("git")),
+      "{lines:?}"
+    );
+    assert!(lines.iter().any(|line| line.contains("nohup bash")));
+  }
+
+  #[test]
+  fn init_without_vcs_creates_only_work_tree() {
+    let runner = &RecordingRunner::new();
+    init_srv_repo(runner, &env(), Vcs::None).unwrap();
+    assert_eq!(
+      runner.command_lines(),
+      ["ssh user@host mkdir -p ~/'.hmd/app/work-tree';"]
+    );
+  }
+
+  #[test]
+  fn deploy_of_missing_project_fails_before_push() {
+    let runner = &deploy_runner().with_failure("exit 66", 66);
+    let err =
+      deploy(runner, &env(), &HmdYml::default(), &deploy_options())
+        .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "No project `app` at user@host. Try `hmd init`"
+    );
+    assert!(!runner
+      .command_lines()
+      .iter()
+      .any(|line| line.starts_with("git push")));
+  }
+
+  fn transfer(dir: &str, locals: &[&str]) -> Transfer {
+    let locals = locals.iter().map(|&local| local.to_owned());
+    (dir.to_owned(), locals.collect())
+  }
+
+  #[test]
+  fn upload_buckets_split_artifacts_evenly() {
+    let split = artifacts(&["a", "b", "c", "d", "e"]);
+    assert_eq!(
+      upload_buckets(&split, 2),
+      [
+        vec![transfer("", &["a", "b", "c"])],
+        vec![transfer("", &["d", "e"])],
+      ]
+    );
+    assert_eq!(upload_buckets(&split[..2], 4).len(), 2);
+    assert_eq!(
+      upload_buckets(&split, 0),
+      [vec![transfer("", &["a", "b", "c", "d", "e"])]]
+    );
+  }
+
+  #[test]
+  fn upload_buckets_split_dirs_to_parallel_chunks() {
+    let split = artifacts(&[
+      "web/a", "web/b", "bin/app", "web/c", "web/d", "web/e",
+    ]);
+    assert_eq!(
+      upload_buckets(&split, 2),
+      [
+        vec![
+          transfer("web", &["web/a", "web/b", "web/c"]),
+          transfer("bin", &["bin/app"]),
+        ],
+        vec![transfer("web", &["web/d", "web/e"])],
+      ]
+    );
+    assert_eq!(
+      upload_buckets(&artifacts(&["~/a", "/etc/b"]), 4),
+      [
+        vec![transfer(".home", &["~/a"])],
+        vec![transfer(".root/etc", &["/etc/b"])],
+      ]
+    );
+    assert_eq!(upload_buckets(&split, 0).len(), 1);
+  }
+
+  #[test]
+  fn upload_error_reports_every_failure() {
+    let failure = || other_err("scp failed");
+    assert!(upload_error(Vec::new(), 2).is_none());
+    let err = upload_error(vec![failure()], 2).unwrap();
+    assert_eq!(err.to_string(), "scp failed");
+    let err = upload_error(vec![failure(), failure()], 3).unwrap();
+    assert_eq!(
+      err.to_string(),
+      "2 of 3 uploads failed: scp failed; scp failed"
+    );
+  }
+
+  #[test]
+  fn held_lock_fails_until_force_unlocked() {
+    let dir = TestDir::new("lock");
+    let lock = dir.join("deploy lock");
+    let lock = &lock.to_string_lossy();
+    let take = |force_unlock| {
+      Cmd::new("bash")
+        .args(["-c", &lock_cmd(lock, force_unlock)])
+        .current_dir(&dir)
+        .output()
+        .unwrap()
+    };
+    let taken = take(false);
+    let held = take(false);
+    let unlocked = take(true);
+    assert!(taken.status.success());
+    assert!(!held.status.success());
+    let held = String::from_utf8_lossy(&held.stderr);
+    assert!(held.starts_with("Deploy is locked since 2"));
+    assert!(held.ends_with(", use --force-unlock if it's stale\n"));
+    assert!(unlocked.status.success());
+  }
+
+  #[test]
+  fn stop_all_stops_running_projects_and_reports_idle() {
+    let runner = RecordingRunner::new();
+    let target = Target::new("user@host".to_owned());
+    stop_all(&runner, &target).unwrap();
+    assert_eq!(
+      runner.commands(),
+      [strings(&["ssh", "user@host", &stop_all_cmd("~/.hmd")])]
+    );
+    let dir = TestDir::new("stop-all");
+    let busy = dir.join("busy/work-tree");
+    fs::create_dir_all(&busy).unwrap();
+    fs::create_dir_all(dir.join("idle/work-tree")).unwrap();
+    let mut pipeline = Cmd::new("sh")
+      .args(["-c", "sleep 30; true"])
+      .spawn()
+      .unwrap();
+    let pid = pipeline.id().to_string();
+    fs::write(busy.join(Env::PIPELINE_PID), &pid).unwrap();
+    // pipeline runs stages as its children, let it start one
+    for _ in 0..50 {
+      let pgrep = Cmd::new("pgrep")
+        .args(["-P", &pid])
+        .stdout(Stdio::null())
+        .status();
+      if pgrep.is_ok_and(|status| status.success()) {
+        break;
+      }
+      std::thread::sleep(Duration::from_millis(100));
+    }
+    let output = Cmd::new("bash")
+      .args(["-c", &stop_all_cmd(&dir.to_string_lossy())])
+      .output()
+      .unwrap();
+    let stopped = pipeline.wait().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+      String::from_utf8_lossy(&output.stdout),
+      "busy: stopped\nidle: idle\n"
+    );
+    assert!(stopped.success());
+  }
+
+  #[test]
+  fn restart_all_restarts_every_project_in_one_ssh() {
+    let runner = RecordingRunner::new();
+    let target = Target::new("user@host".to_owned());
+    restart_all(&runner, &target, Some(60), true).unwrap();
+    let commands = runner.commands();
+    assert_eq!(commands.len(), 1);
+    let ssh = &commands[0];
+    assert_eq!(ssh[0], "ssh");
+    assert!(ssh.contains(&"user@host".to_owned()));
+    assert!(ssh.contains(&"source '.profile';".to_owned()));
+    let restart = ssh.last().unwrap();
+    assert!(restart.starts_with(&format!(
+      "for project in `ls {}`;",
+      shell_quote(&target.projects_dir())
+    )));
+    assert!(restart.contains("then echo \"$project: restarting\";"));
+    assert!(restart.contains(&restart_cmd(
+      "bash",
+      None,
+      Some(60),
+      true
+    )));
+  }
+
+  #[test]
+  fn restart_all_skips_never_deployed_projects() {
+    let dir = TestDir::new("restart-all");
+    let deployed = dir.join("deployed/work-tree");
+    fs::create_dir_all(&deployed).unwrap();
+    fs::create_dir_all(dir.join("fresh/work-tree")).unwrap();
+    fs::write(deployed.join(Env::PIPELINE_SH), "true\n").unwrap();
+    let output = Cmd::new("bash")
+      .args([
+        "-c",
+        &restart_all_cmd(&dir.to_string_lossy(), "bash", None, false),
+      ])
+      .output()
+      .unwrap();
+    let pid_saved = deployed.join(Env::PIPELINE_PID).exists();
+    // pipeline is detached, let it open its log before cleanup
+    for _ in 0..50 {
+      if deployed.join(Env::OUT_LOG).exists() {
+        break;
+      }
+      std::thread::sleep(Duration::from_millis(100));
+    }
+    let fresh_pid_saved =
+      dir.join("fresh/work-tree").join(Env::PIPELINE_PID).exists();
+    assert!(output.status.success());
+    assert_eq!(
+      String::from_utf8_lossy(&output.stdout),
+      "deployed: restarting\nfresh: never deployed\n"
+    );
+    assert!(pid_saved);
+    assert!(!fresh_pid_saved);
+  }
+
+  #[test]
+  fn remove_creates_nested_logs_dir_before_download() {
+    let root = TestDir::new("keep-logs");
+    let dir = root.join("logs/app");
+    let runner = RecordingRunner::new();
+    remove(&runner, &env(), true, Some(&dir)).unwrap();
+    let created = dir.is_dir();
+    let leftovers = fs::read_dir(&dir).unwrap().count();
+    assert!(created);
+    assert_eq!(leftovers, 0);
+    let commands = runner.commands();
+    assert_eq!(commands[0][0], "scp");
+    assert_eq!(commands[0].last(), Some(&dir.display().to_string()));
+    assert_eq!(commands.len(), 2);
+  }
+
+  #[test]
+  fn remove_downloads_logs_then_removes_project() {
+    let dir = TestDir::new("keep-logs-order");
+    let env = &env();
+    let runner = RecordingRunner::new();
+    remove(&runner, env, true, Some(&dir)).unwrap();
+    let remote = "user@host:~/.hmd/app/work-tree";
+    assert_eq!(
+      runner.commands(),
+      [
+        strings(&[
+          "scp",
+          &format!("{remote}/out.log"),
+          &format!("{remote}/status.log"),
+          &dir.display().to_string(),
+        ]),
+        strings(&[
+          "ssh",
+          "user@host",
+          &kill_and_wait_cmd(&env.pipeline_pid()),
+          &systemd::uninstall_cmd("app"),
+          "rm -rf ~/'.hmd/app'",
+        ]),
+      ]
+    );
+    let failed = RecordingRunner::new().with_failure("scp", 1);
+    let err = remove(&failed, env, true, Some(&dir)).unwrap_err();
+    assert!(err.to_string().starts_with("Can't keep logs"));
+    assert_eq!(failed.commands().len(), 1);
+  }
+
+  #[test]
+  fn remove_fails_before_ssh_on_uncreatable_logs_dir() {
+    let root = TestDir::new("bad-logs");
+    let file = root.join("file");
+    fs::write(&file, "").unwrap();
+    let runner = RecordingRunner::new();
+    let err = remove(&runner, &env(), true, Some(&file.join("logs")))
+      .unwrap_err();
+    assert!(err.to_string().starts_with("Can't create logs dir"));
+    assert!(runner.commands().is_empty());
+  }
+
+  #[test]
+  fn download_paths_of_each_log() {
+    let paths = |what, dest: Option<&str>| {
+      download_paths(&env(), what, dest.map(Path::new))
+        .into_iter()
+        .map(|(remote, local)| (remote, local.display().to_string()))
+        .collect::<Vec<_>>()
+    };
+    let out = "~/.hmd/app/work-tree/out.log".to_owned();
+    let status = "~/.hmd/app/work-tree/status.log".to_owned();
+    assert_eq!(
+      paths(LogFile::Out, None),
+      [(out.clone(), "./app-out.log".to_owned())]
+    );
+    assert_eq!(
+      paths(LogFile::Status, Some("share/last.log")),
+      [(status.clone(), "share/last.log".to_owned())]
+    );
+    assert_eq!(
+      paths(LogFile::All, Some("share")),
+      [
+        (out, "share/app-out.log".to_owned()),
+        (status, "share/app-status.log".to_owned()),
+      ]
+    );
+  }
+
+  #[test]
+  fn download_copies_log_by_scp() {
+    let runner = RecordingRunner::new();
+    download(&runner, &env(), LogFile::Out, None).unwrap();
+    assert_eq!(
+      runner.command_lines(),
+      ["scp user@host:~/.hmd/app/work-tree/out.log ./app-out.log"]
+    );
+  }
+
+  #[test]
+  fn connects_to_ipv6_address_by_ipv6() {
+    let runner = RecordingRunner::new();
+    let env = Env::new("app", &Target::new("user@::1".to_owned()));
+    download(&runner, &env, LogFile::Out, None).unwrap();
+    runner.run(&mut ssh(&env)).unwrap();
+    assert_eq!(
+      runner.command_lines(),
+      [
+        "scp -6 user@[::1]:~/.hmd/app/work-tree/out.log ./app-out.log",
+        "ssh -6 user@::1",
+      ]
+    );
+  }
+
+  #[test]
+  fn status_prints_last_tail_entries() {
+    let env = env();
+    assert_eq!(
+      status_cmd(&env, Some(5)),
+      "tail -n 5 -f ~/'.hmd/app/work-tree/status.log' \
+        --pid `cat ~/'.hmd/app/work-tree/pipeline.pid'`;"
+    );
+    assert!(status_cmd(&env, None).starts_with("tail -f "));
+  }
+
+  #[test]
+  fn status_summary_of_running_pipeline() {
+    let output = "4242\n✅ build\n🟩 run\n2024-01-31T12:00:00\n";
+    let summary = parse_status_summary("app", output);
+    assert_eq!(
+      serde_json::to_value(&summary).unwrap(),
+      serde_json::json!({
+        "project": "app",
+        "running": true,
+        "pid": 4242,
+        "last_stage": "run",
+        "last_event_ts": "2024-01-31T12:00:00",
+      })
+    );
+  }
+
+  #[test]
+  fn status_summary_of_stopped_pipeline() {
+    let output = "\n✅ build\n❌ test\n🟥 run\n2024-01-31T12:00:00\n";
+    let summary = parse_status_summary("app", output);
+    assert_eq!(
+      serde_json::to_value(&summary).unwrap(),
+      serde_json::json!({
+        "project": "app",
+        "running": false,
+        "pid": null,
+        "last_stage": "test",
+        "last_event_ts": "2024-01-31T12:00:00",
+      })
+    );
+    let summary = parse_status_summary("app", "\n");
+    assert_eq!(
+      summary.to_json().unwrap(),
+      r#"{"project":"app","running":false,"pid":null,"last_stage":null,"last_event_ts":null}"#
+    );
+  }
+
+  #[test]
+  fn ps_shows_usage_of_running_pipeline_only() {
+    let runner = RecordingRunner::new();
+    ps(&runner, &env()).unwrap();
+    let commands = runner.commands();
+    assert_eq!(commands.len(), 1);
+    let ps = commands[0].last().unwrap();
+    assert!(ps.starts_with(
+      "if pid=`cat ~/'.hmd/app/work-tree/pipeline.pid' 2>/dev/null`;"
+    ));
+    assert!(ps.contains(r#"ps -o pid,pcpu,pmem,etime,cmd -p "$pid""#));
+    let dir = TestDir::new("ps");
+    let output = Cmd::new("sh")
+      .args(["-c", &ps_cmd(&dir.path(Env::PIPELINE_PID))])
+      .output()
+      .unwrap();
+    assert_eq!(
+      String::from_utf8_lossy(&output.stdout),
+      "not running\n"
+    );
+  }
+
+  #[test]
+  fn list_targets_remote_root() {
+    let runner = RecordingRunner::new();
+    let mut target = Target::new("host".to_owned());
+    list(&runner, &target).unwrap();
+    target.remote_root = Some("/srv/my apps".to_owned());
+    list(&runner, &target).unwrap();
+    assert_eq!(
+      runner.command_lines(),
+      ["ssh host ls ~/'.hmd'", "ssh host ls '/srv/my apps'"]
+    );
+    target.remote_root = Some(String::new());
+    assert!(list(&runner, &target).is_err());
+  }
+
+  #[test]
+  fn parses_du_sizes_of_projects() {
+    let du = "12\tapp/\n2048\tmy app/\n3145728\tbig/\nbad line\n";
+    assert_eq!(
+      parse_du(du),
+      [
+        ("12K".to_owned(), "app".to_owned()),
+        ("2M".to_owned(), "my app".to_owned()),
+        ("3G".to_owned(), "big".to_owned()),
+      ]
+    );
+    assert!(parse_du("du: command not found").is_empty());
+  }
+
+  #[test]
+  fn parses_available_kb_of_df() {
+    let df =
+      "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+      /dev/vda1 25671908 13671908 12000000 54% /\n";
+    assert_eq!(parse_df_available(df), Some(12_000_000));
+    assert_eq!(parse_df_available(""), None);
+    assert_eq!(parse_df_available("df: not found\n"), None);
+  }
+
+  #[test]
+  fn artifacts_must_fit_available_space() {
+    assert!(check_fits(1024, 1024).is_ok());
+    let err = check_fits(2048, 1024).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Insufficient disk space: artifacts need 2M, 1M available. \
+      Use --no-disk-check to skip check"
+    );
+  }
+
+  #[test]
+  fn human_size_stops_at_terabytes() {
+    assert_eq!(human_size(0), "0K");
+    assert_eq!(human_size(1023), "1023K");
+    assert_eq!(human_size(1024), "1M");
+    assert_eq!(human_size(1024 * 1024 * 1024 * 2048), "2048T");
+  }
+
+  #[test]
+  fn interrupted_streaming_fails_as_interrupted() {
+    let failed = || Err(other_err("ssh failed"));
+    let err = interrupted_context(failed(), true).unwrap_err();
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::Interrupted)
+    ));
+    let err = interrupted_context(failed(), false).unwrap_err();
+    assert_eq!(err.to_string(), "ssh failed");
+    assert!(interrupted_context(Ok(()), true).is_ok());
+  }
+
+  /// Runs `move_uploaded_cmd` of `artifacts` uploaded to `upload`
+  /// dir of temp `root` with work tree `work-tree`
+  fn move_uploaded(root: &Path, artifacts: &[Artifact]) -> bool {
+    let mut env = env();
+    env.upload_dir = root.join("upload").to_string_lossy().into();
+    env.work_tree = root.join("work-tree").to_string_lossy().into();
+    Cmd::new("sh")
+      .args(["-c", &move_uploaded_cmd(&env, artifacts)])
+      .status()
+      .unwrap()
+      .success()
+  }
+
+  #[test]
+  fn move_replaces_previous_dir_artifact() {
+    let root = TestDir::new("move-dir");
+    fs::create_dir_all(root.join("upload/dist")).unwrap();
+    fs::create_dir_all(root.join("work-tree/dist")).unwrap();
+    fs::write(root.join("upload/dist/new.js"), "").unwrap();
+    fs::write(root.join("work-tree/dist/old.js"), "").unwrap();
+    let moved = move_uploaded(&root, &artifacts(&["dist"]));
+    let new = root.join("work-tree/dist/new.js").exists();
+    let old = root.join("work-tree/dist/old.js").exists();
+    assert!(moved);
+    assert!(new && !old);
+  }
+
+  #[test]
+  fn move_of_file_keeps_dir_at_its_path() {
+    let root = TestDir::new("move-file");
+    fs::create_dir_all(root.join("upload")).unwrap();
+    fs::create_dir_all(root.join("work-tree/www")).unwrap();
+    fs::write(root.join("upload/app.conf"), "").unwrap();
+    fs::write(root.join("work-tree/www/index.html"), "").unwrap();
+    let artifact = Artifact {
+      local: "app.conf".to_owned(),
+      remote: "www".to_owned(),
+    };
+    let moved = move_uploaded(&root, &[artifact]);
+    let kept = root.join("work-tree/www/index.html").exists();
+    assert!(!moved);
+    assert!(kept);
+  }
+
+  #[test]
+  fn interrupted_wait_stops_pipeline_if_confirmed() {
+    let runner = &RecordingRunner::new();
+    let err =
+      interrupted_wait(runner, &env(), Supervisor::Nohup, false);
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::Interrupted)
+    ));
+    assert!(runner.commands().is_empty());
+    let err =
+      interrupted_wait(runner, &env(), Supervisor::Nohup, true);
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::Interrupted)
+    ));
+    assert!(runner.command_lines()[0].contains("pkill -SIGINT"));
+    let runner = &RecordingRunner::new().with_failure("pkill", 1);
+    let err =
+      interrupted_wait(runner, &env(), Supervisor::Nohup, true);
+    assert!(!matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::Interrupted)
+    ));
+  }
+}
@@ -1,429 +1,755 @@
 mod cli;
-mod env;
-mod hmd_config_yml;
-mod hmd_yml;
 
-use std::{
-  error::Error,
-  fs, io,
-  process::{Command as Cmd, ExitCode},
-};
-
-use clap::Parser;
-use cli::{LogOption, StatusOption};
-use indexmap::IndexMap;
+use std::{io, path::Path, process::ExitCode};
 
-use crate::{
-  cli::{Cli, Command, ProjectOption, SshAddressOption},
-  env::Env,
-  hmd_config_yml::HMD_CONFIG_YML,
-  hmd_yml::HmdYml,
+use clap::{ColorChoice, Parser};
+use cli::{
+  InstanceOption, LogOption, StatusOption, TailOption, TimeoutOption,
+};
+use hmd::{
+  branch_target, color, dotenv, get_init_project, get_project,
+  get_target,
+  hmd_config_yml::{self, HmdConfigYml, Target},
+  hmd_yml::{self, HmdYml, Supervisor, Template, Vcs},
+  interrupt, list, list_sizes, output, restart_all, stop_all,
+  DeployOptions, Env, Hmd, HmdError, InitOptions, LogFile, LogStart,
+  VerboseRunner,
 };
 
-const HMD_ROOT: &str = "~/.hmd";
-const SCRIPT: &str = include_str!("../script.sh");
-const PROJECT_NOT_PROVIDED: &str = "Project not provided";
+use crate::cli::{
+  Cli, Command, ConfigCommand, ErrorFormat, HostKeyCheck,
+  ProjectOption, SshAddressOption, TargetOptions,
+};
 
 fn main() -> ExitCode {
-  match launch() {
+  let cli = Cli::parse();
+  let error_format = cli.error_format;
+  match launch(cli) {
     Ok(()) => ExitCode::SUCCESS,
     Err(err) => {
-      eprintln!("{err}\n\n{err:?}");
-      ExitCode::FAILURE
+      // Failure of interrupted child exits by Ctrl-C handler
+      interrupt::settle();
+      eprintln!("{}", format_error(&err, error_format));
+      HmdError::downcast(&err)
+        .map_or(ExitCode::FAILURE, |err| err.exit_code().into())
+    }
+  }
+}
+
+/// Failure message for stderr in `format`
+fn format_error(err: &io::Error, format: ErrorFormat) -> String {
+  match format {
+    ErrorFormat::Human => format!(
+      "{}\n\n{}",
+      color::stderr().red(err),
+      color::stderr().dim(format!("{err:?}"))
+    ),
+    ErrorFormat::Json => {
+      let kind = HmdError::downcast(err).map_or("io", HmdError::kind);
+      serde_json::json!({ "error": err.to_string(), "kind": kind })
+        .to_string()
     }
   }
 }
 
-fn launch() -> io::Result<()> {
-  match Cli::parse().command {
+/// Passes `--host-key-check` to ssh options of command
+fn apply_host_key_check(cli: &mut Cli) {
+  let Some(check) = cli.host_key_check else {
+    return;
+  };
+  if let Some(ssh_address) = ssh_address_mut(&mut cli.command) {
+    ssh_address.ssh_options.push(host_key_option(check));
+  }
+}
+
+/// Ssh options of command connecting to server
+fn ssh_address_mut(
+  command: &mut Command,
+) -> Option<&mut SshAddressOption> {
+  match command {
+    Command::Init { ssh_address, .. }
+    | Command::Deploy { ssh_address, .. }
+    | Command::Stop { ssh_address, .. }
+    | Command::Restart { ssh_address, .. }
+    | Command::Status { ssh_address, .. }
+    | Command::Ps { ssh_address, .. }
+    | Command::Log { ssh_address, .. }
+    | Command::History { ssh_address, .. }
+    | Command::Events { ssh_address, .. }
+    | Command::Download { ssh_address, .. }
+    | Command::Env { ssh_address, .. }
+    | Command::List { ssh_address, .. }
+    | Command::Open { ssh_address, .. }
+    | Command::Exec { ssh_address, .. }
+    | Command::Clean { ssh_address, .. }
+    | Command::Prune { ssh_address, .. }
+    | Command::Remove { ssh_address, .. } => Some(ssh_address),
+    Command::Config { .. } => None,
+    #[cfg(feature = "self-update")]
+    Command::SelfUpdate { .. } => None,
+  }
+}
+
+/// `-o` option of ssh and scp
+fn host_key_option(check: HostKeyCheck) -> String {
+  let value = match check {
+    HostKeyCheck::Yes => "yes",
+    HostKeyCheck::No => "no",
+    HostKeyCheck::AcceptNew => "accept-new",
+  };
+  format!("StrictHostKeyChecking={value}")
+}
+
+#[allow(clippy::too_many_lines)]
+fn launch(mut cli: Cli) -> io::Result<()> {
+  color::init(match cli.color {
+    ColorChoice::Always => Some(true),
+    ColorChoice::Never => Some(false),
+    ColorChoice::Auto => None,
+  });
+  output::init(cli.quiet, cli.verbose);
+  interrupt::init();
+  if let Some(directory) = &cli.directory {
+    change_dir(directory)?;
+  }
+  apply_host_key_check(&mut cli);
+  // Interactive shell needs terminal, not piped output
+  let prefixed = (cli.prefix || cli.targets.target.is_some())
+    && !matches!(cli.command, Command::Open { .. });
+  let prefix = prefixed
+    .then(|| {
+      let target = cli.targets.target.clone();
+      target.or_else(|| get_project(None).ok())
+    })
+    .flatten();
+  // Output of these is what's asked for, so quiet keeps it
+  let shows_output = matches!(
+    cli.command,
+    Command::Open { .. }
+      | Command::Exec { .. }
+      | Command::Log { .. }
+      | Command::Status { .. }
+      | Command::Ps { .. }
+      | Command::History { .. }
+      | Command::Events { .. }
+      | Command::List { .. }
+  );
+  let runner = &VerboseRunner {
+    prefix,
+    mute: cli.quiet && !shows_output,
+  };
+  let targets = &cli.targets;
+  match cli.command {
     Command::Init {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
       project: ProjectOption { project },
+      force,
+      template,
+      stages,
+      artifacts,
+      no_git,
+      check,
     } => {
-      let project = project
-        .or_else(|| Some(hmd_yml::read().ok()?.project))
-        .or_else(|| current_dir().ok())
-        .ok_or(other_err(PROJECT_NOT_PROVIDED))?;
-      init(&Env::new(&project, &get_ssh_address(ssh_address)?))
+      let template = template
+        .map_or_else(hmd_yml::detect_template, Template::from);
+      let vcs = if no_git {
+        Vcs::None
+      } else {
+        hmd_yml::read()
+          .map(|hmd_yml| hmd_yml.settings.vcs)
+          .unwrap_or_default()
+      };
+      let project = get_init_project(project)?;
+      let hmd = Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      );
+      if check {
+        return hmd.check_init();
+      }
+      hmd.init(&InitOptions {
+        force,
+        template,
+        stages,
+        artifacts,
+        vcs,
+      })
     }
     Command::Deploy {
+      ssh_address,
       dirty,
+      message,
+      remote_branch,
+      limit,
+      compress,
+      parallel,
+      compress_artifacts,
+      git_ref,
+      retry_stages,
+      env_from,
+      tag,
+      wait,
+      only_upload,
+      no_checkout,
+      no_disk_check,
+      force_unlock,
+      no_force_push,
+      keep_pipeline_sh,
+      print_pipeline,
+      watch,
+      timeout: TimeoutOption { timeout },
       log: LogOption { log: log_flag },
       status: StatusOption {
         status: status_flag,
       },
+      instance: InstanceOption { instance },
     } => {
-      let hmd_yml = hmd_yml::read()?;
-      let env = &Env::new(&hmd_yml.project, &hmd_yml.ssh_address);
-      deploy(env, &hmd_yml, dirty)?;
+      // Read before dir change, so path is relative to current one
+      let env_vars = env_from
+        .as_deref()
+        .map(dotenv::read)
+        .transpose()?
+        .unwrap_or_default();
+      // Artifacts and pipeline are relative to `hmd.yml` dir
+      if let Some(root) = hmd_yml::find_hmd_yml()?.parent() {
+        std::env::set_current_dir(root)?;
+      }
+      let mut hmd_yml = hmd_yml::read()?;
+      if retry_stages.is_some() {
+        hmd_yml.settings.stage_retries = retry_stages;
+      }
+      let project = get_project(None)?;
+      if print_pipeline {
+        let env =
+          preview_env(&project, ssh_address.ssh_address, &hmd_yml);
+        let hmd = Hmd::new(runner, env);
+        print!("{}", hmd.pipeline_script(&hmd_yml, &env_vars));
+        return Ok(());
+      }
+      // Explicit target or address wins over branch mapping
+      let branch_target = if targets.target.is_none()
+        && ssh_address.ssh_address.is_none()
+        && matches!(hmd_yml.settings.vcs, Vcs::Git)
+      {
+        branch_target(runner)?
+      } else {
+        None
+      };
+      let targets = TargetOptions {
+        target: targets.target.clone().or(branch_target),
+        ..targets.clone()
+      };
+      let mut env = Env::new(
+        &project,
+        &targets.get(ssh_address, Some(&project))?,
+      )
+      .with_instance(instance);
+      if limit.is_some() {
+        env.upload_limit = limit;
+      }
+      env.compress |= compress;
+      let hmd = Hmd::new(runner, env);
+      let options = &DeployOptions {
+        dirty: dirty || watch,
+        message: message.as_deref(),
+        remote_branch: remote_branch.as_deref(),
+        parallel,
+        compress_artifacts,
+        git_ref: git_ref.as_deref(),
+        wait,
+        only_upload,
+        no_checkout,
+        no_disk_check,
+        force_unlock,
+        no_force_push: no_force_push
+          || hmd_yml.settings.force_push == Some(false),
+        keep_pipeline_sh,
+        timeout: timeout.or(hmd_yml.settings.timeout),
+        env_vars: &env_vars,
+        tag: tag.as_deref(),
+      };
+      if watch {
+        return hmd.watch_deploy(&hmd_yml, options);
+      }
+      hmd.deploy(&hmd_yml, options)?;
       if log_flag {
-        log(env)?;
+        hmd.log(&LogStart::default(), None)?;
       } else if status_flag {
-        status(env)?;
+        hmd.status(false, None, hmd_yml.settings.supervisor)?;
       }
       Ok(())
     }
     Command::Stop {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
       project: ProjectOption { project },
+      all,
+      instance: InstanceOption { instance },
     } => {
+      if all {
+        return stop_all(runner, &targets.get(ssh_address, None)?);
+      }
       let project = get_project(project)?;
-      stop(&Env::new(&project, &get_ssh_address(ssh_address)?))
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        )
+        .with_instance(instance),
+      )
+      .stop(local_supervisor(&project))
     }
     Command::Restart {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
       project: ProjectOption { project },
+      if_running,
+      verify_script,
+      all,
+      timeout: TimeoutOption { timeout },
       log: LogOption { log: log_flag },
       status: StatusOption {
         status: status_flag,
       },
+      instance: InstanceOption { instance },
     } => {
+      if all {
+        return restart_all(
+          runner,
+          &targets.get(ssh_address, None)?,
+          timeout,
+          if_running,
+        );
+      }
       let project = get_project(project)?;
-      let env = &Env::new(&project, &get_ssh_address(ssh_address)?);
-      restart_pipeline(env)?;
+      let hmd = Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        )
+        .with_instance(instance),
+      );
+      let timeout =
+        timeout.or_else(|| local_hmd_yml(&project)?.settings.timeout);
+      hmd.restart(timeout, if_running, verify_script)?;
       if log_flag {
-        log(env)?;
+        hmd.log(&LogStart::default(), None)?;
       } else if status_flag {
-        status(env)?;
+        hmd.status(false, None, local_supervisor(&project))?;
       }
       Ok(())
     }
     Command::Status {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
       project: ProjectOption { project },
+      watch_exit,
+      json,
+      tail: TailOption { tail },
+      instance: InstanceOption { instance },
     } => {
       let project = get_project(project)?;
-      status(&Env::new(&project, &get_ssh_address(ssh_address)?))
+      let hmd = Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        )
+        .with_instance(instance),
+      );
+      if json {
+        println!("{}", hmd.status_summary()?.to_json()?);
+        return Ok(());
+      }
+      hmd.status(watch_exit, tail, local_supervisor(&project))
+    }
+    Command::Ps {
+      ssh_address,
+      project: ProjectOption { project },
+    } => {
+      let project = get_project(project)?;
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .ps()
     }
     Command::Log {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
+      project: ProjectOption { project },
+      since,
+      last_deploy,
+      stage,
+      tail: TailOption { tail },
+      instance: InstanceOption { instance },
+    } => {
+      let project = get_project(project)?;
+      let log_start = match (since, tail) {
+        (Some(since), _) => LogStart::Since(since),
+        _ if last_deploy => LogStart::LastDeploy,
+        (None, Some(lines)) => LogStart::Tail(lines),
+        (None, None) => LogStart::default(),
+      };
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        )
+        .with_instance(instance),
+      )
+      .log(&log_start, stage.as_deref())
+    }
+    Command::History {
+      ssh_address,
+      project: ProjectOption { project },
+    } => {
+      let project = get_project(project)?;
+      let history = Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .history()?;
+      for meta in history {
+        println!("{meta}");
+      }
+      Ok(())
+    }
+    Command::Events {
+      ssh_address,
       project: ProjectOption { project },
     } => {
       let project = get_project(project)?;
-      log(&Env::new(&project, &get_ssh_address(ssh_address)?))
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .events()
+    }
+    Command::Download {
+      ssh_address,
+      project: ProjectOption { project },
+      what,
+      dest,
+    } => {
+      let project = get_project(project)?;
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .download(what.into(), dest.as_deref())
+    }
+    Command::Env {
+      ssh_address,
+      project: ProjectOption { project },
+    } => {
+      let project = get_project(project)?;
+      let env = Env::new(
+        &project,
+        &targets.get(ssh_address, Some(&project))?,
+      );
+      print!("{env}");
+      Ok(())
     }
     Command::List {
-      ssh_address: SshAddressOption { ssh_address },
-    } => list(&get_ssh_address(ssh_address)?),
+      ssh_address,
+      size,
+      remote_root,
+    } => {
+      let target = &mut targets.get(ssh_address, None)?;
+      if remote_root.is_some() {
+        target.remote_root = remote_root;
+      }
+      if size {
+        list_sizes(runner, target)
+      } else {
+        list(runner, target)
+      }
+    }
     Command::Open {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
+      project: ProjectOption { project },
+    } => {
+      let project = get_project(project)?;
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .open()
+    }
+    Command::Exec {
+      ssh_address,
       project: ProjectOption { project },
+      args,
     } => {
       let project = get_project(project)?;
-      open(&Env::new(&project, &get_ssh_address(ssh_address)?))
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .exec(&args)
+    }
+    Command::Clean {
+      ssh_address,
+      project: ProjectOption { project },
+      all,
+    } => {
+      let project = get_project(project)?;
+      let clean_cmd = if all {
+        hmd_yml::read()?.settings.clean
+      } else {
+        None
+      };
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .clean(clean_cmd.as_deref())
+    }
+    Command::Prune {
+      ssh_address,
+      project: ProjectOption { project },
+      keep,
+    } => {
+      let project = get_project(project)?;
+      Hmd::new(
+        runner,
+        Env::new(
+          &project,
+          &targets.get(ssh_address, Some(&project))?,
+        ),
+      )
+      .prune(keep)
+    }
+    Command::Config { command } => config(command),
+    #[cfg(feature = "self-update")]
+    Command::SelfUpdate { check_only } => {
+      hmd::self_update::self_update(runner, check_only)
     }
     Command::Remove {
-      ssh_address: SshAddressOption { ssh_address },
+      ssh_address,
       project: ProjectOption { project },
+      yes,
+      keep_logs,
     } => {
       let project =
-        project.as_ref().ok_or(other_err(PROJECT_NOT_PROVIDED))?;
-      remove(&Env::new(project, &get_ssh_address(ssh_address)?))
+        project.as_ref().ok_or(HmdError::ProjectNotProvided)?;
+      Hmd::new(
+        runner,
+        Env::new(project, &targets.get(ssh_address, Some(project))?),
+      )
+      .remove(yes, keep_logs.as_deref())
     }
   }
 }
 
-/// Searches ssh address in `ssh_address`, `hmd.yml` and `~/.hmd/config.yml`
-///
-/// # Errors
-///
-/// Returns an error if ssh address not provided
-fn get_ssh_address(
-  ssh_address: Option<String>,
-) -> io::Result<String> {
-  ssh_address
-    .or_else(|| Some(hmd_yml::read().ok()?.ssh_address))
-    .or_else(|| Some(hmd_config_yml::read().ok()?.ssh_address))
-    .ok_or(other_err("SSH address not provided"))
-}
-
-/// Searches project in `project` and `hmd.yml`
-///
-/// # Errors
-///
-/// Returns an error if project not provided
-fn get_project(project: Option<String>) -> io::Result<String> {
-  project
-    .or_else(|| Some(hmd_yml::read().ok()?.project))
-    .ok_or(other_err(PROJECT_NOT_PROVIDED))
-}
-
-fn current_dir() -> io::Result<String> {
-  let error =
-    || other_err("Can't parse project name from current dir");
-  let project = std::env::current_dir()?
-    .file_name()
-    .ok_or_else(error)?
-    .to_str()
-    .ok_or_else(error)?
-    .to_string();
-  Ok(project)
-}
-
-fn init(env: &Env) -> io::Result<()> {
-  init_srv_repo(env)?;
-  hmd_yml::write(&env.project, &env.ssh_address)?;
-  if hmd_config_yml::read().is_err() {
-    hmd_config_yml::write(env.ssh_address.clone())?;
-    println!("Config created at {HMD_CONFIG_YML}");
+fn config(command: ConfigCommand) -> io::Result<()> {
+  match command {
+    ConfigCommand::Show => {
+      print!(
+        "{}",
+        hmd_config_yml::to_string(&hmd_config_yml::read()?)?
+      );
+    }
+    ConfigCommand::SetSsh { ssh_address } => {
+      let mut hmd_config_yml = match hmd_config_yml::read() {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+          HmdConfigYml::default()
+        }
+        result => result?,
+      };
+      hmd_config_yml.ssh_address = Some(ssh_address);
+      hmd_config_yml::write(&hmd_config_yml)?;
+    }
+    ConfigCommand::Path => {
+      println!("{}", hmd_config_yml::path()?.display());
+    }
   }
   Ok(())
 }
 
-fn init_srv_repo(env: &Env) -> io::Result<()> {
-  let ssh_address = &env.ssh_address;
-  let git_dir = &env.git_dir;
-  let work_tree = &env.work_tree;
-  let ssh = &mut ssh(ssh_address);
-  ssh
-    .arg(format!("mkdir -p {git_dir} {work_tree};"))
-    .arg(format!("cd {git_dir};"))
-    .arg("git init --bare;");
-  exec_verbose(ssh)?;
-  Ok(())
-}
-
-fn ssh(ssh_address: &str) -> Cmd {
-  let mut ssh = Cmd::new("ssh");
-  ssh.arg(ssh_address);
-  ssh
-}
-
-fn exec_verbose(cmd: &mut Cmd) -> io::Result<()> {
-  let program = cmd.get_program().to_string_lossy();
-  let args = cmd
-    .get_args()
-    .map(|arg| arg.to_string_lossy())
-    .collect::<Vec<_>>()
-    .join(" ");
-  println!("\n{program} {args}");
-  match cmd.status()? {
-    status if status.success() => Ok(()),
-    status => {
-      Err(other_err(format!("Process terminated with {status}")))
-    }
+impl TargetOptions {
+  /// Target of `project` at `ssh_address` resolved by `get_target`
+  fn get(
+    &self,
+    ssh_address: SshAddressOption,
+    project: Option<&str>,
+  ) -> io::Result<Target> {
+    get_target(
+      ssh_address.ssh_address,
+      &ssh_address.ssh_options,
+      self.target.as_deref(),
+      self.scope.as_deref(),
+      self.user.as_deref(),
+      project,
+    )
   }
 }
 
-fn deploy(
-  env: &Env,
+/// Env of `--print-pipeline` built without resolving target,
+/// so preview needs neither config nor git
+fn preview_env(
+  project: &str,
+  ssh_address: Option<String>,
   hmd_yml: &HmdYml,
-  dirty: bool,
-) -> io::Result<()> {
-  if dirty {
-    git_push_dirty(env)?;
-  } else {
-    git_push(env)?;
-  }
-  generate_pipeline_sh(&hmd_yml.stages)?;
-  let mut artifacts = hmd_yml.artifacts.clone();
-  artifacts.push(Env::PIPELINE_SH.to_string());
-  upload(env, &artifacts)?;
-  fs::remove_file(Env::PIPELINE_SH)?;
-  run_pipeline(env)?;
-  Ok(())
-}
-
-fn git_push_dirty(env: &Env) -> io::Result<()> {
-  git_commit_staged()?;
-  git_commit_unstaged()?;
-  let push_result = git_push(env);
-  git_reset_unstaged()?;
-  git_reset_staged()?;
-  push_result?;
-  Ok(())
+) -> Env {
+  let ssh_address =
+    ssh_address.unwrap_or_else(|| hmd_yml.ssh_address.clone());
+  Env::new(project, &Target::new(ssh_address))
 }
 
-fn git_commit_staged() -> io::Result<()> {
-  run_verbose("git commit -m staged --allow-empty")?;
-  Ok(())
+/// Local `hmd.yml` if it's config of `project`
+fn local_hmd_yml(project: &str) -> Option<HmdYml> {
+  config_of(hmd_yml::read().ok(), project)
 }
 
-fn git_commit_unstaged() -> io::Result<()> {
-  run_verbose("git add .")?;
-  run_verbose("git commit -m unstaged --allow-empty")?;
-  Ok(())
+fn config_of(
+  hmd_yml: Option<HmdYml>,
+  project: &str,
+) -> Option<HmdYml> {
+  hmd_yml.filter(|hmd_yml| hmd_yml.project == project)
 }
 
-fn git_reset_unstaged() -> io::Result<()> {
-  run_verbose("git reset HEAD~1")?;
-  Ok(())
+/// Supervisor of local `hmd.yml` of `project`,
+/// default one without it or for other project
+fn local_supervisor(project: &str) -> Supervisor {
+  local_hmd_yml(project)
+    .map(|hmd_yml| hmd_yml.settings.supervisor)
+    .unwrap_or_default()
 }
 
-fn git_reset_staged() -> io::Result<()> {
-  run_verbose("git reset HEAD~1 --soft")?;
-  Ok(())
-}
-
-fn git_push(env: &Env) -> io::Result<()> {
-  let ssh_address = &env.ssh_address;
-  let git_dir = &env.git_dir;
-  run_verbose(&format!(
-    "git push --force {ssh_address}:{git_dir} HEAD"
-  ))?;
-  Ok(())
-}
-
-fn run_verbose(args: &str) -> io::Result<()> {
-  let args = &mut args.split_whitespace();
-  let mut cmd = Cmd::new(args.next().unwrap());
-  cmd.args(args);
-  exec_verbose(&mut cmd)
-}
-
-fn generate_pipeline_sh(
-  stages: &IndexMap<String, String>,
-) -> io::Result<()> {
-  let pipeline = stage_commands(stages).join("\n\n");
-  let stages = stages.keys().cloned().collect::<Vec<_>>().join(" ");
-  let script =
-    format!("{SCRIPT}\n\nstages=({stages});\n\n{pipeline}");
-  fs::write(Env::PIPELINE_SH, script)?;
-  Ok(())
-}
-
-fn upload(env: &Env, artifacts: &[String]) -> io::Result<()> {
-  if artifacts.is_empty() {
-    return Ok(());
-  }
-  let ssh_address = &env.ssh_address;
-  let work_tree = &env.work_tree;
-  let scp = &mut Cmd::new("scp");
-  scp
-    .args(artifacts)
-    .arg(format!("{ssh_address}:{work_tree}"));
-  exec_verbose(scp)?;
-  Ok(())
-}
-
-fn run_pipeline(env: &Env) -> io::Result<()> {
-  let out_log = Env::OUT_LOG;
-  let pipeline_sh = Env::PIPELINE_SH;
-  let pipeline_pid = Env::PIPELINE_PID;
-  let work_tree = &env.work_tree;
-  // FIXME: Why git doesn't recognize ~ path?
-  let git_dir = env.git_dir.replacen('~', "$HOME", 1);
-  let branch = git_branch()?;
-  let ssh = &mut ssh(&env.ssh_address);
-  ssh
-    .arg("source .profile;")
-    .arg(format!("cd {work_tree};"))
-    .arg(kill_and_wait_cmd(pipeline_pid))
-    .arg(format!(
-      "git --git-dir={git_dir} --work-tree=. checkout --force {branch};"
+/// Runs hmd in `directory` of `-C` like git does
+fn change_dir(directory: &Path) -> io::Result<()> {
+  std::env::set_current_dir(directory).map_err(|err| {
+    let directory = directory.display();
+    io::Error::other(format!(
+      "Can't change dir to {directory}: {err}"
     ))
-    .arg(format!(
-      "nohup bash {pipeline_sh} > {out_log} 2>&1 & echo $! > {pipeline_pid};"
-    ));
-  exec_verbose(ssh)?;
-  Ok(())
+  })
 }
 
-fn restart_pipeline(env: &Env) -> io::Result<()> {
-  let out_log = Env::OUT_LOG;
-  let pipeline_sh = Env::PIPELINE_SH;
-  let pipeline_pid = Env::PIPELINE_PID;
-  let work_tree = &env.work_tree;
-  let ssh = &mut ssh(&env.ssh_address);
-  ssh
-    .arg("source .profile;")
-    .arg(format!("cd {work_tree};"))
-    .arg(kill_and_wait_cmd(pipeline_pid))
-    .arg(format!(
-      "nohup bash {pipeline_sh} > {out_log} 2>&1 & echo $! > {pipeline_pid};"
-    ));
-  exec_verbose(ssh)?;
-  Ok(())
-}
-
-fn git_branch() -> io::Result<String> {
-  let output = Cmd::new("git")
-    .args(["branch", "--show-current"])
-    .output()?;
-  Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
-}
-
-fn stage_commands(stages: &IndexMap<String, String>) -> Vec<String> {
-  stages
-    .iter()
-    .enumerate()
-    .map(|(i, (stage, cmd))| {
-      format!(
-        r#"
-          echo -e "\n🟩 [`date +%FT%T`] > Start {stage}\n{cmd}\n";
-          run {i} && {cmd} && complete {i} || {{
-            echo -e "\n❌ [`date +%FT%T`] > Failed {stage}\n";
-            panic {i};
-            exit 1;
-          }};
-          echo -e "\n🟩 [`date +%FT%T`] > End {stage}\n";
-        "#
-      )
-    })
-    .collect()
-}
-
-fn stop(env: &Env) -> io::Result<()> {
-  let ssh = &mut ssh(&env.ssh_address);
-  ssh.arg(kill_and_wait_cmd(&env.pipeline_pid()));
-  exec_verbose(ssh)?;
-  Ok(())
+impl From<cli::LogFile> for LogFile {
+  fn from(what: cli::LogFile) -> Self {
+    match what {
+      cli::LogFile::Out => Self::Out,
+      cli::LogFile::Status => Self::Status,
+      cli::LogFile::All => Self::All,
+    }
+  }
 }
 
-fn kill_and_wait_cmd(pipeline_pid: &str) -> String {
-  format!(
-    "while pkill -SIGINT -P `cat {pipeline_pid}` 2>/dev/null; do sleep 1; done;"
-  )
+impl From<cli::Template> for Template {
+  fn from(template: cli::Template) -> Self {
+    match template {
+      cli::Template::Rust => Self::Rust,
+      cli::Template::Node => Self::Node,
+      cli::Template::Python => Self::Python,
+      cli::Template::Static => Self::Static,
+    }
+  }
 }
 
-fn status(env: &Env) -> io::Result<()> {
-  let ssh = &mut ssh(&env.ssh_address);
-  let status_log = env.status_log();
-  let pid = env.pipeline_pid();
-  ssh.arg(format!("tail -f {status_log} --pid `cat {pid}`"));
-  exec_verbose(ssh)?;
-  Ok(())
-}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn local_config_of_other_project_is_ignored() {
+    let supervisor = |project| {
+      let mut hmd_yml = HmdYml {
+        project: "app".to_owned(),
+        ..HmdYml::default()
+      };
+      hmd_yml.settings.supervisor = Supervisor::Systemd;
+      config_of(Some(hmd_yml), project)
+        .map(|hmd_yml| hmd_yml.settings.supervisor)
+    };
+    assert_eq!(supervisor("app"), Some(Supervisor::Systemd));
+    assert_eq!(supervisor("other"), None);
+  }
 
-fn log(env: &Env) -> io::Result<()> {
-  let ssh = &mut ssh(&env.ssh_address);
-  let log = env.out_log();
-  let pid = env.pipeline_pid();
-  ssh.arg(format!("tail -n 50 -f {log} --pid `cat {pid}`"));
-  exec_verbose(ssh)?;
-  Ok(())
-}
+  #[test]
+  fn target_options_are_global() {
+    let cli = Cli::try_parse_from([
+      "hmd", "status", "--target", "prod", "--scope", "team",
+      "--user", "bob",
+    ])
+    .unwrap();
+    let targets = cli.targets;
+    assert_eq!(targets.target.as_deref(), Some("prod"));
+    assert_eq!(targets.scope.as_deref(), Some("team"));
+    assert_eq!(targets.user.as_deref(), Some("bob"));
+  }
 
-fn list(ssh_address: &str) -> io::Result<()> {
-  let ssh = &mut ssh(ssh_address);
-  ssh.arg(format!("ls {HMD_ROOT}"));
-  exec_verbose(ssh)?;
-  Ok(())
-}
+  #[test]
+  fn preview_env_takes_address_without_config() {
+    let hmd_yml = HmdYml {
+      ssh_address: "local".to_owned(),
+      ..HmdYml::default()
+    };
+    let address = |ssh_address: Option<&str>| {
+      let ssh_address = ssh_address.map(str::to_owned);
+      preview_env("app", ssh_address, &hmd_yml).ssh_address
+    };
+    assert_eq!(address(None), "local");
+    assert_eq!(address(Some("given")), "given");
+  }
 
-fn open(env: &Env) -> io::Result<()> {
-  let ssh = &mut ssh(&env.ssh_address);
-  let work_tree = &env.work_tree;
-  ssh
-    .arg("-t")
-    .arg(format!(r#"cd {work_tree}; bash --login"#));
-  exec_verbose(ssh)?;
-  Ok(())
-}
+  fn ssh_options(args: &[&str]) -> Vec<String> {
+    let mut cli = Cli::parse_from(args);
+    apply_host_key_check(&mut cli);
+    match cli.command {
+      Command::Status { ssh_address, .. } => ssh_address.ssh_options,
+      _ => unreachable!(),
+    }
+  }
 
-fn remove(env: &Env) -> io::Result<()> {
-  let project_dir = &env.project_dir;
-  let ssh = &mut ssh(&env.ssh_address);
-  ssh
-    .arg(kill_and_wait_cmd(&env.pipeline_pid()))
-    .arg(format!("rm -rf {project_dir}"));
-  exec_verbose(ssh)?;
-  Ok(())
-}
+  #[test]
+  fn host_key_check_maps_to_ssh_option() {
+    assert_eq!(
+      ssh_options(&[
+        "hmd",
+        "status",
+        "-o",
+        "Port=2222",
+        "--host-key-check",
+        "accept-new",
+      ]),
+      ["Port=2222", "StrictHostKeyChecking=accept-new"]
+    );
+    assert_eq!(
+      ssh_options(&["hmd", "--host-key-check", "no", "status"]),
+      ["StrictHostKeyChecking=no"]
+    );
+    assert!(ssh_options(&["hmd", "status"]).is_empty());
+  }
 
-fn other_err<E>(err: E) -> io::Error
-where
-  E: Into<Box<dyn Error + Send + Sync>>,
-{
-  io::Error::other(err)
+  #[test]
+  fn directory_option_fails_on_missing_dir() {
+    let err = change_dir(Path::new("/no/such/hmd/dir")).unwrap_err();
+    assert!(err.to_string().starts_with("Can't change dir"));
+  }
 }
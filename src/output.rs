@@ -0,0 +1,35 @@
+use std::{
+  fmt::Display,
+  sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use crate::spinner;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// Verbosity from which remote pipeline is traced
+const TRACE_VERBOSITY: u8 = 2;
+
+/// Suppresses non-error output if `quiet`,
+/// sets `verbose` level otherwise
+pub fn init(quiet: bool, verbose: u8) {
+  QUIET.store(quiet, Ordering::Relaxed);
+  VERBOSITY.store(verbose, Ordering::Relaxed);
+}
+
+/// Whether remote pipeline should run under `set -x`
+pub(crate) fn is_tracing() -> bool {
+  VERBOSITY.load(Ordering::Relaxed) >= TRACE_VERBOSITY
+}
+
+pub(crate) fn is_quiet() -> bool {
+  QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints `line` to stdout unless quiet, above spinner if shown
+pub(crate) fn println(line: impl Display) {
+  if !is_quiet() {
+    spinner::suspend(|| println!("{line}"));
+  }
+}
@@ -0,0 +1,406 @@
+use std::{
+  io::{self, BufRead, BufReader, Read},
+  process::{Command as Cmd, ExitStatus, Stdio},
+  sync::{Mutex, PoisonError},
+  thread,
+  time::Duration,
+};
+
+use crate::{
+  color::{self, Palette},
+  error::HmdError,
+  interrupt, output, spinner,
+};
+
+/// Exit code of ssh and scp on connection errors
+const SSH_CONNECTION_ERROR: i32 = 255;
+/// Exit code of git on fatal errors, connection ones too
+const GIT_FATAL_ERROR: i32 = 128;
+
+/// Executes external commands
+///
+/// Deploy flow depends on it instead of spawning processes,
+/// so it can be driven by a test double.
+/// It's shared between threads of parallel upload.
+pub trait CommandRunner: Sync {
+  /// Executes `cmd` and waits for its exit status
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `cmd` can't be spawned
+  fn status(&self, cmd: &mut Cmd) -> io::Result<ExitStatus>;
+
+  /// Executes `cmd` and captures its stdout
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `cmd` can't be spawned or fails
+  fn output(&self, cmd: &mut Cmd) -> io::Result<String>;
+
+  /// Executes `cmd`
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `cmd` can't be spawned or fails
+  fn run(&self, cmd: &mut Cmd) -> io::Result<()> {
+    let status = self.status(cmd)?;
+    exit_status_result(cmd, status)
+  }
+
+  /// Executes remote `cmd` retrying connection failures
+  /// up to `retries` times with exponential backoff
+  ///
+  /// Failures of remote command itself aren't retried.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `cmd` can't be spawned or fails
+  fn run_remote(
+    &self,
+    cmd: &mut Cmd,
+    retries: u32,
+  ) -> io::Result<()> {
+    run_retrying(self, cmd, retries, SSH_CONNECTION_ERROR)
+  }
+
+  /// Executes `git` talking to remote by ssh, e.g. `push`,
+  /// retrying its fatal errors like `run_remote`
+  ///
+  /// Git exits with the same code on connection errors
+  /// and other fatal ones.
+  ///
+  /// # Errors
+  ///
+  /// Returns an error if `cmd` can't be spawned or fails
+  fn run_remote_git(
+    &self,
+    cmd: &mut Cmd,
+    retries: u32,
+  ) -> io::Result<()> {
+    run_retrying(self, cmd, retries, GIT_FATAL_ERROR)
+  }
+}
+
+/// Executes `cmd` by `runner` retrying exits with `failure_code`
+/// up to `retries` times with exponential backoff
+///
+/// Caught Ctrl-C stops retrying.
+fn run_retrying<R: CommandRunner + ?Sized>(
+  runner: &R,
+  cmd: &mut Cmd,
+  retries: u32,
+  failure_code: i32,
+) -> io::Result<()> {
+  let mut attempt = 0;
+  loop {
+    let status = runner.status(cmd)?;
+    if status.code() != Some(failure_code)
+      || attempt >= retries
+      || interrupt::is_interrupted()
+    {
+      return exit_status_result(cmd, status);
+    }
+    let delay = Duration::from_secs(2u64.saturating_pow(attempt));
+    attempt += 1;
+    output::println(color::red(format!(
+      "Connection failed, retry {attempt}/{retries} in {}s",
+      delay.as_secs()
+    )));
+    thread::sleep(delay);
+  }
+}
+
+/// Spawns commands printing them before
+///
+/// In quiet mode command isn't printed.
+/// With `prefix` every output line is prefixed with it.
+pub struct VerboseRunner {
+  pub prefix: Option<String>,
+  /// Drop stdout of commands, e.g. of deploy steps in quiet mode,
+  /// while ones printing requested output, like `open`, keep it
+  pub mute: bool,
+}
+
+impl CommandRunner for VerboseRunner {
+  fn status(&self, cmd: &mut Cmd) -> io::Result<ExitStatus> {
+    if self.mute {
+      cmd.stdout(Stdio::null());
+    }
+    let line = color::dim(command_line(cmd));
+    let Some(prefix) = &self.prefix else {
+      output::println(format!("\n{line}"));
+      return cmd.status();
+    };
+    output::println(prefix_lines(color::stdout(), prefix, &line));
+    if !self.mute {
+      cmd.stdout(Stdio::piped());
+    }
+    let mut child = cmd.stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    thread::scope(|scope| {
+      if let Some(stderr) = stderr {
+        scope.spawn(|| {
+          for_each_line(stderr, |line| {
+            spinner::suspend(|| {
+              eprintln!(
+                "{}",
+                prefix_lines(color::stderr(), prefix, &line)
+              );
+            });
+          });
+        });
+      }
+      if let Some(stdout) = stdout {
+        for_each_line(stdout, |line| {
+          spinner::suspend(|| {
+            println!(
+              "{}",
+              prefix_lines(color::stdout(), prefix, &line)
+            );
+          });
+        });
+      }
+    });
+    child.wait()
+  }
+
+  /// Captures only stdout, so ssh password prompt and typed
+  /// password still go through terminal
+  fn output(&self, cmd: &mut Cmd) -> io::Result<String> {
+    output::println(format!("\n{}", color::dim(command_line(cmd))));
+    let output = cmd
+      .stdin(Stdio::inherit())
+      .stderr(Stdio::inherit())
+      .output()?;
+    exit_status_result(cmd, output.status)?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+  }
+}
+
+fn command_line(cmd: &Cmd) -> String {
+  let program = cmd.get_program().to_string_lossy();
+  let args = cmd
+    .get_args()
+    .map(|arg| arg.to_string_lossy())
+    .collect::<Vec<_>>()
+    .join(" ");
+  format!("{program} {args}")
+}
+
+/// Records commands instead of spawning them
+///
+/// Test double of `CommandRunner`, so generated ssh, scp and git
+/// commands are checked without server. Commands succeed with
+/// empty output unless reply or failure matches their command line.
+#[derive(Default)]
+pub struct RecordingRunner {
+  commands: Mutex<Vec<Vec<String>>>,
+  outputs: Vec<(String, String)>,
+  failures: Vec<(String, i32)>,
+}
+
+impl RecordingRunner {
+  #[must_use]
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Replies `stdout` to commands containing `pattern`
+  #[must_use]
+  pub fn with_output(mut self, pattern: &str, stdout: &str) -> Self {
+    self.outputs.push((pattern.to_owned(), stdout.to_owned()));
+    self
+  }
+
+  /// Fails commands containing `pattern` with exit `code`
+  #[must_use]
+  pub fn with_failure(mut self, pattern: &str, code: i32) -> Self {
+    self.failures.push((pattern.to_owned(), code));
+    self
+  }
+
+  /// Recorded commands as program followed by args
+  #[must_use]
+  pub fn commands(&self) -> Vec<Vec<String>> {
+    self
+      .commands
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone()
+  }
+
+  /// Recorded commands as space separated lines
+  #[must_use]
+  pub fn command_lines(&self) -> Vec<String> {
+    self.commands().iter().map(|argv| argv.join(" ")).collect()
+  }
+
+  /// Records `cmd` returning its exit status
+  fn record(&self, cmd: &Cmd) -> ExitStatus {
+    let argv = [cmd.get_program()]
+      .into_iter()
+      .chain(cmd.get_args())
+      .map(|arg| arg.to_string_lossy().into_owned())
+      .collect::<Vec<_>>();
+    let line = argv.join(" ");
+    self
+      .commands
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .push(argv);
+    let code = self
+      .failures
+      .iter()
+      .find(|(pattern, _)| line.contains(pattern))
+      .map_or(0, |&(_, code)| code);
+    exit_status(code)
+  }
+}
+
+impl CommandRunner for RecordingRunner {
+  fn status(&self, cmd: &mut Cmd) -> io::Result<ExitStatus> {
+    Ok(self.record(cmd))
+  }
+
+  fn output(&self, cmd: &mut Cmd) -> io::Result<String> {
+    let line = command_line(cmd);
+    let status = self.record(cmd);
+    exit_status_result(cmd, status)?;
+    Ok(
+      self
+        .outputs
+        .iter()
+        .find(|(pattern, _)| line.contains(pattern))
+        .map(|(_, stdout)| stdout.clone())
+        .unwrap_or_default(),
+    )
+  }
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+  use std::os::unix::process::ExitStatusExt;
+  ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+  use std::os::windows::process::ExitStatusExt;
+  ExitStatus::from_raw(code.unsigned_abs())
+}
+
+/// Prefixes every line of `text` with `[prefix]` dimmed by `palette`
+///
+/// Trailing newline doesn't make empty prefixed line.
+fn prefix_lines(
+  palette: Palette,
+  prefix: &str,
+  text: &str,
+) -> String {
+  let prefix = palette.dim(format!("[{prefix}]"));
+  text
+    .lines()
+    .map(|line| format!("{prefix} {line}"))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+fn for_each_line(reader: impl Read, mut f: impl FnMut(String)) {
+  BufReader::new(reader)
+    .lines()
+    .map_while(Result::ok)
+    .for_each(&mut f);
+}
+
+/// Result of `cmd` exited with `status`
+///
+/// Connection failure of ssh or scp is `ConnectionFailed`,
+/// so it's told apart from failure of remote command.
+fn exit_status_result(
+  cmd: &Cmd,
+  status: ExitStatus,
+) -> io::Result<()> {
+  if status.success() {
+    return Ok(());
+  }
+  let is_ssh =
+    matches!(cmd.get_program().to_str(), Some("ssh" | "scp"));
+  if is_ssh && status.code() == Some(SSH_CONNECTION_ERROR) {
+    return Err(HmdError::ConnectionFailed.into());
+  }
+  Err(HmdError::CommandFailed { status }.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn connection_failure_of_ssh_is_told_apart() {
+    let runner = RecordingRunner::new()
+      .with_failure("ssh", SSH_CONNECTION_ERROR)
+      .with_failure("tail", SSH_CONNECTION_ERROR);
+    let err = runner.run(Cmd::new("ssh").arg("host")).unwrap_err();
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::ConnectionFailed)
+    ));
+    let err = runner.run(&mut Cmd::new("tail")).unwrap_err();
+    assert!(matches!(
+      HmdError::downcast(&err),
+      Some(HmdError::CommandFailed { status })
+        if status.code() == Some(SSH_CONNECTION_ERROR)
+    ));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn output_keeps_inherited_stdin_and_stderr() {
+    let own = |fd: u8| {
+      let path = std::fs::read_link(format!("/proc/self/fd/{fd}"));
+      path.unwrap().display().to_string()
+    };
+    let runner = VerboseRunner {
+      prefix: None,
+      mute: false,
+    };
+    let stdio = runner
+      .output(
+        Cmd::new("sh")
+          .args(["-c", "readlink /proc/self/fd/0 /proc/self/fd/2"]),
+      )
+      .unwrap();
+    assert_eq!(stdio, format!("{}\n{}\n", own(0), own(2)));
+  }
+
+  #[test]
+  fn prefixes_every_line() {
+    let palette = color::stdout();
+    assert_eq!(
+      prefix_lines(palette, "prod", "a\nb\n"),
+      "[prod] a\n[prod] b"
+    );
+    assert_eq!(
+      prefix_lines(palette, "prod", "a\nb"),
+      "[prod] a\n[prod] b"
+    );
+    assert_eq!(
+      prefix_lines(palette, "prod", "\na"),
+      "[prod] \n[prod] a"
+    );
+    assert_eq!(prefix_lines(palette, "prod", ""), "");
+  }
+
+  #[test]
+  fn prefixed_lines_of_partial_output() {
+    let mut lines = Vec::new();
+    for_each_line("first\nsecond\npartial".as_bytes(), |line| {
+      lines.push(prefix_lines(color::stdout(), "app", &line));
+    });
+    assert_eq!(
+      lines,
+      ["[app] first", "[app] second", "[app] partial"]
+    );
+  }
+}
@@ -0,0 +1,102 @@
+use std::{cmp::Ordering, io, process::Command as Cmd};
+
+use serde::Deserialize;
+
+use crate::{other_err, output, runner::CommandRunner};
+
+/// Repository of releases, tagged like `v0.2.0`
+const REPO: &str = "https://github.com/FedorBuggins/hmd";
+const LATEST_RELEASE_URL: &str =
+  "https://api.github.com/repos/FedorBuggins/hmd/releases/latest";
+
+#[derive(Deserialize)]
+struct Release {
+  tag_name: String,
+}
+
+/// Installs latest GitHub release by `cargo install`
+/// if it's newer than running binary, only reports it if `check_only`
+///
+/// # Errors
+///
+/// Returns an error if release can't be fetched or installed
+pub fn self_update(
+  runner: &dyn CommandRunner,
+  check_only: bool,
+) -> io::Result<()> {
+  let current = env!("CARGO_PKG_VERSION");
+  let tag = latest_tag(runner)?;
+  if !is_newer(&tag, current) {
+    output::println(format!("hmd {current} is up to date"));
+    return Ok(());
+  }
+  output::println(format!("Update {current} -> {tag} is available"));
+  if check_only {
+    return Ok(());
+  }
+  runner.run(Cmd::new("cargo").args([
+    "install", "--locked", "--force", "--git", REPO, "--tag", &tag,
+  ]))
+}
+
+/// Tag of latest release
+fn latest_tag(runner: &dyn CommandRunner) -> io::Result<String> {
+  let json = runner
+    .output(Cmd::new("curl").args(["-fsSL", LATEST_RELEASE_URL]))?;
+  let release: Release =
+    serde_json::from_str(&json).map_err(|err| {
+      other_err(format!("Can't read latest release: {err}"))
+    })?;
+  Ok(release.tag_name)
+}
+
+/// Compares dot separated numbers of versions, `v` prefix
+/// and pre-release or build suffix are ignored
+fn is_newer(latest: &str, current: &str) -> bool {
+  let numbers = |version: &str| {
+    version
+      .trim_start_matches('v')
+      .split(['-', '+', ' '])
+      .next()
+      .unwrap_or_default()
+      .split('.')
+      .map(|n| n.parse::<u64>().unwrap_or_default())
+      .collect::<Vec<_>>()
+  };
+  let (latest, current) = (numbers(latest), numbers(current));
+  let len = latest.len().max(current.len());
+  let at = |version: &[u64], i| version.get(i).copied().unwrap_or(0);
+  (0..len)
+    .map(|i| at(&latest, i).cmp(&at(&current, i)))
+    .find(|ordering| ordering.is_ne())
+    == Some(Ordering::Greater)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::RecordingRunner;
+
+  #[test]
+  fn compares_versions_by_numbers() {
+    assert!(is_newer("v0.2.0", "0.1.9"));
+    assert!(is_newer("0.10.0", "0.9.0"));
+    assert!(is_newer("v1.0.1", "1.0"));
+    assert!(!is_newer("v0.1.0", "0.1.0"));
+    assert!(!is_newer("0.1", "0.1.0"));
+    assert!(!is_newer("v0.1.0", "0.2.0"));
+    assert!(!is_newer("v0.1.0-rc.1", "0.1.0"));
+  }
+
+  #[test]
+  fn check_only_doesnt_install() {
+    let runner = RecordingRunner::new()
+      .with_output("curl", r#"{"tag_name":"v999.0.0"}"#);
+    self_update(&runner, true).unwrap();
+    assert_eq!(runner.commands().len(), 1);
+    self_update(&runner, false).unwrap();
+    let install = runner.command_lines().pop().unwrap();
+    assert!(install.starts_with("cargo install"));
+    assert!(install.ends_with("--tag v999.0.0"));
+  }
+}
@@ -0,0 +1,30 @@
+/// Quotes `value` to be interpolated into remote shell command
+///
+/// Leading `~/` stays unquoted to keep tilde expansion.
+pub(crate) fn shell_quote(value: &str) -> String {
+  match value.strip_prefix("~/") {
+    Some(path) => format!("~/{}", quote(path)),
+    None => quote(value),
+  }
+}
+
+fn quote(value: &str) -> String {
+  format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quotes_value() {
+    assert_eq!(shell_quote("a b;c"), "'a b;c'");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+  }
+
+  #[test]
+  fn keeps_home_prefix_unquoted() {
+    assert_eq!(shell_quote("~/.hmd/app"), "~/'.hmd/app'");
+    assert_eq!(shell_quote("~user/app"), "'~user/app'");
+  }
+}
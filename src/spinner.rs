@@ -0,0 +1,82 @@
+use std::{
+  io::{self, IsTerminal},
+  sync::{Mutex, MutexGuard, PoisonError},
+  time::Duration,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::output;
+
+/// Spinner shown now, hidden while hmd prints lines
+static ACTIVE: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Redraw interval of spinner
+const TICK: Duration = Duration::from_millis(100);
+
+/// Spinner at stderr while long remote command runs,
+/// cleared when it's dropped
+pub(crate) struct Spinner {
+  bar: ProgressBar,
+}
+
+impl Spinner {
+  /// Shows spinner with `message` unless quiet
+  /// or stderr isn't terminal
+  pub(crate) fn start(message: &str) -> Self {
+    let tty = io::stderr().is_terminal();
+    Self::start_if(message, tty && !output::is_quiet())
+  }
+
+  fn start_if(message: &str, enabled: bool) -> Self {
+    if !enabled {
+      return Self {
+        bar: ProgressBar::hidden(),
+      };
+    }
+    let bar =
+      ProgressBar::new_spinner().with_message(message.to_owned());
+    if let Ok(style) = ProgressStyle::with_template("{spinner} {msg}")
+    {
+      bar.set_style(style);
+    }
+    bar.enable_steady_tick(TICK);
+    *active() = Some(bar.clone());
+    Self { bar }
+  }
+}
+
+impl Drop for Spinner {
+  fn drop(&mut self) {
+    self.bar.finish_and_clear();
+    active().take();
+  }
+}
+
+/// Runs `print` with active spinner cleared,
+/// so printed lines aren't overwritten by it
+pub(crate) fn suspend<T>(print: impl FnOnce() -> T) -> T {
+  let bar = active().clone();
+  match bar {
+    Some(bar) => bar.suspend(print),
+    None => print(),
+  }
+}
+
+fn active() -> MutexGuard<'static, Option<ProgressBar>> {
+  ACTIVE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn spinner_is_hidden_without_terminal() {
+    let spinner = Spinner::start_if("Uploading", false);
+    assert!(spinner.bar.is_hidden());
+    if !io::stderr().is_terminal() {
+      assert!(Spinner::start("Uploading").bar.is_hidden());
+    }
+  }
+}
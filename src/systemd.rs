@@ -0,0 +1,147 @@
+use crate::{env::Env, shell::shell_quote};
+
+const UNIT_DIR: &str = "~/.config/systemd/user";
+
+/// Name of `--user` unit running last stage of project
+pub(crate) fn unit_name(project: &str) -> String {
+  let project = project
+    .chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+      _ => '_',
+    })
+    .collect::<String>();
+  format!("hmd-{project}.service")
+}
+
+/// Builds unit file running `cmd` at project work tree
+pub(crate) fn unit(env: &Env, cmd: &str) -> String {
+  let project = &env.project;
+  let work_tree = match env.work_tree.strip_prefix('~') {
+    Some(path) => format!("%h{path}"),
+    None => env.work_tree.clone(),
+  };
+  let cmd = exec_quote(cmd);
+  format!(
+    "[Unit]
+Description=hmd {project}
+
+[Service]
+WorkingDirectory={work_tree}
+ExecStart=/bin/bash -lc {cmd}
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+"
+  )
+}
+
+/// Writes and enables unit running `cmd`
+pub(crate) fn install_cmd(env: &Env, cmd: &str) -> String {
+  let unit_name = unit_name(&env.project);
+  let unit = shell_quote(&unit(env, cmd));
+  format!(
+    "mkdir -p {UNIT_DIR}; \
+    printf '%s' {unit} > {UNIT_DIR}/{unit_name}; \
+    systemctl --user daemon-reload; \
+    systemctl --user enable {unit_name};"
+  )
+}
+
+pub(crate) fn restart_cmd(project: &str) -> String {
+  format!("systemctl --user restart {}", unit_name(project))
+}
+
+/// Stops unit if installed
+pub(crate) fn stop_cmd(project: &str) -> String {
+  let unit_name = unit_name(project);
+  format!("systemctl --user stop {unit_name} 2>/dev/null || true;")
+}
+
+/// Prints unit status if installed
+///
+/// Inactive unit isn't a failure, as it's stopped by `hmd stop`.
+pub(crate) fn status_cmd(project: &str) -> String {
+  let unit_name = unit_name(project);
+  format!(
+    "if systemctl --user cat {unit_name} > /dev/null 2>&1; then \
+    systemctl --user status --no-pager {unit_name} || true; fi;"
+  )
+}
+
+/// Stops, disables and removes unit if installed
+pub(crate) fn uninstall_cmd(project: &str) -> String {
+  let unit_name = unit_name(project);
+  format!(
+    "systemctl --user disable --now {unit_name} 2>/dev/null; \
+    rm -f {UNIT_DIR}/{unit_name}; \
+    systemctl --user daemon-reload 2>/dev/null || true;"
+  )
+}
+
+/// Quotes `cmd` as single `ExecStart` argument
+fn exec_quote(cmd: &str) -> String {
+  let cmd = cmd
+    .replace('\\', r"\\")
+    .replace('"', r#"\""#)
+    .replace('\n', r"\n")
+    .replace('%', "%%")
+    .replace('$', "$$");
+  format!("\"{cmd}\"")
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::hmd_config_yml::Target;
+
+  use super::*;
+
+  fn env() -> Env {
+    Env::new("my app", &Target::new("user@host".to_owned()))
+  }
+
+  #[test]
+  fn unit_name_escapes_project() {
+    assert_eq!(unit_name("my app"), "hmd-my_app.service");
+  }
+
+  #[test]
+  fn unit_runs_cmd_at_work_tree() {
+    assert_eq!(
+      unit(&env(), "./app --port $PORT"),
+      r#"[Unit]
+Description=hmd my app
+
+[Service]
+WorkingDirectory=%h/.hmd/my app/work-tree
+ExecStart=/bin/bash -lc "./app --port $$PORT"
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+"#
+    );
+  }
+
+  #[test]
+  fn install_cmd_writes_unit_and_enables_it() {
+    let cmd = install_cmd(&env(), "./app");
+    let unit_file = "~/.config/systemd/user/hmd-my_app.service";
+    assert!(cmd.starts_with(
+      "mkdir -p ~/.config/systemd/user; printf '%s' '[Unit]"
+    ));
+    assert!(cmd.contains(&format!("' > {unit_file};")));
+    assert!(cmd.ends_with(
+      "systemctl --user daemon-reload; \
+      systemctl --user enable hmd-my_app.service;"
+    ));
+  }
+
+  #[test]
+  fn status_of_inactive_unit_succeeds() {
+    assert!(status_cmd("my app").ends_with(
+      "status --no-pager hmd-my_app.service || true; fi;"
+    ));
+  }
+}
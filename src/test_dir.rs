@@ -0,0 +1,55 @@
+//! Temp dir of tests, shared by unit and integration ones
+#![allow(dead_code)]
+
+use std::{
+  fs,
+  ops::Deref,
+  path::{Path, PathBuf},
+};
+
+/// Temp dir of test `name` removed with its content on drop
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+  /// Creates empty dir unique to test `name` and process
+  pub(crate) fn new(name: &str) -> Self {
+    let dir = std::env::temp_dir()
+      .join(format!("hmd-test-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    Self(dir)
+  }
+
+  /// Creates empty file at relative `path` with its parent dirs
+  pub(crate) fn file(&self, path: &str) -> String {
+    let path = self.0.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, "").unwrap();
+    path.to_string_lossy().into_owned()
+  }
+
+  /// Relative `path` inside dir
+  pub(crate) fn path(&self, path: &str) -> String {
+    self.0.join(path).to_string_lossy().into_owned()
+  }
+}
+
+impl Deref for TestDir {
+  type Target = Path;
+
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl AsRef<Path> for TestDir {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TestDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
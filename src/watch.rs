@@ -0,0 +1,301 @@
+use std::{
+  collections::BTreeSet,
+  io::{self, Read, Write},
+  path::{Component, Path, PathBuf},
+  process::{Command as Cmd, Stdio},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{self, RecvTimeoutError},
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::{interrupt, other_err};
+
+/// Quiet period after last change before it's handled
+pub(crate) const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Period of checking Ctrl-C while no changes come
+const POLL: Duration = Duration::from_millis(100);
+
+static SUPERSEDED: AtomicBool = AtomicBool::new(false);
+
+/// Whether change came while deploy runs, so it should stop
+/// at next step and let newer one start
+pub(crate) fn is_superseded() -> bool {
+  SUPERSEDED.load(Ordering::Relaxed)
+}
+
+/// Coalesces bursts of changes into one
+///
+/// Changes are ready once none came for `delay`,
+/// so editor saves and build outputs trigger single deploy.
+#[derive(Debug)]
+pub(crate) struct Debouncer {
+  delay: Duration,
+  last_change: Option<Instant>,
+}
+
+impl Debouncer {
+  pub(crate) fn new(delay: Duration) -> Self {
+    Self {
+      delay,
+      last_change: None,
+    }
+  }
+
+  /// Records change at `now`, postponing pending one
+  pub(crate) fn change(&mut self, now: Instant) {
+    self.last_change = Some(now);
+  }
+
+  /// Takes pending change if it's quiet for `delay` at `now`
+  pub(crate) fn take_ready(&mut self, now: Instant) -> bool {
+    let ready = self
+      .last_change
+      .is_some_and(|last| now.duration_since(last) >= self.delay);
+    if ready {
+      self.last_change = None;
+    }
+    ready
+  }
+}
+
+/// Calls `on_change` after debounced changes of files in `dir`
+/// until Ctrl-C
+///
+/// Changes of `.git`, gitignored paths and ones `is_excluded`
+/// by relative path are skipped, so build output doesn't retrigger.
+/// `on_change` runs in background, change coming meanwhile marks it
+/// superseded and it's called again once in-flight one returns.
+///
+/// # Errors
+///
+/// Returns an error if `dir` can't be watched or `on_change` fails
+pub(crate) fn watch(
+  dir: &Path,
+  is_excluded: impl Fn(&Path) -> bool,
+  on_change: impl Fn() -> io::Result<()> + Sync,
+) -> io::Result<()> {
+  let root = dir.canonicalize()?;
+  let (sender, receiver) = mpsc::channel();
+  let mut watcher = notify::recommended_watcher(sender)
+    .map_err(|err| other_err(format!("Can't watch files: {err}")))?;
+  watcher
+    .watch(&root, RecursiveMode::Recursive)
+    .map_err(|err| other_err(format!("Can't watch files: {err}")))?;
+  let mut debouncer = Debouncer::new(DEBOUNCE);
+  let mut changed = BTreeSet::new();
+  let mut pending = false;
+  thread::scope(|scope| {
+    let mut running = None;
+    while !interrupt::is_interrupted() {
+      match receiver.recv_timeout(POLL) {
+        Ok(Ok(event)) if !event.kind.is_access() => {
+          let paths = event
+            .paths
+            .iter()
+            .filter_map(|path| relative_path(&root, path))
+            .filter(|path| {
+              !is_git(path) && !excluded(path, &is_excluded)
+            });
+          let before = changed.len();
+          changed.extend(paths);
+          if changed.len() > before {
+            debouncer.change(Instant::now());
+          }
+        }
+        Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => break,
+      }
+      if debouncer.take_ready(Instant::now()) {
+        let paths = std::mem::take(&mut changed);
+        if !without_gitignored(&root, paths).is_empty() {
+          pending = true;
+          if running.is_some() {
+            SUPERSEDED.store(true, Ordering::Relaxed);
+          }
+        }
+      }
+      if running
+        .as_ref()
+        .is_some_and(thread::ScopedJoinHandle::is_finished)
+      {
+        if let Some(deploy) = running.take() {
+          deploy
+            .join()
+            .map_err(|_| other_err("Deploy thread panicked"))??;
+        }
+      }
+      if pending && running.is_none() {
+        pending = false;
+        SUPERSEDED.store(false, Ordering::Relaxed);
+        running = Some(scope.spawn(&on_change));
+      }
+    }
+    match running {
+      Some(deploy) => deploy
+        .join()
+        .map_err(|_| other_err("Deploy thread panicked"))?,
+      None => Ok(()),
+    }
+  })
+}
+
+/// `path` of event relative to watched `root`
+/// without `.` components
+fn relative_path(root: &Path, path: &Path) -> Option<PathBuf> {
+  let relative = path.strip_prefix(root).unwrap_or(path);
+  let relative = relative
+    .components()
+    .filter(|c| !matches!(c, Component::CurDir))
+    .collect::<PathBuf>();
+  (!relative.as_os_str().is_empty()).then_some(relative)
+}
+
+fn is_git(path: &Path) -> bool {
+  path
+    .components()
+    .any(|c| c == Component::Normal(".git".as_ref()))
+}
+
+/// Whether `path` or any dir containing it `is_excluded`
+fn excluded(
+  path: &Path,
+  is_excluded: impl Fn(&Path) -> bool,
+) -> bool {
+  path
+    .ancestors()
+    .filter(|path| !path.as_os_str().is_empty())
+    .any(is_excluded)
+}
+
+/// `paths` without ones ignored by git of `root`,
+/// all of them if it isn't git work tree
+fn without_gitignored(
+  root: &Path,
+  mut paths: BTreeSet<PathBuf>,
+) -> BTreeSet<PathBuf> {
+  if paths.is_empty() {
+    return paths;
+  }
+  for ignored in git_check_ignore(root, &paths).unwrap_or_default() {
+    paths.remove(&ignored);
+  }
+  paths
+}
+
+/// Ignored of `paths` by `git check-ignore`, paths go
+/// NUL separated by stdin, so their number isn't limited
+fn git_check_ignore(
+  root: &Path,
+  paths: &BTreeSet<PathBuf>,
+) -> io::Result<Vec<PathBuf>> {
+  let mut git = Cmd::new("git")
+    .args(["check-ignore", "--stdin", "-z"])
+    .current_dir(root)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()?;
+  let stdin = git.stdin.take();
+  let stdout = git.stdout.take();
+  let output = thread::scope(|scope| {
+    scope.spawn(|| {
+      if let Some(mut stdin) = stdin {
+        for path in paths {
+          let path = path.to_string_lossy();
+          // Closed pipe means git failed, its status tells
+          if stdin.write_all(path.as_bytes()).is_err()
+            || stdin.write_all(b"\0").is_err()
+          {
+            break;
+          }
+        }
+      }
+    });
+    let mut output = Vec::new();
+    if let Some(mut stdout) = stdout {
+      stdout.read_to_end(&mut output)?;
+    }
+    io::Result::Ok(output)
+  })?;
+  git.wait()?;
+  Ok(
+    output
+      .split(|&b| b == 0)
+      .filter(|path| !path.is_empty())
+      .map(|path| {
+        PathBuf::from(String::from_utf8_lossy(path).as_ref())
+      })
+      .collect(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_dir::TestDir;
+
+  #[test]
+  fn debouncer_waits_for_quiet_period() {
+    let start = Instant::now();
+    let mut debouncer = Debouncer::new(DEBOUNCE);
+    assert!(!debouncer.take_ready(start));
+    debouncer.change(start);
+    debouncer.change(start + DEBOUNCE / 2);
+    assert!(!debouncer.take_ready(start + DEBOUNCE));
+    assert!(debouncer.take_ready(start + DEBOUNCE / 2 + DEBOUNCE));
+    assert!(!debouncer.take_ready(start + DEBOUNCE * 3));
+  }
+
+  #[test]
+  fn relative_path_strips_root_and_cur_dir() {
+    let root = Path::new("/project");
+    assert_eq!(
+      relative_path(root, Path::new("/project/src/main.rs")),
+      Some(PathBuf::from("src/main.rs"))
+    );
+    assert_eq!(
+      relative_path(root, Path::new("./src/main.rs")),
+      Some(PathBuf::from("src/main.rs"))
+    );
+    assert_eq!(relative_path(root, root), None);
+  }
+
+  #[test]
+  fn skips_git_dir() {
+    assert!(is_git(Path::new(".git/index")));
+    assert!(!is_git(Path::new("src/git.rs")));
+  }
+
+  #[test]
+  fn excludes_paths_inside_excluded_dir() {
+    let is_excluded = |path: &Path| path == Path::new("target");
+    assert!(excluded(Path::new("target/debug/hmd"), is_excluded));
+    assert!(!excluded(Path::new("src/target.rs"), is_excluded));
+  }
+
+  #[test]
+  fn skips_gitignored_paths() {
+    let root = TestDir::new("gitignore");
+    let init = Cmd::new("git")
+      .args(["init", "-q"])
+      .current_dir(&root)
+      .status()
+      .unwrap();
+    assert!(init.success());
+    std::fs::write(root.join(".gitignore"), "target/\n*.log\n")
+      .unwrap();
+    let paths = ["src/main.rs", "target/debug/hmd", "out.log"]
+      .map(PathBuf::from)
+      .into();
+    let changed = without_gitignored(&root, paths);
+    assert_eq!(
+      changed,
+      BTreeSet::from([PathBuf::from("src/main.rs")])
+    );
+  }
+}
@@ -0,0 +1,80 @@
+use std::fmt::Write;
+
+use crate::shell::shell_quote;
+
+/// Builds best-effort remote command posting `text` to webhook `url`
+///
+/// Failed request doesn't fail pipeline.
+pub(crate) fn notify_cmd(url: &str, text: &str) -> String {
+  let payload = shell_quote(&payload(text));
+  let url = shell_quote(url);
+  format!(
+    "curl -fsS -X POST -H 'Content-Type: application/json' \
+    -d {payload} {url} > /dev/null || true;"
+  )
+}
+
+/// Builds JSON payload accepted by Slack and Discord webhooks
+///
+/// Slack reads `text` field, Discord reads `content` one.
+fn payload(text: &str) -> String {
+  let text = json_string(text);
+  format!(r#"{{"text":{text},"content":{text}}}"#)
+}
+
+fn json_string(value: &str) -> String {
+  let mut json = String::with_capacity(value.len() + 2);
+  json.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => json.push_str(r#"\""#),
+      '\\' => json.push_str(r"\\"),
+      '\n' => json.push_str(r"\n"),
+      '\r' => json.push_str(r"\r"),
+      '\t' => json.push_str(r"\t"),
+      c if c.is_control() => {
+        let _ = write!(json, r"\u{:04x}", u32::from(c));
+      }
+      c => json.push(c),
+    }
+  }
+  json.push('"');
+  json
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn payload_has_text_for_slack_and_content_for_discord() {
+    assert_eq!(
+      payload("app: ✅ Done"),
+      r#"{"text":"app: ✅ Done","content":"app: ✅ Done"}"#
+    );
+  }
+
+  #[test]
+  fn escapes_json_string() {
+    assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+    assert_eq!(json_string("a\\b"), r#""a\\b""#);
+    assert_eq!(json_string("a\nb\r\tc"), r#""a\nb\r\tc""#);
+    assert_eq!(json_string("\u{1b}[0m\0"), r#""\u001b[0m\u0000""#);
+  }
+
+  #[test]
+  fn payload_is_valid_json() {
+    let text = "quote \" slash \\ line\n bell \u{7}";
+    let json: serde_json::Value =
+      serde_json::from_str(&payload(text)).unwrap();
+    assert_eq!(json["text"], text);
+    assert_eq!(json["content"], text);
+  }
+
+  #[test]
+  fn notify_cmd_never_fails_pipeline() {
+    let cmd = notify_cmd("https://hooks/x", "it's done");
+    assert!(cmd.contains(r#"-d '{"text":"it'\''s done","#));
+    assert!(cmd.ends_with(" 'https://hooks/x' > /dev/null || true;"));
+  }
+}
@@ -0,0 +1,186 @@
+use std::process::Command;
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+#[test]
+fn json_error_format_prints_single_line_to_stderr() {
+  let dir = TestDir::new("cli");
+  let output = Command::new(env!("CARGO_BIN_EXE_hmd"))
+    .args(["--error-format", "json", "status"])
+    .current_dir(&dir)
+    .env("HOME", dir.as_os_str())
+    .env_remove("HMD_PROJECT")
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(2));
+  assert_eq!(
+    String::from_utf8_lossy(&output.stderr),
+    "{\"error\":\"Project not provided\",\"kind\":\"project_not_provided\"}\n"
+  );
+}
+
+#[test]
+fn config_set_ssh_is_shown_back() {
+  let home = TestDir::new("cli-config");
+  let hmd = |args: &[&str]| {
+    let output = Command::new(env!("CARGO_BIN_EXE_hmd"))
+      .args(args)
+      .env("HOME", home.as_os_str())
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+  };
+  hmd(&["config", "set-ssh", "user@host"]);
+  assert_eq!(hmd(&["config", "show"]), "ssh_address: user@host\n");
+  hmd(&["config", "set-ssh", "other"]);
+  assert_eq!(hmd(&["config", "show"]), "ssh_address: other\n");
+  assert_eq!(
+    hmd(&["config", "path"]).trim_end(),
+    home.path(".hmd/config.yml")
+  );
+}
+
+/// Puts `ssh` of `script` to `dir`, returns `PATH` preferring it
+#[cfg(unix)]
+fn stub_ssh(dir: &TestDir, script: &str) -> String {
+  use std::os::unix::fs::PermissionsExt;
+
+  let ssh = dir.file("bin/ssh");
+  std::fs::write(&ssh, format!("#!/bin/sh\n{script}\n")).unwrap();
+  std::fs::set_permissions(&ssh, PermissionsExt::from_mode(0o755))
+    .unwrap();
+  let path = std::env::var("PATH").unwrap_or_default();
+  format!("{}:{path}", dir.path("bin"))
+}
+
+#[cfg(unix)]
+#[test]
+fn quiet_init_prints_nothing_to_stdout() {
+  let dir = TestDir::new("cli-quiet");
+  // Stub ssh succeeds without server
+  let path = stub_ssh(&dir, "exit 0");
+  let init = |quiet: &[&str]| {
+    let _ = std::fs::remove_file(dir.join("hmd.yml"));
+    let _ = std::fs::remove_dir_all(dir.join(".hmd"));
+    let output = Command::new(env!("CARGO_BIN_EXE_hmd"))
+      .args(quiet)
+      .args(["init", "--ssh", "host", "--project", "app"])
+      .current_dir(&dir)
+      .env("HOME", dir.as_os_str())
+      .env("PATH", &path)
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+  };
+  assert!(init(&[]).contains("Config created"));
+  assert_eq!(init(&["-q"]), "");
+}
+
+#[cfg(unix)]
+#[test]
+fn env_vars_override_hmd_yml_but_not_flags() {
+  let dir = TestDir::new("cli-env-vars");
+  let path = stub_ssh(&dir, r#"echo "ssh $*""#);
+  std::fs::write(
+    dir.join("hmd.yml"),
+    "project: yml-app\nssh_address: yml-host\nrun: ./app\n",
+  )
+  .unwrap();
+  let ssh = |args: &[&str], env_vars: bool| {
+    let mut hmd = Command::new(env!("CARGO_BIN_EXE_hmd"));
+    hmd
+      .args(["-q", "exec"])
+      .args(args)
+      .args(["--", "true"])
+      .current_dir(&dir)
+      .env("HOME", dir.as_os_str())
+      .env("PATH", &path)
+      .env_remove("HMD_SSH_ADDRESS")
+      .env_remove("HMD_PROJECT");
+    if env_vars {
+      hmd
+        .env("HMD_SSH_ADDRESS", "env-host")
+        .env("HMD_PROJECT", "env-app");
+    }
+    let output = hmd.output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+  };
+  let flags = ["--ssh", "flag-host", "--project", "flag-app"];
+  assert!(ssh(&[], false).starts_with(
+    "ssh yml-host source '.profile'; cd ~/'.hmd/yml-app/"
+  ));
+  assert!(ssh(&[], true).starts_with(
+    "ssh env-host source '.profile'; cd ~/'.hmd/env-app/"
+  ));
+  assert!(ssh(&flags, true).starts_with(
+    "ssh flag-host source '.profile'; cd ~/'.hmd/flag-app/"
+  ));
+}
+
+#[test]
+fn pipeline_is_traced_from_second_verbosity_level() {
+  let dir = TestDir::new("cli-trace");
+  std::fs::write(
+    dir.join("hmd.yml"),
+    "project: app\nssh_address: host\nrun: ./app\n",
+  )
+  .unwrap();
+  let trace = |verbose: &[&str]| {
+    let output = Command::new(env!("CARGO_BIN_EXE_hmd"))
+      .args(verbose)
+      .args(["deploy", "--print-pipeline"])
+      .current_dir(&dir)
+      .env("HOME", dir.as_os_str())
+      .output()
+      .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let script = String::from_utf8_lossy(&output.stdout).into_owned();
+    script
+      .lines()
+      .find(|line| line.starts_with("trace="))
+      .map(str::to_owned)
+  };
+  assert_eq!(trace(&[]).as_deref(), Some("trace=0;"));
+  assert_eq!(trace(&["-v"]).as_deref(), Some("trace=0;"));
+  assert_eq!(trace(&["-vv"]).as_deref(), Some("trace=1;"));
+}
+
+#[cfg(unix)]
+#[test]
+fn directory_option_reads_hmd_yml_of_dir() {
+  let dir = TestDir::new("cli-directory");
+  let path = stub_ssh(&dir, r#"echo "ssh $*""#);
+  let project_dir = dir.join("project");
+  std::fs::create_dir(&project_dir).unwrap();
+  std::fs::write(
+    project_dir.join("hmd.yml"),
+    "project: dir-app\nssh_address: dir-host\nrun: ./app\n",
+  )
+  .unwrap();
+  std::fs::write(
+    dir.join("hmd.yml"),
+    "project: cwd-app\nssh_address: cwd-host\nrun: ./app\n",
+  )
+  .unwrap();
+  let output = Command::new(env!("CARGO_BIN_EXE_hmd"))
+    .args(["-q", "-C"])
+    .arg(&project_dir)
+    .args(["exec", "--", "true"])
+    .current_dir(&dir)
+    .env("HOME", dir.as_os_str())
+    .env("PATH", &path)
+    .env_remove("HMD_SSH_ADDRESS")
+    .env_remove("HMD_PROJECT")
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{output:?}");
+  assert!(String::from_utf8_lossy(&output.stdout).starts_with(
+    "ssh dir-host source '.profile'; cd ~/'.hmd/dir-app/"
+  ));
+}
@@ -0,0 +1,80 @@
+use hmd::{
+  hmd_config_yml::Target,
+  hmd_yml::{HmdYml, Supervisor},
+  DeployOptions, Env, Hmd, HmdError, RecordingRunner,
+};
+
+const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+fn env() -> Env {
+  Env::new("app", &Target::new("user@host".to_owned()))
+}
+
+fn runner() -> RecordingRunner {
+  RecordingRunner::new()
+    .with_output("rev-parse", SHA)
+    .with_output("branch --show-current", "main")
+}
+
+fn hmd_yml() -> HmdYml {
+  HmdYml {
+    project: "app".to_owned(),
+    ..HmdYml::default()
+  }
+}
+
+fn options() -> DeployOptions<'static> {
+  DeployOptions {
+    parallel: 1,
+    ..DeployOptions::default()
+  }
+}
+
+#[test]
+fn deploys_through_library() {
+  let runner = runner();
+  let hmd = Hmd::new(&runner, env());
+  hmd.deploy(&hmd_yml(), &options()).unwrap();
+  let lines = runner.command_lines();
+  assert!(lines.contains(&format!(
+    "git push --force user@host:~/.hmd/app/git {SHA}:refs/heads/main"
+  )));
+  assert!(lines.iter().any(|line| line.starts_with("scp -r ")
+    && line.ends_with(" user@host:~/.hmd/app/upload")));
+  let run = lines
+    .iter()
+    .find(|line| line.contains("nohup"))
+    .expect("pipeline is started");
+  assert!(run.contains(&format!("checkout --force '{SHA}';")));
+  assert_eq!(
+    lines.last().unwrap(),
+    "ssh user@host rm -rf ~/'.hmd/app/deploy.lock';"
+  );
+}
+
+#[test]
+fn failed_pipeline_start_fails_deploy_and_unlocks() {
+  let runner = runner().with_failure("nohup", 1);
+  let hmd = Hmd::new(&runner, env());
+  let err = hmd.deploy(&hmd_yml(), &options()).unwrap_err();
+  assert!(matches!(
+    HmdError::downcast(&err),
+    Some(HmdError::CommandFailed { .. })
+  ));
+  assert!(runner
+    .command_lines()
+    .last()
+    .unwrap()
+    .contains("deploy.lock"));
+}
+
+#[test]
+fn stops_pipeline_through_library() {
+  let runner = RecordingRunner::new();
+  let hmd = Hmd::new(&runner, env());
+  hmd.stop(Supervisor::Nohup).unwrap();
+  let lines = runner.command_lines();
+  assert_eq!(lines.len(), 1);
+  assert!(lines[0].starts_with("ssh user@host "));
+  assert!(lines[0].contains("pipeline.pid"));
+}